@@ -0,0 +1,408 @@
+//! Instruction builders for Rust clients, behind the `client` feature.
+//!
+//! Each builder derives the PDAs an instruction needs and fills in every
+//! account, so callers only pass the keys that can't be derived. Signing is left
+//! to the caller: initialize_escrow_ix needs the initializer and the new escrow
+//! account to sign, exchange_ix and exchange_many_ix the taker, cancel_escrow_ix
+//! the authority, exchange_legacy_escrow_ix the taker, and
+//! cancel_legacy_escrow_ix the initializer.
+
+use crate::{
+    accounts, instruction, EscrowAccount, EscrowAccountV1, NftTerms, CONFIG_SEED, ESCROW_PDA_SEED,
+    ORDER_BOOK_SEED,
+};
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::bpf_loader_upgradeable;
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::solana_program::system_program;
+use anchor_lang::{InstructionData, ToAccountMetas};
+
+// The PDA that owns an escrow's deposit, and its bump.
+pub fn escrow_pda(escrow: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[ESCROW_PDA_SEED, escrow.as_ref()], &crate::ID)
+}
+
+// The PDA that owned the deposits of escrows from the original program (see
+// EscrowAccountV1), and its bump.
+pub fn legacy_escrow_pda() -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[ESCROW_PDA_SEED], &crate::ID)
+}
+
+// The order book listing escrows that offer offered_mint for requested_mint.
+pub fn order_book_address(offered_mint: &Pubkey, requested_mint: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[
+            ORDER_BOOK_SEED,
+            offered_mint.as_ref(),
+            requested_mint.as_ref(),
+        ],
+        &crate::ID,
+    )
+}
+
+// The global EscrowConfig.
+pub fn config_address() -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[CONFIG_SEED], &crate::ID)
+}
+
+// The program's ProgramData account, which records its upgrade authority.
+pub fn program_data_address() -> Pubkey {
+    Pubkey::find_program_address(&[crate::ID.as_ref()], &bpf_loader_upgradeable::id()).0
+}
+
+// Builds initialize_config. The admin pays for the config and must sign, and
+// must be the program's upgrade authority.
+pub fn initialize_config_ix(
+    admin: &Pubkey,
+    offered_leg_fee_bps: u16,
+    requested_leg_fee_bps: u16,
+    fee_recipient: &Pubkey,
+) -> Instruction {
+    let (config, bump) = config_address();
+    let accounts = accounts::InitializeConfig {
+        config,
+        admin: *admin,
+        escrow_program: crate::ID,
+        program_data: program_data_address(),
+        system_program: system_program::ID,
+    };
+    let data = instruction::InitializeConfig {
+        offered_leg_fee_bps,
+        requested_leg_fee_bps,
+        fee_recipient: *fee_recipient,
+        bump,
+    };
+    Instruction {
+        program_id: crate::ID,
+        accounts: accounts.to_account_metas(None),
+        data: data.data(),
+    }
+}
+
+// Builds initialize_order_book for the given mint pair. The payer must sign.
+pub fn initialize_order_book_ix(
+    payer: &Pubkey,
+    offered_mint: &Pubkey,
+    requested_mint: &Pubkey,
+) -> Instruction {
+    let (order_book, bump) = order_book_address(offered_mint, requested_mint);
+    let accounts = accounts::InitializeOrderBook {
+        payer: *payer,
+        offered_mint: *offered_mint,
+        requested_mint: *requested_mint,
+        order_book,
+        system_program: system_program::ID,
+    };
+    Instruction {
+        program_id: crate::ID,
+        accounts: accounts.to_account_metas(None),
+        data: instruction::InitializeOrderBook { bump }.data(),
+    }
+}
+
+// Builds list_escrow, for an escrow created while its order book was full.
+pub fn list_escrow_ix(escrow: &Pubkey, escrow_account: &EscrowAccount) -> Instruction {
+    let accounts = accounts::ListEscrow {
+        escrow_account: *escrow,
+        order_book: order_book_address(
+            &escrow_account.offered_mint,
+            &escrow_account.requested_mint,
+        )
+        .0,
+    };
+    Instruction {
+        program_id: crate::ID,
+        accounts: accounts.to_account_metas(None),
+        data: instruction::ListEscrow {}.data(),
+    }
+}
+
+// Builds initialize_escrow. The mints are those of the two token accounts, and
+// pick the order book the escrow is listed in. For an NFT escrow, append the
+// NFT's mint (and metadata account, if nft names a collection) to the
+// instruction's accounts.
+#[allow(clippy::too_many_arguments)]
+pub fn initialize_escrow_ix(
+    initializer: &Pubkey,
+    initializer_deposit_token_account: &Pubkey,
+    initializer_receive_token_account: &Pubkey,
+    offered_mint: &Pubkey,
+    requested_mint: &Pubkey,
+    escrow: &Pubkey,
+    initializer_amount: u64,
+    taker_amount: u64,
+    expires_at: Option<i64>,
+    allowed_taker: Option<Pubkey>,
+    nft: Option<NftTerms>,
+) -> Instruction {
+    let (pda_account, bump) = escrow_pda(escrow);
+    let accounts = accounts::InitializeEscrow {
+        initializer: *initializer,
+        initializer_deposit_token_account: *initializer_deposit_token_account,
+        initializer_receive_token_account: *initializer_receive_token_account,
+        escrow_account: *escrow,
+        pda_account,
+        order_book: order_book_address(offered_mint, requested_mint).0,
+        system_program: system_program::ID,
+        token_program: spl_token::ID,
+    };
+    let data = instruction::InitializeEscrow {
+        initializer_amount,
+        taker_amount,
+        bump,
+        expires_at,
+        allowed_taker,
+        nft,
+    };
+    Instruction {
+        program_id: crate::ID,
+        accounts: accounts.to_account_metas(None),
+        data: data.data(),
+    }
+}
+
+// Builds exchange for everything left in the escrow. The expected amounts are
+// taken from escrow_account, so the exchange fails if the terms have changed
+// since it was fetched. For a Dutch auction the stored taker_amount is the most
+// the taker pays, since the price only falls after it's stored. Oracle-priced
+// escrows aren't handled here: their exchange needs the price account and a
+// bound on the oracle's price. The fee token accounts must belong to the config's
+// fee_recipient and hold the offered and requested mints respectively, and the
+// exchange fails if either leg's fee is above max_fee_bps. NFT escrows need
+// the same extra accounts as initialize_escrow_ix.
+#[allow(clippy::too_many_arguments)]
+pub fn exchange_ix(
+    escrow: &Pubkey,
+    escrow_account: &EscrowAccount,
+    taker: &Pubkey,
+    taker_deposit_token_account: &Pubkey,
+    taker_receive_token_account: &Pubkey,
+    offered_fee_token_account: &Pubkey,
+    requested_fee_token_account: &Pubkey,
+    max_fee_bps: u16,
+) -> Instruction {
+    let data = instruction::Exchange {
+        expected_initializer_amount: escrow_account.initializer_amount,
+        expected_taker_amount: escrow_account.taker_amount,
+        max_fee_bps,
+    };
+    Instruction {
+        program_id: crate::ID,
+        accounts: exchange_accounts(
+            escrow,
+            escrow_account,
+            taker,
+            taker_deposit_token_account,
+            taker_receive_token_account,
+            offered_fee_token_account,
+            requested_fee_token_account,
+        )
+        .to_account_metas(None),
+        data: data.data(),
+    }
+}
+
+// Same as exchange_ix, but only fills taker_amount of the escrow, which must be
+// less than what's left. As with exchange_ix, the expected amounts are taken
+// from escrow_account.
+#[allow(clippy::too_many_arguments)]
+pub fn exchange_partial_ix(
+    escrow: &Pubkey,
+    escrow_account: &EscrowAccount,
+    taker: &Pubkey,
+    taker_deposit_token_account: &Pubkey,
+    taker_receive_token_account: &Pubkey,
+    offered_fee_token_account: &Pubkey,
+    requested_fee_token_account: &Pubkey,
+    taker_amount: u64,
+    max_fee_bps: u16,
+) -> Instruction {
+    let data = instruction::ExchangePartial {
+        taker_amount,
+        expected_initializer_amount: escrow_account.initializer_amount,
+        expected_taker_amount: escrow_account.taker_amount,
+        max_fee_bps,
+    };
+    let exchange = exchange_accounts(
+        escrow,
+        escrow_account,
+        taker,
+        taker_deposit_token_account,
+        taker_receive_token_account,
+        offered_fee_token_account,
+        requested_fee_token_account,
+    );
+    let accounts = accounts::ExchangePartial {
+        taker: exchange.taker,
+        taker_deposit_token_account: exchange.taker_deposit_token_account,
+        taker_receive_token_account: exchange.taker_receive_token_account,
+        pda_deposit_token_account: exchange.pda_deposit_token_account,
+        initializer_receive_token_account: exchange.initializer_receive_token_account,
+        initializer_main_account: exchange.initializer_main_account,
+        escrow_account: exchange.escrow_account,
+        pda_account: exchange.pda_account,
+        order_book: exchange.order_book,
+        config: exchange.config,
+        offered_fee_token_account: exchange.offered_fee_token_account,
+        requested_fee_token_account: exchange.requested_fee_token_account,
+        token_program: exchange.token_program,
+    };
+    Instruction {
+        program_id: crate::ID,
+        accounts: accounts.to_account_metas(None),
+        data: data.data(),
+    }
+}
+
+// The accounts for exchanging escrow, as exchange_ix and exchange_partial_ix
+// pass them. Also what exchange_many_ix takes for each escrow.
+pub fn exchange_accounts(
+    escrow: &Pubkey,
+    escrow_account: &EscrowAccount,
+    taker: &Pubkey,
+    taker_deposit_token_account: &Pubkey,
+    taker_receive_token_account: &Pubkey,
+    offered_fee_token_account: &Pubkey,
+    requested_fee_token_account: &Pubkey,
+) -> accounts::Exchange {
+    accounts::Exchange {
+        taker: *taker,
+        taker_deposit_token_account: *taker_deposit_token_account,
+        taker_receive_token_account: *taker_receive_token_account,
+        pda_deposit_token_account: escrow_account.vault,
+        initializer_receive_token_account: escrow_account.initializer_receive_token_account,
+        initializer_main_account: escrow_account.initializer_key,
+        escrow_account: *escrow,
+        pda_account: escrow_pda(escrow).0,
+        order_book: order_book_address(
+            &escrow_account.offered_mint,
+            &escrow_account.requested_mint,
+        )
+        .0,
+        config: config_address().0,
+        offered_fee_token_account: *offered_fee_token_account,
+        requested_fee_token_account: *requested_fee_token_account,
+        token_program: spl_token::ID,
+    }
+}
+
+// Builds exchange_many, exchanging each escrow in full with the accounts
+// alongside it (see exchange_accounts). As with exchange_ix, the expected
+// amounts are taken from each escrow account.
+pub fn exchange_many_ix(
+    taker: &Pubkey,
+    escrows: &[(&EscrowAccount, accounts::Exchange)],
+    max_fee_bps: u16,
+) -> Instruction {
+    let mut account_metas = accounts::ExchangeMany { taker: *taker }.to_account_metas(None);
+    for (_, exchange_accounts) in escrows {
+        account_metas.extend(exchange_accounts.to_account_metas(None));
+    }
+    let data = instruction::ExchangeMany {
+        expected_initializer_amounts: escrows
+            .iter()
+            .map(|(escrow_account, _)| escrow_account.initializer_amount)
+            .collect(),
+        expected_taker_amounts: escrows
+            .iter()
+            .map(|(escrow_account, _)| escrow_account.taker_amount)
+            .collect(),
+        max_fee_bps,
+    };
+    Instruction {
+        program_id: crate::ID,
+        accounts: account_metas,
+        data: data.data(),
+    }
+}
+
+// Builds cancel_escrow. authority is the initializer or their delegated canceller.
+pub fn cancel_escrow_ix(
+    escrow: &Pubkey,
+    escrow_account: &EscrowAccount,
+    authority: &Pubkey,
+) -> Instruction {
+    let accounts = accounts::CancelEscrow {
+        authority: *authority,
+        initializer: escrow_account.initializer_key,
+        pda_deposit_token_account: escrow_account.vault,
+        initializer_deposit_token_account: escrow_account.initializer_deposit_token_account,
+        pda_account: escrow_pda(escrow).0,
+        escrow_account: *escrow,
+        order_book: order_book_address(
+            &escrow_account.offered_mint,
+            &escrow_account.requested_mint,
+        )
+        .0,
+        token_program: spl_token::ID,
+    };
+    Instruction {
+        program_id: crate::ID,
+        accounts: accounts.to_account_metas(None),
+        data: instruction::CancelEscrow {}.data(),
+    }
+}
+
+// Builds exchange_legacy_escrow for the v1 escrow at legacy_escrow. As with
+// exchange_ix, the expected amounts are taken from legacy_escrow_account, and
+// the fee token accounts must belong to the config's fee_recipient and hold
+// the offered and requested mints respectively.
+#[allow(clippy::too_many_arguments)]
+pub fn exchange_legacy_escrow_ix(
+    legacy_escrow: &Pubkey,
+    legacy_escrow_account: &EscrowAccountV1,
+    taker: &Pubkey,
+    taker_deposit_token_account: &Pubkey,
+    taker_receive_token_account: &Pubkey,
+    offered_fee_token_account: &Pubkey,
+    requested_fee_token_account: &Pubkey,
+    max_fee_bps: u16,
+) -> Instruction {
+    let (legacy_pda_account, legacy_bump) = legacy_escrow_pda();
+    let accounts = accounts::ExchangeLegacyEscrow {
+        taker: *taker,
+        taker_deposit_token_account: *taker_deposit_token_account,
+        taker_receive_token_account: *taker_receive_token_account,
+        pda_deposit_token_account: legacy_escrow_account.initializer_deposit_token_account,
+        initializer_receive_token_account: legacy_escrow_account.initializer_receive_token_account,
+        initializer_main_account: legacy_escrow_account.initializer_key,
+        legacy_escrow_account: *legacy_escrow,
+        legacy_pda_account,
+        config: config_address().0,
+        offered_fee_token_account: *offered_fee_token_account,
+        requested_fee_token_account: *requested_fee_token_account,
+        token_program: spl_token::ID,
+    };
+    let data = instruction::ExchangeLegacyEscrow {
+        expected_initializer_amount: legacy_escrow_account.initializer_amount,
+        expected_taker_amount: legacy_escrow_account.taker_amount,
+        max_fee_bps,
+        legacy_bump,
+    };
+    Instruction {
+        program_id: crate::ID,
+        accounts: accounts.to_account_metas(None),
+        data: data.data(),
+    }
+}
+
+// Builds cancel_legacy_escrow for the v1 escrow at legacy_escrow.
+pub fn cancel_legacy_escrow_ix(
+    legacy_escrow: &Pubkey,
+    legacy_escrow_account: &EscrowAccountV1,
+) -> Instruction {
+    let (legacy_pda_account, legacy_bump) = legacy_escrow_pda();
+    let accounts = accounts::CancelLegacyEscrow {
+        initializer: legacy_escrow_account.initializer_key,
+        pda_deposit_token_account: legacy_escrow_account.initializer_deposit_token_account,
+        initializer_receive_token_account: legacy_escrow_account.initializer_receive_token_account,
+        legacy_escrow_account: *legacy_escrow,
+        legacy_pda_account,
+        token_program: spl_token::ID,
+    };
+    Instruction {
+        program_id: crate::ID,
+        accounts: accounts.to_account_metas(None),
+        data: instruction::CancelLegacyEscrow { legacy_bump }.data(),
+    }
+}
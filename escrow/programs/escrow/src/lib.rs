@@ -6,33 +6,290 @@
 //! User (Initializer) constructs an escrow deal:
 //! - SPL token (X) they will offer and amount
 //! - SPL token (Y) count they want in return and amount
-//! - Program will take ownership of initializer's token X account
+//! - Program will take ownership of initializer's token X account, via a PDA
+//!   derived from the escrow account's key (so each escrow has its own authority)
+//!
+//! Alternatively, with `initialize_escrow_with_vault`, the initializer keeps their
+//! token X account and the program instead creates a dedicated vault token account
+//! (owned by the same PDA) and transfers the X tokens into it.
+//!
+//! The initializer can optionally name the only taker allowed to take the escrow
+//! (for privately negotiated deals). Otherwise, anyone can take it.
 //!
 //! Once this escrow is initialised, either:
 //! 1. User (Taker) can call the exchange function to exchange their Y for X
 //! - This will close the escrow account and no longer be usable
+//! - Or, with exchange_partial, the taker can fill only part of the escrow at the
+//!   same price. The last fill goes through exchange, which closes the escrow.
 //! OR
-//! 2. If no one has exchanged, the initializer can close the escrow account
-//! - Initializer will get back ownership of their token X account
+//! 2. If no one has exchanged, the initializer (or a canceller they delegated to
+//!    with set_canceller) can close the escrow account
+//! - Initializer will get back ownership of their token X account (or, in vault
+//!   mode, the X tokens are sent back to it)
+//! - If the escrow was given an expiry, anyone can do this (via crank_expired)
+//!   once it has expired. Expired escrows can no longer be exchanged.
+//! - If the deposit no longer holds initializer_amount (or no longer exists),
+//!   the escrow can never be exchanged, so anyone can close it with reclaim.
+//!
+//! In vault mode both paths also close the vault, returning its rent to the initializer.
+//!
+//! Either leg can also be native SOL instead of an SPL token:
+//! - initialize_escrow_offering_sol escrows the offered lamports in the escrow
+//!   account itself, and exchange_sol_offer pays them out to the taker.
+//!   cancel_sol_offer gives them back.
+//! - initialize_escrow_requesting_sol deposits the offered tokens in a vault, and
+//!   exchange_sol_request has the taker pay the initializer in lamports.
+//!   These are cancelled like any other vault escrow.
+//!
+//! initialize_escrow and initialize_escrow_with_vault can also make one leg an
+//! NFT: a 0-decimal mint with a supply of 1, traded one at a time, optionally
+//! required to be a verified member of a Metaplex collection. This is checked
+//! when the escrow is initialized and again on exchange (see NftTerms).
+//!
+//! Bundle escrows trade several tokens at once: the initializer offers up to
+//! MAX_BUNDLE_LEGS different tokens and asks for up to MAX_BUNDLE_LEGS in return
+//! (see initialize_bundle_escrow). The deposits are handed to the escrow's PDA
+//! like in initialize_escrow, and exchange_bundle settles every leg atomically.
+//!
+//! Every open (non-bundle) escrow is listed in the OrderBook for its mint pair,
+//! a PDA derived from [ORDER_BOOK_SEED, offered mint, requested mint], so clients
+//! can find the live offers for a pair with a single account read. Native SOL legs
+//! use spl_token::native_mint. The order book has to be created with
+//! initialize_order_book before escrows for the pair can be initialized. An
+//! order book lists at most MAX_ORDER_BOOK_ENTRIES escrows; escrows created
+//! while it's full still work, they just aren't listed until someone calls
+//! list_escrow once there's room.
+//!
+//! Escrows backed by an EscrowAccount emit an EscrowCreated event when they are
+//! initialized, an EscrowExchanged event for every fill, and an EscrowCancelled
+//! event when they are cancelled or cranked, so indexers can follow them from
+//! transaction logs. update_escrow emits EscrowUpdated, and reclaim emits
+//! EscrowReclaimed.
+//!
+//! The initializer can also turn an escrow into a Dutch auction with
+//! set_dutch_auction: the price (taker_amount) then falls linearly from a start
+//! to an end price between two timestamps, and exchange charges the price at the
+//! time it runs. log_dutch_auction_price logs the current price for clients.
+//! Auctions can only be exchanged in full.
+//!
+//! Alternatively, start_english_auction sells the deposit to the highest bidder.
+//! place_bid locks a bid of the requested mint in its own vault, a PDA derived
+//! from [BID_VAULT_SEED, escrow_account.key, bidder], and refunds the bid it
+//! beats (to the outbid bidder's associated token account if they've closed
+//! the account the bid came from). Once the auction has ended, anyone can
+//! settle it: the deposit goes to the winner and their bid to the initializer
+//! (or, if either has closed the account they named, to their associated token
+//! account). An auction with bids can't be cancelled or exchanged.
+//!
+//! With set_oracle_pricing, an escrow can instead be priced off an oracle:
+//! exchange charges the oracle's current price for the deposit, plus a spread
+//! (see OraclePricing). The workspace's mock-oracle program publishes prices in
+//! the expected layout for local testing.
+//!
+//! For trades that need a human in the loop (say, for off-chain deliverables),
+//! set_arbiter names an arbiter. The escrow is then taken with fund instead of
+//! exchange: the taker's payment is locked in a taker vault, a PDA derived from
+//! [TAKER_VAULT_SEED, escrow_account.key], and the escrow moves through
+//! EscrowState:
+//! - Open -> Funded, when the taker funds it.
+//! - Funded -> Approved, once both parties approve; anyone can then release it,
+//!   which swaps the two deposits like exchange.
+//! - Funded -> Disputed, if either party disputes; the arbiter then resolves it
+//!   by choosing how much of each deposit goes to the other side.
+//! Releasing or resolving an escrow closes it, so there's no settled state:
+//! once it's paid out, the escrow account is gone.
+//! A funded escrow can't be cancelled, cranked or reclaimed. The taker names
+//! the arbiter they expect when funding, and if either party closes a token
+//! account they named, release and resolve pay their associated token account
+//! instead.
+//!
+//! EscrowAccount carries a version byte (ESCROW_ACCOUNT_VERSION) and reserved
+//! padding, so fields can be added later without breaking live escrows. Escrows
+//! created by the original, unversioned program (see EscrowAccountV1) are too
+//! small for the current layout, and accounts can't be resized on this version
+//! of Solana, so they stay at their own address on the v1 layout: the program
+//! reads either layout at the same key, and exchange_legacy_escrow and
+//! cancel_legacy_escrow close a v1 escrow the way exchange and cancel_escrow
+//! close a current one.
+//!
+//! exchange_many exchanges several escrows in one transaction, all or nothing,
+//! checking each one as exchange would.
+//!
+//! The deployment can charge a protocol fee of up to MAX_FEE_BPS on every
+//! exchange (including fills, SOL and bundle exchanges, settled auctions and
+//! released or resolved arbitrations), configured in the global EscrowConfig
+//! account (see initialize_config and update_config). The fee on each leg is
+//! deducted from what the other side receives, and sent to token accounts owned
+//! by the config's fee_recipient (or, for SOL legs, to fee_recipient itself).
+//! Takers pass the most they're willing to pay as max_fee_bps, so the admin
+//! can't raise fees on a transaction that's already signed. Bidders and
+//! arbitrated takers, whose payment is locked in before it's settled, have
+//! theirs recorded and checked again at settlement.
 
 use anchor_lang::prelude::*;
-use anchor_spl::token::{self, SetAuthority, Token, TokenAccount, Transfer};
+use anchor_lang::solana_program::bpf_loader_upgradeable::{self, UpgradeableLoaderState};
+use anchor_lang::solana_program::{
+    program::invoke, program_pack::Pack, program_utils::limited_deserialize, system_instruction,
+};
+use anchor_lang::AccountsClose;
+use anchor_lang::Discriminator;
+use anchor_spl::associated_token::{self, get_associated_token_address, AssociatedToken, Create};
+use anchor_spl::token::{self, CloseAccount, Mint, SetAuthority, Token, TokenAccount, Transfer};
 use spl_token::instruction::AuthorityType;
+use std::convert::TryFrom;
 
 declare_id!("Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnS");
 
+#[cfg(feature = "client")]
+pub mod client;
+pub mod metadata;
+pub mod oracle;
+
+// The layout version of every EscrowAccount this program creates.
+// Escrows from the original program predate the version byte, and are version 1
+// (see EscrowAccountV1).
+pub const ESCROW_ACCOUNT_VERSION: u8 = 2;
+
+// The vault authority for an escrow is the PDA derived from
+// [ESCROW_PDA_SEED, escrow_account.key]. Seeding by the escrow account means
+// every escrow gets its own authority, so one escrow's PDA can never sign for
+// another escrow's deposit.
+pub const ESCROW_PDA_SEED: &[u8] = b"escrow";
+
+// Vault token accounts (see initialize_escrow_with_vault) live at the PDA derived
+// from [VAULT_PDA_SEED, escrow_account.key], and are owned by the escrow's PDA.
+pub const VAULT_PDA_SEED: &[u8] = b"vault";
+
+// The OrderBook for a mint pair lives at the PDA derived from
+// [ORDER_BOOK_SEED, offered mint, requested mint].
+pub const ORDER_BOOK_SEED: &[u8] = b"order-book";
+
+// How many open escrows an order book can list at once.
+pub const MAX_ORDER_BOOK_ENTRIES: usize = 64;
+
+// A bid on an English auction (see place_bid) is held in a token account at the
+// PDA derived from [BID_VAULT_SEED, escrow_account.key, bidder], owned by the
+// escrow's PDA.
+pub const BID_VAULT_SEED: &[u8] = b"bid";
+
+// The taker's payment into an arbitrated escrow (see fund) is held in a token
+// account at the PDA derived from [TAKER_VAULT_SEED, escrow_account.key], owned
+// by the escrow's PDA.
+pub const TAKER_VAULT_SEED: &[u8] = b"taker-vault";
+
+// The global EscrowConfig lives at the PDA derived from [CONFIG_SEED].
+pub const CONFIG_SEED: &[u8] = b"config";
+
+// Fees (and oracle spreads) are expressed in basis points, i.e. 1/10_000ths of
+// the amount.
+pub const BASIS_POINTS: u16 = 10_000;
+
+// The most the config can charge on either leg: 5%.
+pub const MAX_FEE_BPS: u16 = 500;
+
 #[program]
 pub mod escrow {
     use super::*;
 
-    const ESCROW_PDA_SEED: &[u8] = b"escrow";
+    // Creates the global EscrowConfig, making the signer its admin. Only the
+    // program's upgrade authority can do this, so nobody can take the config
+    // by calling this before the deployer does (see validate_config_admin).
+    pub fn initialize_config(
+        ctx: Context<InitializeConfig>,
+        offered_leg_fee_bps: u16,
+        requested_leg_fee_bps: u16,
+        fee_recipient: Pubkey,
+        bump: u8,
+    ) -> ProgramResult {
+        validate_config_admin(
+            &ctx.accounts.escrow_program,
+            &ctx.accounts.program_data,
+            ctx.accounts.admin.key,
+            ctx.program_id,
+        )?;
+        validate_fee_bps(offered_leg_fee_bps)?;
+        validate_fee_bps(requested_leg_fee_bps)?;
+
+        let config = &mut ctx.accounts.config;
+        config.admin = *ctx.accounts.admin.key;
+        config.offered_leg_fee_bps = offered_leg_fee_bps;
+        config.requested_leg_fee_bps = requested_leg_fee_bps;
+        config.fee_recipient = fee_recipient;
+        config.bump = bump;
+        Ok(())
+    }
+
+    // Lets the admin change the fees, where they go, and who the admin is.
+    pub fn update_config(
+        ctx: Context<UpdateConfig>,
+        offered_leg_fee_bps: u16,
+        requested_leg_fee_bps: u16,
+        fee_recipient: Pubkey,
+        admin: Pubkey,
+    ) -> ProgramResult {
+        validate_fee_bps(offered_leg_fee_bps)?;
+        validate_fee_bps(requested_leg_fee_bps)?;
+
+        let config = &mut ctx.accounts.config;
+        config.admin = admin;
+        config.offered_leg_fee_bps = offered_leg_fee_bps;
+        config.requested_leg_fee_bps = requested_leg_fee_bps;
+        config.fee_recipient = fee_recipient;
+        Ok(())
+    }
+
+    // Creates the order book listing open escrows that offer offered_mint in
+    // exchange for requested_mint. Anyone can create it, paying its rent.
+    pub fn initialize_order_book(ctx: Context<InitializeOrderBook>, bump: u8) -> ProgramResult {
+        let order_book = &mut ctx.accounts.order_book;
+        order_book.offered_mint = *ctx.accounts.offered_mint.to_account_info().key;
+        order_book.requested_mint = *ctx.accounts.requested_mint.to_account_info().key;
+        order_book.bump = bump;
+        Ok(())
+    }
+
+    // Lists an open escrow that was created while its order book was full.
+    // Anyone can call this. Listing an escrow that's already listed does
+    // nothing.
+    pub fn list_escrow(ctx: Context<ListEscrow>) -> ProgramResult {
+        let escrow_account = &ctx.accounts.escrow_account;
+        if escrow_account.state != EscrowState::Open {
+            return Err(ErrorCode::EscrowFunded.into());
+        }
+        let escrow_key = escrow_account.to_account_info().key;
+        let order_book = &mut ctx.accounts.order_book;
+        if order_book.escrows.contains(escrow_key) {
+            return Ok(());
+        }
+        if order_book.escrows.len() >= MAX_ORDER_BOOK_ENTRIES {
+            return Err(ErrorCode::OrderBookFull.into());
+        }
+        order_book.escrows.push(*escrow_key);
+        Ok(())
+    }
 
     pub fn initialize_escrow(
         ctx: Context<InitializeEscrow>,
         initializer_amount: u64,
         taker_amount: u64,
+        bump: u8,
+        expires_at: Option<i64>,
+        allowed_taker: Option<Pubkey>,
+        nft: Option<NftTerms>,
     ) -> ProgramResult {
+        validate_expiry(expires_at)?;
+        if let Some(nft) = &nft {
+            nft.verify(
+                &ctx.accounts.initializer_deposit_token_account.mint,
+                &ctx.accounts.initializer_receive_token_account.mint,
+                initializer_amount,
+                taker_amount,
+                ctx.remaining_accounts,
+            )?;
+        }
+
         // This chunk of codes just sets fields on ctx.accounts.escrow_account
+        ctx.accounts.escrow_account.version = ESCROW_ACCOUNT_VERSION;
         ctx.accounts.escrow_account.initializer_key = *ctx.accounts.initializer.key;
         ctx.accounts
             .escrow_account
@@ -48,225 +305,4564 @@ pub mod escrow {
             .initializer_receive_token_account
             .to_account_info()
             .key;
+        ctx.accounts.escrow_account.offered_mint =
+            ctx.accounts.initializer_deposit_token_account.mint;
+        ctx.accounts.escrow_account.requested_mint =
+            ctx.accounts.initializer_receive_token_account.mint;
         ctx.accounts.escrow_account.initializer_amount = initializer_amount;
         ctx.accounts.escrow_account.taker_amount = taker_amount;
+        ctx.accounts.escrow_account.expires_at = expires_at;
+        ctx.accounts.escrow_account.allowed_taker = allowed_taker;
+        ctx.accounts.escrow_account.nft = nft;
+        // Stored so later instructions can sign with the PDA without calling
+        // find_program_address again.
+        ctx.accounts.escrow_account.bump = bump;
+        // The PDA takes over the deposit account itself, so that's the "vault".
+        ctx.accounts.escrow_account.vault = ctx
+            .accounts
+            .escrow_account
+            .initializer_deposit_token_account;
+
+        let escrow_key = *ctx.accounts.escrow_account.to_account_info().key;
+        ctx.accounts.order_book.insert(escrow_key);
+        emit!(ctx
+            .accounts
+            .escrow_account
+            .created_event(escrow_key, Clock::get()?.slot));
 
-        let (pda, _bump_seed) = Pubkey::find_program_address(&[ESCROW_PDA_SEED], ctx.program_id);
         // Transfers owernship of initializer_deposit_token_account from
         // initializer -> pda.
+        let pda = *ctx.accounts.pda_account.key;
         token::set_authority(ctx.accounts.into(), AuthorityType::AccountOwner, Some(pda))?;
 
         Ok(())
     }
 
+    // Same as initialize_escrow, except the initializer keeps ownership of
+    // initializer_deposit_token_account. Instead, initializer_amount tokens are
+    // moved into a vault token account created (and owned) by this escrow's PDA.
+    pub fn initialize_escrow_with_vault(
+        ctx: Context<InitializeEscrowWithVault>,
+        initializer_amount: u64,
+        taker_amount: u64,
+        bump: u8,
+        _vault_bump: u8,
+        expires_at: Option<i64>,
+        allowed_taker: Option<Pubkey>,
+        nft: Option<NftTerms>,
+    ) -> ProgramResult {
+        validate_expiry(expires_at)?;
+        if let Some(nft) = &nft {
+            nft.verify(
+                &ctx.accounts.initializer_deposit_token_account.mint,
+                &ctx.accounts.initializer_receive_token_account.mint,
+                initializer_amount,
+                taker_amount,
+                ctx.remaining_accounts,
+            )?;
+        }
+
+        let escrow_account = &mut ctx.accounts.escrow_account;
+        escrow_account.version = ESCROW_ACCOUNT_VERSION;
+        escrow_account.initializer_key = *ctx.accounts.initializer.key;
+        // In vault mode, this is where the deposit gets refunded to on cancel.
+        escrow_account.initializer_deposit_token_account = *ctx
+            .accounts
+            .initializer_deposit_token_account
+            .to_account_info()
+            .key;
+        escrow_account.initializer_receive_token_account = *ctx
+            .accounts
+            .initializer_receive_token_account
+            .to_account_info()
+            .key;
+        escrow_account.offered_mint = ctx.accounts.initializer_deposit_token_account.mint;
+        escrow_account.requested_mint = ctx.accounts.initializer_receive_token_account.mint;
+        escrow_account.initializer_amount = initializer_amount;
+        escrow_account.taker_amount = taker_amount;
+        escrow_account.expires_at = expires_at;
+        escrow_account.allowed_taker = allowed_taker;
+        escrow_account.nft = nft;
+        escrow_account.bump = bump;
+        escrow_account.vault = *ctx.accounts.vault.to_account_info().key;
+
+        let escrow_key = *escrow_account.to_account_info().key;
+        ctx.accounts.order_book.insert(escrow_key);
+        emit!(ctx
+            .accounts
+            .escrow_account
+            .created_event(escrow_key, Clock::get()?.slot));
+
+        // Transfers initializer_amount tokens from
+        // initializer_deposit_token_account -> vault.
+        token::transfer(
+            ctx.accounts.into_transfer_to_vault_context(),
+            initializer_amount,
+        )?;
+
+        Ok(())
+    }
+
     // In this context, "cancelling" just means the ownership of pda_deposit_token_account gets transferred
-    // back to the person who originally initialized the escrow.
+    // back to the person who originally initialized the escrow. In vault mode, the vault's tokens are
+    // sent back to initializer_deposit_token_account and the vault is closed.
+    // Must be signed by the initializer, or by the canceller they delegated to.
     pub fn cancel_escrow(ctx: Context<CancelEscrow>) -> ProgramResult {
-        let (_pda, bump_seed) = Pubkey::find_program_address(&[ESCROW_PDA_SEED], ctx.program_id);
-        let seeds = &[&ESCROW_PDA_SEED[..], &[bump_seed]];
+        if ctx.accounts.escrow_account.best_bid().is_some() {
+            return Err(ErrorCode::AuctionHasBids.into());
+        }
+        if ctx.accounts.escrow_account.state != EscrowState::Open {
+            return Err(ErrorCode::EscrowFunded.into());
+        }
 
-        // Transfers ownership of pda_deposit_token_account from
-        // pda_account -> escrow_account.initializer_key (the person who initialized the escrow).
-        token::set_authority(
-            ctx.accounts
-                .into_set_authority_context()
-                .with_signer(&[&seeds[..]]),
-            AuthorityType::AccountOwner,
-            Some(ctx.accounts.escrow_account.initializer_key),
+        let escrow_key = ctx.accounts.escrow_account.to_account_info().key;
+        ctx.accounts.order_book.remove(escrow_key);
+
+        refund_deposit(
+            &ctx.accounts.escrow_account,
+            &ctx.accounts.pda_deposit_token_account,
+            &ctx.accounts.initializer_deposit_token_account,
+            &ctx.accounts.initializer,
+            &ctx.accounts.pda_account,
+            &ctx.accounts.token_program,
         )?;
 
+        emit!(ctx
+            .accounts
+            .escrow_account
+            .cancelled_event(*escrow_key, false, Clock::get()?.slot));
         Ok(())
     }
 
-    pub fn exchange(ctx: Context<Exchange>) -> ProgramResult {
-        // Transferring from initializer to taker
-        let (_pda, bump_seed) = Pubkey::find_program_address(&[ESCROW_PDA_SEED], ctx.program_id);
-        let seeds = &[&ESCROW_PDA_SEED[..], &[bump_seed]];
+    // Lets the initializer change the amounts left to trade without cancelling,
+    // so the escrow keeps its address (and its place in the order book).
+    //
+    // In vault mode, the difference in initializer_amount is moved between the
+    // vault and initializer_deposit_token_account. Otherwise the PDA already owns
+    // the whole deposit account, so to offer more the initializer first transfers
+    // the extra tokens into it. Offering less isn't supported outside of vault
+    // mode, since there's nowhere to withdraw the difference to: the initializer
+    // would only get it back once the escrow closes. They can cancel and
+    // re-create the escrow instead.
+    pub fn update_escrow(
+        ctx: Context<UpdateEscrow>,
+        initializer_amount: u64,
+        taker_amount: u64,
+    ) -> ProgramResult {
+        zero_escrow_data(&ctx.accounts.escrow_account)?;
+        if initializer_amount == 0 || taker_amount == 0 {
+            return Err(ErrorCode::InvalidEscrowAmount.into());
+        }
+        if ctx
+            .accounts
+            .escrow_account
+            .is_expired(Clock::get()?.unix_timestamp)
+        {
+            return Err(ErrorCode::EscrowExpired.into());
+        }
+        if ctx.accounts.escrow_account.auction.is_some() {
+            return Err(ErrorCode::DutchAuctionUnsupported.into());
+        }
+        if ctx.accounts.escrow_account.english_auction.is_some() {
+            return Err(ErrorCode::EnglishAuctionUnsupported.into());
+        }
+        if ctx.accounts.escrow_account.state != EscrowState::Open {
+            return Err(ErrorCode::EscrowFunded.into());
+        }
+        if let Some(nft) = &ctx.accounts.escrow_account.nft {
+            if !nft.is_valid_amount(initializer_amount, taker_amount) {
+                return Err(ErrorCode::InvalidNftAmount.into());
+            }
+        }
 
-        // Transfers initializer_amount tokens from
-        // pda_deposit_token_account -> taker_receive_token_account.
-        token::transfer(
-            ctx.accounts
-                .into_transfer_to_taker_context()
-                .with_signer(&[&seeds[..]]),
-            ctx.accounts.escrow_account.initializer_amount,
-        )?;
+        let current_amount = ctx.accounts.escrow_account.initializer_amount;
+        if ctx.accounts.escrow_account.uses_vault() {
+            if initializer_amount > current_amount {
+                let top_up = initializer_amount - current_amount;
+                if ctx.accounts.initializer_deposit_token_account.amount < top_up {
+                    return Err(ErrorCode::InsufficientInitializerFunds.into());
+                }
+                // Transfers top_up tokens from
+                // initializer_deposit_token_account -> pda_deposit_token_account.
+                token::transfer(ctx.accounts.into_top_up_context(), top_up)?;
+            } else if initializer_amount < current_amount {
+                let escrow_key = ctx.accounts.escrow_account.to_account_info().key;
+                let seeds = &[
+                    ESCROW_PDA_SEED,
+                    escrow_key.as_ref(),
+                    &[ctx.accounts.escrow_account.bump],
+                ];
+                // Transfers the difference from
+                // pda_deposit_token_account -> initializer_deposit_token_account.
+                token::transfer(
+                    ctx.accounts
+                        .into_withdraw_context()
+                        .with_signer(&[&seeds[..]]),
+                    current_amount - initializer_amount,
+                )?;
+            }
+        } else if initializer_amount < current_amount {
+            return Err(ErrorCode::DepositDecreaseUnsupported.into());
+        } else if ctx.accounts.pda_deposit_token_account.amount < initializer_amount {
+            return Err(ErrorCode::InsufficientInitializerFunds.into());
+        }
 
-        // Transfers taker_amount tokens from
-        // taker_deposit_token_account -> initializer_receive_token_account.
-        token::transfer(
-            ctx.accounts.into_transfer_to_initializer_context(),
-            ctx.accounts.escrow_account.taker_amount,
-        )?;
+        let escrow_account = &mut ctx.accounts.escrow_account;
+        escrow_account.initializer_amount = initializer_amount;
+        escrow_account.taker_amount = taker_amount;
 
-        // Transfers ownership of pda_deposit_token_account from
-        // pda_account -> initializer_key
-        token::set_authority(
-            ctx.accounts
-                .into_set_authority_context()
-                .with_signer(&[&seeds[..]]),
-            AuthorityType::AccountOwner,
-            Some(ctx.accounts.escrow_account.initializer_key),
-        )?;
+        emit!(EscrowUpdated {
+            escrow: *escrow_account.to_account_info().key,
+            initializer: escrow_account.initializer_key,
+            initializer_amount,
+            taker_amount,
+            slot: Clock::get()?.slot,
+        });
+        Ok(())
+    }
 
+    // Lets the initializer delegate cancelling the escrow to another key (e.g. a
+    // bot that manages their orders). Passing None revokes the delegation.
+    pub fn set_canceller(ctx: Context<SetCanceller>, canceller: Option<Pubkey>) -> ProgramResult {
+        zero_escrow_data(&ctx.accounts.escrow_account)?;
+        ctx.accounts.escrow_account.canceller = canceller;
         Ok(())
     }
-}
 
-#[derive(Accounts)]
-#[instruction(initializer_amount: u64)]
-pub struct InitializeEscrow<'info> {
-    // The account of the person initializing the escrow.
-    #[account(signer)]
-    pub initializer: AccountInfo<'info>,
+    // Lets the initializer price the escrow as a Dutch auction (or, with None,
+    // go back to a fixed price at whatever the auction's current price is).
+    // Prices are for everything left in the escrow.
+    pub fn set_dutch_auction(
+        ctx: Context<SetDutchAuction>,
+        auction: Option<DutchAuction>,
+    ) -> ProgramResult {
+        zero_escrow_data(&ctx.accounts.escrow_account)?;
+        let escrow_account = &mut ctx.accounts.escrow_account;
+        if escrow_account.english_auction.is_some() {
+            return Err(ErrorCode::EnglishAuctionUnsupported.into());
+        }
+        if escrow_account.oracle.is_some() || escrow_account.arbiter.is_some() {
+            return Err(ErrorCode::InvalidDutchAuction.into());
+        }
+        if let Some(auction) = &auction {
+            // An NFT is always bought for exactly one token, so there's nothing to auction.
+            let requests_nft = escrow_account
+                .nft
+                .map_or(false, |nft| nft.leg == NftLeg::Requested);
+            if !auction.is_valid() || requests_nft {
+                return Err(ErrorCode::InvalidDutchAuction.into());
+            }
+        }
 
-    // A temporary token account that should be created prior to this instruction
-    // and owned by the initializer
-    //
-    // Ownership of this gets transferred from the initializer to this program's PDA.
-    #[account(
-        mut,
-        constraint = initializer_deposit_token_account.amount >= initializer_amount
-    )]
-    pub initializer_deposit_token_account: Account<'info, TokenAccount>,
+        let now = Clock::get()?.unix_timestamp;
+        escrow_account.taker_amount = match &auction {
+            Some(auction) => auction.price_at(now)?,
+            None => escrow_account.current_taker_amount(now)?,
+        };
+        escrow_account.auction = auction;
+        Ok(())
+    }
 
-    // The initializer's token account for the token they will receive should
-    // the trade go through.
-    pub initializer_receive_token_account: Account<'info, TokenAccount>,
+    // Turns the escrow into an English auction ending at ends_at, with bids of at
+    // least min_bid. Until it ends, the escrow can only be bid on.
+    pub fn start_english_auction(
+        ctx: Context<StartEnglishAuction>,
+        min_bid: u64,
+        ends_at: i64,
+    ) -> ProgramResult {
+        zero_escrow_data(&ctx.accounts.escrow_account)?;
+        let escrow_account = &mut ctx.accounts.escrow_account;
+        // Expiring escrows can be cranked by anyone, which would strand the bids,
+        // and a Dutch auction or an oracle already sets the price. An NFT is
+        // always bought for exactly one token, so there's nothing to bid on.
+        // Bidding and arbitration both lock the taker's payment, in different ways.
+        let requests_nft = escrow_account
+            .nft
+            .map_or(false, |nft| nft.leg == NftLeg::Requested);
+        if min_bid == 0
+            || ends_at <= Clock::get()?.unix_timestamp
+            || escrow_account.expires_at.is_some()
+            || escrow_account.auction.is_some()
+            || escrow_account.english_auction.is_some()
+            || escrow_account.oracle.is_some()
+            || escrow_account.arbiter.is_some()
+            || requests_nft
+        {
+            return Err(ErrorCode::InvalidEnglishAuction.into());
+        }
 
-    // The escrow account, it will hold all necessary info about the trade.
-    #[account(init, payer = initializer, space = 8 + EscrowAccount::LEN)]
-    pub escrow_account: Account<'info, EscrowAccount>,
+        escrow_account.taker_amount = min_bid;
+        escrow_account.english_auction = Some(EnglishAuction {
+            min_bid,
+            ends_at,
+            best_bid: None,
+        });
+        Ok(())
+    }
 
-    pub system_program: Program<'info, System>,
+    // Bids amount of the requested mint. The bid has to beat the current best
+    // bid, which is refunded to its bidder: pass its vault, refund token account,
+    // bidder and the bidder's associated token account for the requested mint
+    // (in that order) as remaining accounts. If the refund token account is no
+    // longer usable, the bid goes to the associated token account, which the new
+    // bidder pays for if need be. The best bidder can't raise their own bid,
+    // since their bid vault already exists. max_fee_bps is as in exchange, and
+    // is checked again when the auction settles.
+    pub fn place_bid<'info>(
+        ctx: Context<'_, '_, '_, 'info, PlaceBid<'info>>,
+        amount: u64,
+        max_fee_bps: u16,
+        _bid_vault_bump: u8,
+    ) -> ProgramResult {
+        zero_escrow_data(&ctx.accounts.escrow_account)?;
+        ctx.accounts.config.check_max_fee(max_fee_bps)?;
+        let auction = ctx
+            .accounts
+            .escrow_account
+            .english_auction
+            .ok_or(ErrorCode::NotAnEnglishAuction)?;
+        if Clock::get()?.unix_timestamp >= auction.ends_at {
+            return Err(ErrorCode::AuctionEnded.into());
+        }
+        if amount < auction.min_bid || auction.best_bid.map_or(false, |bid| amount <= bid.amount) {
+            return Err(ErrorCode::BidTooLow.into());
+        }
+        if ctx.accounts.bidder_deposit_token_account.amount < amount {
+            return Err(ErrorCode::InsufficientTakerFunds.into());
+        }
 
-    // The token program.
-    pub token_program: Program<'info, Token>,
-}
+        // Transfers amount tokens from bidder_deposit_token_account -> bid_vault.
+        token::transfer(ctx.accounts.into_transfer_to_bid_vault_context(), amount)?;
 
-#[derive(Accounts)]
-pub struct Exchange<'info> {
-    #[account(signer)]
-    pub taker: AccountInfo<'info>,
-    #[account(mut)]
-    pub taker_deposit_token_account: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub taker_receive_token_account: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub pda_deposit_token_account: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub initializer_receive_token_account: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub initializer_main_account: AccountInfo<'info>,
-    #[account(
-        mut,
-        constraint = escrow_account.taker_amount <= taker_deposit_token_account.amount,
-        constraint = escrow_account.initializer_deposit_token_account == *pda_deposit_token_account.to_account_info().key,
-        constraint = escrow_account.initializer_receive_token_account == *initializer_receive_token_account.to_account_info().key,
-        constraint = escrow_account.initializer_key == *initializer_main_account.key,
-        close = initializer_main_account
-    )]
-    pub escrow_account: Account<'info, EscrowAccount>,
-    pub pda_account: AccountInfo<'info>,
-    pub token_program: Program<'info, Token>,
-}
+        if let Some(previous_bid) = &auction.best_bid {
+            let (
+                previous_bid_vault,
+                refund_token_account,
+                previous_bidder,
+                associated_token_account,
+            ) = match ctx.remaining_accounts {
+                [vault, refund, bidder, associated] => (vault, refund, bidder, associated),
+                _ => return Err(ErrorCode::BidMismatch.into()),
+            };
+            let requested_mint = ctx.accounts.escrow_account.requested_mint;
+            if *previous_bid_vault.key != previous_bid.vault
+                || *refund_token_account.key != previous_bid.refund_token_account
+                || *previous_bidder.key != previous_bid.bidder
+                || *associated_token_account.key
+                    != get_associated_token_address(&previous_bid.bidder, &requested_mint)
+            {
+                return Err(ErrorCode::BidMismatch.into());
+            }
+            let refund_token_account = payout_account(
+                refund_token_account,
+                ctx.accounts.into_create_associated_token_account_context(
+                    associated_token_account,
+                    previous_bidder,
+                ),
+            )?;
+            refund_bid(
+                &ctx.accounts.escrow_account,
+                &Account::try_from(previous_bid_vault)?,
+                &refund_token_account,
+                previous_bidder,
+                &ctx.accounts.pda_account,
+                &ctx.accounts.token_program,
+            )?;
+        }
 
-#[derive(Accounts)]
-pub struct CancelEscrow<'info> {
-    pub initializer: AccountInfo<'info>,
-    #[account(mut)]
-    pub pda_deposit_token_account: Account<'info, TokenAccount>,
-    pub pda_account: AccountInfo<'info>,
-    #[account(
-        mut,
-        constraint = escrow_account.initializer_key == *initializer.key,
-        constraint = escrow_account.initializer_deposit_token_account == *pda_deposit_token_account.to_account_info().key,
-        close = initializer
-    )]
-    pub escrow_account: Account<'info, EscrowAccount>,
-    pub token_program: Program<'info, Token>,
-}
+        let escrow_account = &mut ctx.accounts.escrow_account;
+        escrow_account.taker_amount = amount;
+        escrow_account.english_auction = Some(EnglishAuction {
+            best_bid: Some(Bid {
+                bidder: *ctx.accounts.bidder.key,
+                amount,
+                vault: *ctx.accounts.bid_vault.to_account_info().key,
+                refund_token_account: *ctx
+                    .accounts
+                    .bidder_deposit_token_account
+                    .to_account_info()
+                    .key,
+                receive_token_account: *ctx
+                    .accounts
+                    .bidder_receive_token_account
+                    .to_account_info()
+                    .key,
+                max_fee_bps,
+            }),
+            ..auction
+        });
 
-#[account]
-pub struct EscrowAccount {
-    pub initializer_key: Pubkey,
-    pub initializer_deposit_token_account: Pubkey,
-    pub initializer_receive_token_account: Pubkey,
-    pub initializer_amount: u64,
-    pub taker_amount: u64,
-}
+        emit!(BidPlaced {
+            escrow: *escrow_account.to_account_info().key,
+            bidder: *ctx.accounts.bidder.key,
+            amount,
+            slot: Clock::get()?.slot,
+        });
+        Ok(())
+    }
 
-impl EscrowAccount {
-    pub const LEN: usize = 32 + 32 + 32 + 8 + 8;
-}
+    // Once an English auction has ended, anyone can settle it: the deposit goes
+    // to the winner and the winning bid to the initializer, less the protocol fee
+    // on each leg as in exchange. If either side has closed (or repurposed) the
+    // token account it named, its share goes to its associated token account
+    // instead, which payer pays for if it doesn't exist yet. NFT escrows need
+    // the same remaining accounts as exchange.
+    pub fn settle(ctx: Context<Settle>) -> ProgramResult {
+        let auction = ctx
+            .accounts
+            .escrow_account
+            .english_auction
+            .ok_or(ErrorCode::NotAnEnglishAuction)?;
+        if Clock::get()?.unix_timestamp < auction.ends_at {
+            return Err(ErrorCode::AuctionNotEnded.into());
+        }
+        let bid = auction.best_bid.ok_or(ErrorCode::NoBids)?;
+        ctx.accounts
+            .escrow_account
+            .verify_nft(ctx.remaining_accounts)?;
+        ctx.accounts.settle(&bid)
+    }
 
-impl<'info> From<&mut InitializeEscrow<'info>>
-    for CpiContext<'_, '_, '_, 'info, SetAuthority<'info>>
-{
-    fn from(accounts: &mut InitializeEscrow<'info>) -> Self {
-        let cpi_accounts = SetAuthority {
-            // The account whose authority should be changed.
-            account_or_mint: accounts
-                .initializer_deposit_token_account
-                .to_account_info()
-                .clone(),
-            // Current authority (owner) of account_or_mint.
-            current_authority: accounts.initializer.clone(),
-        };
-        let cpi_program = accounts.token_program.to_account_info();
-        CpiContext::new(cpi_program, cpi_accounts)
+    // Prices the escrow off an oracle (or, with None, goes back to the stored
+    // taker_amount). Oracle-priced escrows pass the price account as the first
+    // remaining account to exchange, ahead of any NFT accounts.
+    pub fn set_oracle_pricing(
+        ctx: Context<SetOraclePricing>,
+        oracle: Option<OraclePricing>,
+    ) -> ProgramResult {
+        zero_escrow_data(&ctx.accounts.escrow_account)?;
+        let escrow_account = &mut ctx.accounts.escrow_account;
+        if let Some(oracle) = &oracle {
+            let requests_nft = escrow_account
+                .nft
+                .map_or(false, |nft| nft.leg == NftLeg::Requested);
+            if !oracle.is_valid()
+                || escrow_account.auction.is_some()
+                || escrow_account.english_auction.is_some()
+                || escrow_account.arbiter.is_some()
+                || requests_nft
+            {
+                return Err(ErrorCode::InvalidOraclePricing.into());
+            }
+        }
+        escrow_account.oracle = oracle;
+        Ok(())
     }
-}
 
-impl<'info> CancelEscrow<'info> {
-    fn into_set_authority_context(&self) -> CpiContext<'_, '_, '_, 'info, SetAuthority<'info>> {
-        let cpi_accounts = SetAuthority {
-            // The account whose authority should be changed.
-            account_or_mint: self.pda_deposit_token_account.to_account_info().clone(),
-            // Current authority (owner) of account_or_mint.
-            current_authority: self.pda_account.clone(),
-        };
-        let cpi_program = self.token_program.to_account_info();
-        CpiContext::new(cpi_program, cpi_accounts)
+    // Names the arbiter who resolves disputes (see fund), or with None makes the
+    // escrow a plain one again. Only for open vault-mode escrows, since the
+    // deposit may have to be split.
+    pub fn set_arbiter(ctx: Context<SetArbiter>, arbiter: Option<Pubkey>) -> ProgramResult {
+        zero_escrow_data(&ctx.accounts.escrow_account)?;
+        let escrow_account = &mut ctx.accounts.escrow_account;
+        if escrow_account.state != EscrowState::Open
+            || !escrow_account.uses_vault()
+            || escrow_account.auction.is_some()
+            || escrow_account.english_auction.is_some()
+            || escrow_account.oracle.is_some()
+        {
+            return Err(ErrorCode::InvalidArbiter.into());
+        }
+        escrow_account.arbiter = arbiter;
+        Ok(())
     }
-}
 
-impl<'info> Exchange<'info> {
-    fn into_set_authority_context(&self) -> CpiContext<'_, '_, '_, 'info, SetAuthority<'info>> {
-        let cpi_accounts = SetAuthority {
-            account_or_mint: self.pda_deposit_token_account.to_account_info().clone(),
-            current_authority: self.pda_account.clone(),
-        };
-        let cpi_program = self.token_program.to_account_info();
-        CpiContext::new(cpi_program, cpi_accounts)
+    // Takes an arbitrated escrow: the taker's payment is locked in the taker
+    // vault until both parties approve the trade or the arbiter resolves a
+    // dispute. The expected amounts work as in exchange, and expected_arbiter
+    // likewise pins the arbiter, so the initializer can't swap in one of their
+    // own after the taker has signed. NFT escrows need the same remaining
+    // accounts as exchange.
+    pub fn fund(
+        ctx: Context<Fund>,
+        expected_initializer_amount: u64,
+        expected_taker_amount: u64,
+        expected_arbiter: Pubkey,
+        max_fee_bps: u16,
+        _taker_vault_bump: u8,
+    ) -> ProgramResult {
+        zero_escrow_data(&ctx.accounts.escrow_account)?;
+        ctx.accounts.config.check_max_fee(max_fee_bps)?;
+        let escrow_account = &ctx.accounts.escrow_account;
+        if escrow_account.arbiter.is_none() {
+            return Err(ErrorCode::NotArbitrated.into());
+        }
+        if escrow_account.state != EscrowState::Open {
+            return Err(ErrorCode::InvalidEscrowState.into());
+        }
+        if escrow_account.is_expired(Clock::get()?.unix_timestamp) {
+            return Err(ErrorCode::EscrowExpired.into());
+        }
+        if escrow_account.initializer_amount != expected_initializer_amount
+            || escrow_account.taker_amount != expected_taker_amount
+            || escrow_account.arbiter != Some(expected_arbiter)
+        {
+            return Err(ErrorCode::TermsMismatch.into());
+        }
+        escrow_account.verify_nft(ctx.remaining_accounts)?;
+        let taker_amount = escrow_account.taker_amount;
+        if ctx.accounts.taker_deposit_token_account.amount < taker_amount {
+            return Err(ErrorCode::InsufficientTakerFunds.into());
+        }
+
+        // Transfers taker_amount tokens from
+        // taker_deposit_token_account -> taker_vault.
+        token::transfer(
+            ctx.accounts.into_transfer_to_taker_vault_context(),
+            taker_amount,
+        )?;
+
+        // The escrow is spoken for, so it comes off the order book.
+        let escrow_key = ctx.accounts.escrow_account.to_account_info().key;
+        ctx.accounts.order_book.remove(escrow_key);
+
+        let escrow_account = &mut ctx.accounts.escrow_account;
+        escrow_account.state = EscrowState::Funded;
+        escrow_account.funding = Some(Funding {
+            taker: *ctx.accounts.taker.key,
+            taker_vault: *ctx.accounts.taker_vault.to_account_info().key,
+            taker_deposit_token_account: *ctx
+                .accounts
+                .taker_deposit_token_account
+                .to_account_info()
+                .key,
+            taker_receive_token_account: *ctx
+                .accounts
+                .taker_receive_token_account
+                .to_account_info()
+                .key,
+            initializer_approved: false,
+            taker_approved: false,
+            max_fee_bps,
+        });
+        Ok(())
     }
-}
 
-impl<'info> Exchange<'info> {
-    fn into_transfer_to_taker_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
-        let cpi_accounts = Transfer {
-            from: self.pda_deposit_token_account.to_account_info().clone(),
-            to: self.taker_receive_token_account.to_account_info().clone(),
-            authority: self.pda_account.clone(),
-        };
-        let cpi_program = self.token_program.to_account_info();
-        CpiContext::new(cpi_program, cpi_accounts)
+    // Approves a funded escrow as the initializer or the taker. Once both have
+    // approved, anyone can release it.
+    pub fn approve(ctx: Context<Approve>) -> ProgramResult {
+        zero_escrow_data(&ctx.accounts.escrow_account)?;
+        let escrow_account = &mut ctx.accounts.escrow_account;
+        let (is_initializer, is_taker) = escrow_account.funded_party(ctx.accounts.authority.key)?;
+        let mut funding = escrow_account
+            .funding
+            .ok_or(ErrorCode::InvalidEscrowState)?;
+        // The initializer and the taker can be the same key.
+        funding.initializer_approved |= is_initializer;
+        funding.taker_approved |= is_taker;
+        if funding.initializer_approved && funding.taker_approved {
+            escrow_account.state = EscrowState::Approved;
+        }
+        escrow_account.funding = Some(funding);
+        Ok(())
     }
-}
 
-impl<'info> Exchange<'info> {
-    fn into_transfer_to_initializer_context(
-        &self,
-    ) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+    // Disputes a funded escrow as the initializer or the taker, leaving it to the
+    // arbiter to resolve.
+    pub fn dispute(ctx: Context<Dispute>) -> ProgramResult {
+        zero_escrow_data(&ctx.accounts.escrow_account)?;
+        let escrow_account = &mut ctx.accounts.escrow_account;
+        escrow_account.funded_party(ctx.accounts.authority.key)?;
+        escrow_account.state = EscrowState::Disputed;
+        Ok(())
+    }
+
+    // Settles an approved escrow: the deposit goes to the taker and the payment
+    // to the initializer, as in exchange. As with settle, a party whose token
+    // account is gone is paid in their associated token account instead (see
+    // payout_account), so neither side can lock up both vaults by closing theirs.
+    pub fn release(ctx: Context<SettleArbitration>) -> ProgramResult {
+        if ctx.accounts.escrow_account.state != EscrowState::Approved {
+            return Err(ErrorCode::InvalidEscrowState.into());
+        }
+        let initializer_amount = ctx.accounts.escrow_account.initializer_amount;
+        let taker_amount = ctx.accounts.escrow_account.taker_amount;
+        ctx.accounts.settle(initializer_amount, taker_amount)
+    }
+
+    // Lets the arbiter settle a disputed escrow by splitting both deposits:
+    // to_taker of the deposit goes to the taker and to_initializer of the
+    // payment to the initializer, and the rest of each goes back to whoever put
+    // it in.
+    pub fn resolve(
+        ctx: Context<SettleArbitration>,
+        to_taker: u64,
+        to_initializer: u64,
+    ) -> ProgramResult {
+        let escrow_account = &ctx.accounts.escrow_account;
+        if escrow_account.state != EscrowState::Disputed {
+            return Err(ErrorCode::InvalidEscrowState.into());
+        }
+        if escrow_account.arbiter != Some(*ctx.accounts.authority.key) {
+            return Err(ErrorCode::UnauthorizedArbiter.into());
+        }
+        if to_taker > escrow_account.initializer_amount
+            || to_initializer > escrow_account.taker_amount
+        {
+            return Err(ErrorCode::InvalidFillAmount.into());
+        }
+        ctx.accounts.settle(to_taker, to_initializer)
+    }
+
+    // Logs the escrow's current taker_amount, which for a Dutch auction is the
+    // price an exchange would be charged right now. Clients can simulate this
+    // rather than doing the math themselves.
+    pub fn log_dutch_auction_price(ctx: Context<LogDutchAuctionPrice>) -> ProgramResult {
+        let price = ctx
+            .accounts
+            .escrow_account
+            .current_taker_amount(Clock::get()?.unix_timestamp)?;
+        msg!("Current price: {}", price);
+        Ok(())
+    }
+
+    // Once an escrow has expired, anyone can crank it: the deposit goes back to
+    // the initializer exactly as if they had cancelled it themselves.
+    pub fn crank_expired(ctx: Context<CrankExpired>) -> ProgramResult {
+        let now = Clock::get()?.unix_timestamp;
+        if !ctx.accounts.escrow_account.is_expired(now) {
+            return Err(ErrorCode::EscrowNotExpired.into());
+        }
+        if ctx.accounts.escrow_account.best_bid().is_some() {
+            return Err(ErrorCode::AuctionHasBids.into());
+        }
+        if ctx.accounts.escrow_account.state != EscrowState::Open {
+            return Err(ErrorCode::EscrowFunded.into());
+        }
+
+        let escrow_key = ctx.accounts.escrow_account.to_account_info().key;
+        ctx.accounts.order_book.remove(escrow_key);
+
+        refund_deposit(
+            &ctx.accounts.escrow_account,
+            &ctx.accounts.pda_deposit_token_account,
+            &ctx.accounts.initializer_deposit_token_account,
+            &ctx.accounts.initializer,
+            &ctx.accounts.pda_account,
+            &ctx.accounts.token_program,
+        )?;
+
+        emit!(ctx
+            .accounts
+            .escrow_account
+            .cancelled_event(*escrow_key, true, Clock::get()?.slot));
+        Ok(())
+    }
+
+    // Closes an escrow whose deposit account no longer holds initializer_amount
+    // (or has been closed altogether), which would otherwise leave its rent stuck
+    // since no exchange can ever succeed. Like crank_expired anyone can call it:
+    // the rent, and whatever is left of the deposit, go back to the initializer.
+    pub fn reclaim(ctx: Context<Reclaim>) -> ProgramResult {
+        // The deposit only counts if it's still a token account the PDA controls.
+        let deposit = Account::<TokenAccount>::try_from(&ctx.accounts.pda_deposit_token_account)
+            .ok()
+            .filter(|deposit| deposit.owner == *ctx.accounts.pda_account.key);
+        let remaining = deposit.as_ref().map_or(0, |deposit| deposit.amount);
+        if remaining >= ctx.accounts.escrow_account.initializer_amount {
+            return Err(ErrorCode::EscrowNotStale.into());
+        }
+        if ctx.accounts.escrow_account.best_bid().is_some() {
+            return Err(ErrorCode::AuctionHasBids.into());
+        }
+        if ctx.accounts.escrow_account.state != EscrowState::Open {
+            return Err(ErrorCode::EscrowFunded.into());
+        }
+
+        let escrow_key = ctx.accounts.escrow_account.to_account_info().key;
+        ctx.accounts.order_book.remove(escrow_key);
+
+        // Hand back whatever is left, the same way a cancel would.
+        if let Some(deposit) = &deposit {
+            let initializer_deposit_token_account =
+                Account::<TokenAccount>::try_from(&ctx.accounts.initializer_deposit_token_account)?;
+            refund_deposit(
+                &ctx.accounts.escrow_account,
+                deposit,
+                &initializer_deposit_token_account,
+                &ctx.accounts.initializer,
+                &ctx.accounts.pda_account,
+                &ctx.accounts.token_program,
+            )?;
+        }
+
+        emit!(EscrowReclaimed {
+            escrow: *escrow_key,
+            initializer: ctx.accounts.escrow_account.initializer_key,
+            initializer_amount: ctx.accounts.escrow_account.initializer_amount,
+            remaining,
+            slot: Clock::get()?.slot,
+        });
+        Ok(())
+    }
+
+    // Exchanges an escrow created by the original program (see EscrowAccountV1)
+    // at its own address. v1 escrows trade everything at once, have no vault and
+    // aren't listed in any order book; otherwise this is exchange, fees
+    // included. The deposit is owned by the program-wide PDA v1 used.
+    pub fn exchange_legacy_escrow(
+        ctx: Context<ExchangeLegacyEscrow>,
+        expected_initializer_amount: u64,
+        expected_taker_amount: u64,
+        max_fee_bps: u16,
+        legacy_bump: u8,
+    ) -> ProgramResult {
+        let legacy = ctx.accounts.legacy_escrow(ctx.program_id)?;
+        if legacy.initializer_amount != expected_initializer_amount
+            || legacy.taker_amount != expected_taker_amount
+        {
+            return Err(ErrorCode::TermsMismatch.into());
+        }
+        ctx.accounts.config.check_max_fee(max_fee_bps)?;
+        if ctx.accounts.taker_deposit_token_account.amount < legacy.taker_amount {
+            return Err(ErrorCode::InsufficientTakerFunds.into());
+        }
+
+        let offered_fee = ctx
+            .accounts
+            .config
+            .offered_leg_fee(legacy.initializer_amount)?;
+        let requested_fee = ctx.accounts.config.requested_leg_fee(legacy.taker_amount)?;
+        let seeds = &[ESCROW_PDA_SEED, &[legacy_bump]];
+
+        // Transfers initializer_amount tokens (less the fee) from
+        // pda_deposit_token_account -> taker_receive_token_account.
+        transfer_less_fee(
+            &ctx.accounts.token_program,
+            Transfer {
+                from: ctx.accounts.pda_deposit_token_account.to_account_info(),
+                to: ctx.accounts.taker_receive_token_account.to_account_info(),
+                authority: ctx.accounts.legacy_pda_account.clone(),
+            },
+            &ctx.accounts.offered_fee_token_account,
+            &[&seeds[..]],
+            legacy.initializer_amount,
+            offered_fee,
+        )?;
+
+        // Transfers taker_amount tokens (less the fee) from
+        // taker_deposit_token_account -> initializer_receive_token_account.
+        transfer_less_fee(
+            &ctx.accounts.token_program,
+            Transfer {
+                from: ctx.accounts.taker_deposit_token_account.to_account_info(),
+                to: ctx
+                    .accounts
+                    .initializer_receive_token_account
+                    .to_account_info(),
+                authority: ctx.accounts.taker.clone(),
+            },
+            &ctx.accounts.requested_fee_token_account,
+            &[],
+            legacy.taker_amount,
+            requested_fee,
+        )?;
+
+        // Transfers ownership of pda_deposit_token_account from
+        // legacy_pda_account -> initializer_key
+        token::set_authority(
+            ctx.accounts
+                .into_set_authority_context()
+                .with_signer(&[&seeds[..]]),
+            AuthorityType::AccountOwner,
+            Some(legacy.initializer_key),
+        )?;
+
+        close_legacy_escrow(
+            &ctx.accounts.legacy_escrow_account,
+            &ctx.accounts.initializer_main_account,
+        )?;
+
+        let mut event = legacy.exchanged_event(
+            *ctx.accounts.legacy_escrow_account.key,
+            *ctx.accounts.taker.key,
+            ctx.accounts.pda_deposit_token_account.mint,
+            ctx.accounts.initializer_receive_token_account.mint,
+            Clock::get()?.slot,
+        );
+        event.offered_fee = offered_fee;
+        event.requested_fee = requested_fee;
+        emit!(event);
+        Ok(())
+    }
+
+    // Cancels an escrow created by the original program at its own address,
+    // handing the deposit back to the initializer. v1 escrows have no canceller,
+    // so only the initializer can do this.
+    pub fn cancel_legacy_escrow(
+        ctx: Context<CancelLegacyEscrow>,
+        legacy_bump: u8,
+    ) -> ProgramResult {
+        let legacy = EscrowAccountV1::try_from_account_info(
+            &ctx.accounts.legacy_escrow_account,
+            ctx.program_id,
+        )?;
+        if legacy.initializer_key != *ctx.accounts.initializer.key
+            || legacy.initializer_deposit_token_account
+                != *ctx.accounts.pda_deposit_token_account.to_account_info().key
+            || legacy.initializer_receive_token_account
+                != *ctx
+                    .accounts
+                    .initializer_receive_token_account
+                    .to_account_info()
+                    .key
+        {
+            return Err(ErrorCode::LegacyEscrowMismatch.into());
+        }
+
+        // Transfers ownership of pda_deposit_token_account from
+        // legacy_pda_account -> initializer_key
+        let seeds = &[ESCROW_PDA_SEED, &[legacy_bump]];
+        token::set_authority(
+            ctx.accounts
+                .into_set_authority_context()
+                .with_signer(&[&seeds[..]]),
+            AuthorityType::AccountOwner,
+            Some(legacy.initializer_key),
+        )?;
+
+        close_legacy_escrow(
+            &ctx.accounts.legacy_escrow_account,
+            &ctx.accounts.initializer,
+        )?;
+
+        emit!(legacy.cancelled_event(
+            *ctx.accounts.legacy_escrow_account.key,
+            ctx.accounts.pda_deposit_token_account.mint,
+            ctx.accounts.initializer_receive_token_account.mint,
+            Clock::get()?.slot,
+        ));
+        Ok(())
+    }
+
+    // The taker passes the amounts they expect to trade, so that they can't be
+    // caught out by the terms changing (say, through update_escrow or a partial
+    // fill) between signing and the transaction landing. Likewise, max_fee_bps
+    // is the highest fee they accept on either leg, in case the admin raises
+    // fees in the meantime.
+    pub fn exchange(
+        ctx: Context<Exchange>,
+        expected_initializer_amount: u64,
+        expected_taker_amount: u64,
+        max_fee_bps: u16,
+    ) -> ProgramResult {
+        ctx.accounts.exchange_in_full(
+            expected_initializer_amount,
+            expected_taker_amount,
+            max_fee_bps,
+            ctx.remaining_accounts,
+        )
+    }
+
+    // Exchanges several escrows in full, all or nothing, so a market maker can
+    // sweep an order book in one transaction. For the i-th escrow,
+    // ctx.remaining_accounts holds the accounts exchange takes, in the order of
+    // Exchange's fields, and expected_initializer_amounts[i] and
+    // expected_taker_amounts[i] are the amounts passed to exchange, and
+    // max_fee_bps applies to every exchange. Each escrow is checked exactly as
+    // exchange checks it, and if any of them fails the whole batch does. Every
+    // exchange must be by the same taker. NFT and oracle-priced escrows need
+    // extra accounts to exchange, so they can't be batched.
+    pub fn exchange_many<'info>(
+        ctx: Context<'_, '_, '_, 'info, ExchangeMany<'info>>,
+        expected_initializer_amounts: Vec<u64>,
+        expected_taker_amounts: Vec<u64>,
+        max_fee_bps: u16,
+    ) -> ProgramResult {
+        if expected_initializer_amounts.is_empty()
+            || expected_initializer_amounts.len() != expected_taker_amounts.len()
+        {
+            return Err(ErrorCode::ExchangeManyAccountMismatch.into());
+        }
+
+        let mut accounts = ctx.remaining_accounts;
+        for (expected_initializer_amount, expected_taker_amount) in expected_initializer_amounts
+            .into_iter()
+            .zip(expected_taker_amounts)
+        {
+            // Runs each exchange the way the program's entrypoint would: check
+            // the accounts, exchange, then write the accounts back (closing the
+            // escrow). Writing them back before the next exchange means later
+            // exchanges see the changes, e.g. to an order book they share.
+            let mut exchange = Exchange::try_accounts(ctx.program_id, &mut accounts, &[])?;
+            if exchange.taker.key != ctx.accounts.taker.key {
+                return Err(ErrorCode::ExchangeManyAccountMismatch.into());
+            }
+            exchange.exchange_in_full(
+                expected_initializer_amount,
+                expected_taker_amount,
+                max_fee_bps,
+                &[],
+            )?;
+            exchange.exit(ctx.program_id)?;
+        }
+        if !accounts.is_empty() {
+            return Err(ErrorCode::ExchangeManyAccountMismatch.into());
+        }
+        Ok(())
+    }
+
+    // Fills part of the escrow: the taker pays taker_amount and receives the
+    // matching share of the deposit at the escrow's price. The escrow stays open,
+    // so taker_amount must be less than what's left; the last fill goes through
+    // exchange, which closes the escrow. As with exchange, the taker passes the
+    // amounts they expect to be left in the escrow before the fill (which fixes
+    // the price they pay) and max_fee_bps. If someone else fills the escrow
+    // first, the amounts no longer match and the taker has to retry.
+    pub fn exchange_partial(
+        ctx: Context<ExchangePartial>,
+        taker_amount: u64,
+        expected_initializer_amount: u64,
+        expected_taker_amount: u64,
+        max_fee_bps: u16,
+    ) -> ProgramResult {
+        zero_escrow_data(&ctx.accounts.escrow_account)?;
+        if ctx.accounts.escrow_account.auction.is_some() {
+            return Err(ErrorCode::DutchAuctionUnsupported.into());
+        }
+        // The expected amounts only pin the escrow's stored price, so a partial
+        // fill can't follow a moving one.
+        if ctx.accounts.escrow_account.oracle.is_some() {
+            return Err(ErrorCode::OraclePricingUnsupported.into());
+        }
+        if ctx.accounts.escrow_account.initializer_amount != expected_initializer_amount
+            || ctx.accounts.escrow_account.taker_amount != expected_taker_amount
+        {
+            return Err(ErrorCode::TermsMismatch.into());
+        }
+        if taker_amount >= ctx.accounts.escrow_account.taker_amount {
+            return Err(ErrorCode::InvalidFillAmount.into());
+        }
+        ctx.accounts
+            .escrow_account
+            .verify_nft(ctx.remaining_accounts)?;
+
+        ctx.accounts.fill_accounts().fill(taker_amount, max_fee_bps)
+    }
+
+    // Offers initializer_amount lamports for taker_amount of the token held by
+    // initializer_receive_token_account. The lamports are held by the escrow
+    // account itself, on top of its rent.
+    pub fn initialize_escrow_offering_sol(
+        ctx: Context<InitializeEscrowOfferingSol>,
+        initializer_amount: u64,
+        taker_amount: u64,
+        expires_at: Option<i64>,
+        allowed_taker: Option<Pubkey>,
+    ) -> ProgramResult {
+        validate_expiry(expires_at)?;
+
+        let escrow_account = &mut ctx.accounts.escrow_account;
+        escrow_account.version = ESCROW_ACCOUNT_VERSION;
+        escrow_account.initializer_key = *ctx.accounts.initializer.key;
+        escrow_account.initializer_receive_token_account = *ctx
+            .accounts
+            .initializer_receive_token_account
+            .to_account_info()
+            .key;
+        escrow_account.offered_mint = spl_token::native_mint::id();
+        escrow_account.requested_mint = ctx.accounts.initializer_receive_token_account.mint;
+        escrow_account.initializer_amount = initializer_amount;
+        escrow_account.taker_amount = taker_amount;
+        escrow_account.expires_at = expires_at;
+        escrow_account.allowed_taker = allowed_taker;
+        escrow_account.native_leg = NativeLeg::Offered;
+
+        let escrow_key = *escrow_account.to_account_info().key;
+        ctx.accounts.order_book.insert(escrow_key);
+        emit!(ctx
+            .accounts
+            .escrow_account
+            .created_event(escrow_key, Clock::get()?.slot));
+
+        // Transfers initializer_amount lamports from
+        // initializer -> escrow_account.
+        invoke(
+            &system_instruction::transfer(
+                ctx.accounts.initializer.key,
+                ctx.accounts.escrow_account.to_account_info().key,
+                initializer_amount,
+            ),
+            &[
+                ctx.accounts.initializer.clone(),
+                ctx.accounts.escrow_account.to_account_info(),
+                ctx.accounts.system_program.to_account_info(),
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    // Offers initializer_amount tokens (deposited in a vault, as in
+    // initialize_escrow_with_vault) for taker_amount lamports.
+    pub fn initialize_escrow_requesting_sol(
+        ctx: Context<InitializeEscrowRequestingSol>,
+        initializer_amount: u64,
+        taker_amount: u64,
+        bump: u8,
+        _vault_bump: u8,
+        expires_at: Option<i64>,
+        allowed_taker: Option<Pubkey>,
+    ) -> ProgramResult {
+        validate_expiry(expires_at)?;
+
+        let escrow_account = &mut ctx.accounts.escrow_account;
+        escrow_account.version = ESCROW_ACCOUNT_VERSION;
+        escrow_account.initializer_key = *ctx.accounts.initializer.key;
+        escrow_account.initializer_deposit_token_account = *ctx
+            .accounts
+            .initializer_deposit_token_account
+            .to_account_info()
+            .key;
+        // The lamports are paid straight to the initializer's wallet.
+        escrow_account.initializer_receive_token_account = *ctx.accounts.initializer.key;
+        escrow_account.offered_mint = ctx.accounts.initializer_deposit_token_account.mint;
+        escrow_account.requested_mint = spl_token::native_mint::id();
+        escrow_account.initializer_amount = initializer_amount;
+        escrow_account.taker_amount = taker_amount;
+        escrow_account.expires_at = expires_at;
+        escrow_account.allowed_taker = allowed_taker;
+        escrow_account.bump = bump;
+        escrow_account.vault = *ctx.accounts.vault.to_account_info().key;
+        escrow_account.native_leg = NativeLeg::Requested;
+
+        let escrow_key = *escrow_account.to_account_info().key;
+        ctx.accounts.order_book.insert(escrow_key);
+        emit!(ctx
+            .accounts
+            .escrow_account
+            .created_event(escrow_key, Clock::get()?.slot));
+
+        // Transfers initializer_amount tokens from
+        // initializer_deposit_token_account -> vault.
+        token::transfer(
+            ctx.accounts.into_transfer_to_vault_context(),
+            initializer_amount,
+        )?;
+
+        Ok(())
+    }
+
+    // The taker pays taker_amount tokens and receives the escrowed lamports.
+    // Native escrows are all-or-nothing, there are no partial fills. max_fee_bps
+    // is as in exchange; the fee on the lamports is paid to the config's
+    // fee_recipient itself.
+    pub fn exchange_sol_offer(ctx: Context<ExchangeSolOffer>, max_fee_bps: u16) -> ProgramResult {
+        ctx.accounts.config.check_max_fee(max_fee_bps)?;
+        if ctx
+            .accounts
+            .escrow_account
+            .is_expired(Clock::get()?.unix_timestamp)
+        {
+            return Err(ErrorCode::EscrowExpired.into());
+        }
+        let taker_amount = ctx.accounts.escrow_account.taker_amount;
+        if ctx.accounts.taker_deposit_token_account.amount < taker_amount {
+            return Err(ErrorCode::InsufficientTakerFunds.into());
+        }
+
+        // The protocol fee on each leg comes out of what the other side receives.
+        let initializer_amount = ctx.accounts.escrow_account.initializer_amount;
+        let offered_fee = ctx.accounts.config.offered_leg_fee(initializer_amount)?;
+        let requested_fee = ctx.accounts.config.requested_leg_fee(taker_amount)?;
+
+        // Transfers initializer_amount lamports from escrow_account -> taker,
+        // less the fee, which goes to fee_recipient. The program owns
+        // escrow_account, so it can debit it directly. The rent left behind goes
+        // to the initializer when the escrow account is closed.
+        **ctx
+            .accounts
+            .escrow_account
+            .to_account_info()
+            .try_borrow_mut_lamports()? -= initializer_amount;
+        **ctx.accounts.taker.try_borrow_mut_lamports()? += initializer_amount
+            .checked_sub(offered_fee)
+            .ok_or(ErrorCode::NumericalOverflow)?;
+        **ctx.accounts.fee_recipient.try_borrow_mut_lamports()? += offered_fee;
+
+        // Transfers taker_amount tokens (less the fee) from
+        // taker_deposit_token_account -> initializer_receive_token_account.
+        transfer_less_fee(
+            &ctx.accounts.token_program,
+            Transfer {
+                from: ctx.accounts.taker_deposit_token_account.to_account_info(),
+                to: ctx
+                    .accounts
+                    .initializer_receive_token_account
+                    .to_account_info(),
+                authority: ctx.accounts.taker.clone(),
+            },
+            &ctx.accounts.requested_fee_token_account,
+            &[],
+            taker_amount,
+            requested_fee,
+        )?;
+
+        let escrow_key = ctx.accounts.escrow_account.to_account_info().key;
+        ctx.accounts.order_book.remove(escrow_key);
+
+        // Nothing left to trade.
+        ctx.accounts.escrow_account.initializer_amount = 0;
+        ctx.accounts.escrow_account.taker_amount = 0;
+        let mut event = ctx.accounts.escrow_account.exchanged_event(
+            *escrow_key,
+            *ctx.accounts.taker.key,
+            initializer_amount,
+            taker_amount,
+            Clock::get()?.slot,
+        );
+        event.offered_fee = offered_fee;
+        event.requested_fee = requested_fee;
+        emit!(event);
+        Ok(())
+    }
+
+    // The taker pays taker_amount lamports and receives the tokens in the vault.
+    // Native escrows are all-or-nothing, there are no partial fills. max_fee_bps
+    // is as in exchange; the fee on the lamports is paid to the config's
+    // fee_recipient itself.
+    pub fn exchange_sol_request(
+        ctx: Context<ExchangeSolRequest>,
+        max_fee_bps: u16,
+    ) -> ProgramResult {
+        ctx.accounts.config.check_max_fee(max_fee_bps)?;
+        if ctx
+            .accounts
+            .escrow_account
+            .is_expired(Clock::get()?.unix_timestamp)
+        {
+            return Err(ErrorCode::EscrowExpired.into());
+        }
+
+        let escrow_key = ctx.accounts.escrow_account.to_account_info().key;
+        let seeds = &[
+            ESCROW_PDA_SEED,
+            escrow_key.as_ref(),
+            &[ctx.accounts.escrow_account.bump],
+        ];
+
+        // The protocol fee on each leg comes out of what the other side receives.
+        let offered_fee = ctx
+            .accounts
+            .config
+            .offered_leg_fee(ctx.accounts.escrow_account.initializer_amount)?;
+        let requested_fee = ctx
+            .accounts
+            .config
+            .requested_leg_fee(ctx.accounts.escrow_account.taker_amount)?;
+
+        // Transfers taker_amount lamports (less the fee) from
+        // taker -> initializer_main_account.
+        invoke(
+            &system_instruction::transfer(
+                ctx.accounts.taker.key,
+                ctx.accounts.initializer_main_account.key,
+                ctx.accounts
+                    .escrow_account
+                    .taker_amount
+                    .checked_sub(requested_fee)
+                    .ok_or(ErrorCode::NumericalOverflow)?,
+            ),
+            &[
+                ctx.accounts.taker.clone(),
+                ctx.accounts.initializer_main_account.clone(),
+                ctx.accounts.system_program.to_account_info(),
+            ],
+        )?;
+        if requested_fee > 0 {
+            // Transfers requested_fee lamports from
+            // taker -> fee_recipient.
+            invoke(
+                &system_instruction::transfer(
+                    ctx.accounts.taker.key,
+                    ctx.accounts.fee_recipient.key,
+                    requested_fee,
+                ),
+                &[
+                    ctx.accounts.taker.clone(),
+                    ctx.accounts.fee_recipient.clone(),
+                    ctx.accounts.system_program.to_account_info(),
+                ],
+            )?;
+        }
+
+        // Transfers everything in the vault (less the fee) from
+        // pda_deposit_token_account -> taker_receive_token_account.
+        // As in exchange, that includes anything sent to the vault on top of the deposit.
+        transfer_less_fee(
+            &ctx.accounts.token_program,
+            Transfer {
+                from: ctx.accounts.pda_deposit_token_account.to_account_info(),
+                to: ctx.accounts.taker_receive_token_account.to_account_info(),
+                authority: ctx.accounts.pda_account.clone(),
+            },
+            &ctx.accounts.offered_fee_token_account,
+            &[&seeds[..]],
+            ctx.accounts.pda_deposit_token_account.amount,
+            offered_fee,
+        )?;
+
+        // Closes the (now empty) vault, sending its rent to the initializer.
+        close_deposit(
+            &ctx.accounts.escrow_account,
+            &ctx.accounts.pda_deposit_token_account,
+            &ctx.accounts.initializer_main_account,
+            &ctx.accounts.pda_account,
+            &ctx.accounts.token_program,
+        )?;
+
+        ctx.accounts.order_book.remove(escrow_key);
+
+        // Nothing left to trade. Whatever was sent to the vault on top of the
+        // deposit isn't part of the trade, so the event reports the escrow's amounts.
+        let escrow_account = &mut ctx.accounts.escrow_account;
+        let initializer_amount = std::mem::take(&mut escrow_account.initializer_amount);
+        let taker_amount = std::mem::take(&mut escrow_account.taker_amount);
+        let mut event = escrow_account.exchanged_event(
+            *escrow_key,
+            *ctx.accounts.taker.key,
+            initializer_amount,
+            taker_amount,
+            Clock::get()?.slot,
+        );
+        event.offered_fee = offered_fee;
+        event.requested_fee = requested_fee;
+        emit!(event);
+        Ok(())
+    }
+
+    // Cancels an escrow created with initialize_escrow_offering_sol. Closing the
+    // escrow account is all it takes, since that returns the escrowed lamports
+    // along with the rent. As with cancel_escrow, this must be signed by the
+    // initializer or their canceller, unless the escrow has expired, in which
+    // case anyone can do it (as with crank_expired).
+    pub fn cancel_sol_offer(ctx: Context<CancelSolOffer>) -> ProgramResult {
+        let authorized = ctx.accounts.authority.is_signer
+            && ctx
+                .accounts
+                .escrow_account
+                .can_be_cancelled_by(ctx.accounts.authority.key);
+        if !authorized
+            && !ctx
+                .accounts
+                .escrow_account
+                .is_expired(Clock::get()?.unix_timestamp)
+        {
+            return Err(ErrorCode::UnauthorizedCanceller.into());
+        }
+
+        let escrow_key = ctx.accounts.escrow_account.to_account_info().key;
+        ctx.accounts.order_book.remove(escrow_key);
+
+        emit!(ctx.accounts.escrow_account.cancelled_event(
+            *escrow_key,
+            !authorized,
+            Clock::get()?.slot
+        ));
+        Ok(())
+    }
+
+    // Creates a bundle escrow offering offered_amounts[i] of the token in the i-th
+    // deposit account, for requested_amounts[j] of the token in the j-th receive
+    // account. The token accounts are passed in ctx.remaining_accounts: first one
+    // deposit token account per offered amount, then one receive token account
+    // per requested amount. Ownership of every deposit account is transferred to
+    // the bundle's PDA.
+    pub fn initialize_bundle_escrow<'info>(
+        ctx: Context<'_, '_, '_, 'info, InitializeBundleEscrow<'info>>,
+        offered_amounts: Vec<u64>,
+        requested_amounts: Vec<u64>,
+        bump: u8,
+    ) -> ProgramResult {
+        if !BundleEscrowAccount::is_valid_leg_count(offered_amounts.len())
+            || !BundleEscrowAccount::is_valid_leg_count(requested_amounts.len())
+            || ctx.remaining_accounts.len() != offered_amounts.len() + requested_amounts.len()
+        {
+            return Err(ErrorCode::InvalidBundle.into());
+        }
+        let (deposit_accounts, receive_accounts) =
+            ctx.remaining_accounts.split_at(offered_amounts.len());
+
+        let mut offered = Vec::with_capacity(offered_amounts.len());
+        for (deposit_info, amount) in deposit_accounts.iter().zip(offered_amounts) {
+            let deposit: Account<TokenAccount> = Account::try_from(deposit_info)?;
+            if deposit.owner != *ctx.accounts.initializer.key || deposit.amount < amount {
+                return Err(ErrorCode::InvalidBundle.into());
+            }
+
+            // Transfers ownership of the deposit account from
+            // initializer -> pda.
+            token::set_authority(
+                CpiContext::new(
+                    ctx.accounts.token_program.to_account_info(),
+                    SetAuthority {
+                        account_or_mint: deposit_info.clone(),
+                        current_authority: ctx.accounts.initializer.clone(),
+                    },
+                ),
+                AuthorityType::AccountOwner,
+                Some(*ctx.accounts.pda_account.key),
+            )?;
+
+            offered.push(Leg {
+                mint: deposit.mint,
+                token_account: *deposit_info.key,
+                amount,
+            });
+        }
+
+        let mut requested = Vec::with_capacity(requested_amounts.len());
+        for (receive_info, amount) in receive_accounts.iter().zip(requested_amounts) {
+            let receive: Account<TokenAccount> = Account::try_from(receive_info)?;
+            requested.push(Leg {
+                mint: receive.mint,
+                token_account: *receive_info.key,
+                amount,
+            });
+        }
+
+        let bundle_escrow_account = &mut ctx.accounts.bundle_escrow_account;
+        bundle_escrow_account.initializer_key = *ctx.accounts.initializer.key;
+        bundle_escrow_account.bump = bump;
+        bundle_escrow_account.offered = offered;
+        bundle_escrow_account.requested = requested;
+
+        Ok(())
+    }
+
+    // Settles every leg of a bundle escrow. ctx.remaining_accounts holds, for
+    // each offered leg in order, the deposit token account followed by the taker's
+    // token account to receive it in. Then, for each requested leg in order, the
+    // taker's token account to pay from followed by the initializer's receive
+    // token account. Each leg's accounts are followed by the fee_recipient's
+    // token account for the leg's mint, which receives the protocol fee on it.
+    // max_fee_bps is as in exchange. If any leg fails, the whole exchange fails.
+    pub fn exchange_bundle<'info>(
+        ctx: Context<'_, '_, '_, 'info, ExchangeBundle<'info>>,
+        max_fee_bps: u16,
+    ) -> ProgramResult {
+        let config = &ctx.accounts.config;
+        config.check_max_fee(max_fee_bps)?;
+        let bundle_escrow_account = &ctx.accounts.bundle_escrow_account;
+        let offered_len = 3 * bundle_escrow_account.offered.len();
+        if ctx.remaining_accounts.len() != offered_len + 3 * bundle_escrow_account.requested.len() {
+            return Err(ErrorCode::BundleAccountMismatch.into());
+        }
+        let (offered_accounts, requested_accounts) = ctx.remaining_accounts.split_at(offered_len);
+
+        let bundle_key = bundle_escrow_account.to_account_info().key;
+        let seeds = &[
+            ESCROW_PDA_SEED,
+            bundle_key.as_ref(),
+            &[bundle_escrow_account.bump],
+        ];
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+
+        for (leg, accounts) in bundle_escrow_account
+            .offered
+            .iter()
+            .zip(offered_accounts.chunks(3))
+        {
+            let (pda_deposit_info, taker_receive_info, fee_info) =
+                (&accounts[0], &accounts[1], &accounts[2]);
+            let taker_receive: Account<TokenAccount> = Account::try_from(taker_receive_info)?;
+            if *pda_deposit_info.key != leg.token_account || taker_receive.mint != leg.mint {
+                return Err(ErrorCode::BundleAccountMismatch.into());
+            }
+            validate_fee_token_account(fee_info, config, &leg.mint)?;
+            let fee = config.offered_leg_fee(leg.amount)?;
+
+            // Transfers leg.amount tokens (less the fee) from
+            // pda deposit account -> taker's receive account.
+            token::transfer(
+                CpiContext::new(
+                    cpi_program.clone(),
+                    Transfer {
+                        from: pda_deposit_info.clone(),
+                        to: taker_receive_info.clone(),
+                        authority: ctx.accounts.pda_account.clone(),
+                    },
+                )
+                .with_signer(&[&seeds[..]]),
+                leg.amount
+                    .checked_sub(fee)
+                    .ok_or(ErrorCode::NumericalOverflow)?,
+            )?;
+            if fee > 0 {
+                // Transfers fee tokens from
+                // pda deposit account -> fee token account.
+                token::transfer(
+                    CpiContext::new(
+                        cpi_program.clone(),
+                        Transfer {
+                            from: pda_deposit_info.clone(),
+                            to: fee_info.clone(),
+                            authority: ctx.accounts.pda_account.clone(),
+                        },
+                    )
+                    .with_signer(&[&seeds[..]]),
+                    fee,
+                )?;
+            }
+
+            // Transfers ownership of the deposit account from
+            // pda_account -> initializer_key
+            token::set_authority(
+                CpiContext::new(
+                    cpi_program.clone(),
+                    SetAuthority {
+                        account_or_mint: pda_deposit_info.clone(),
+                        current_authority: ctx.accounts.pda_account.clone(),
+                    },
+                )
+                .with_signer(&[&seeds[..]]),
+                AuthorityType::AccountOwner,
+                Some(bundle_escrow_account.initializer_key),
+            )?;
+        }
+
+        for (leg, accounts) in bundle_escrow_account
+            .requested
+            .iter()
+            .zip(requested_accounts.chunks(3))
+        {
+            let (taker_deposit_info, initializer_receive_info, fee_info) =
+                (&accounts[0], &accounts[1], &accounts[2]);
+            let taker_deposit: Account<TokenAccount> = Account::try_from(taker_deposit_info)?;
+            if *initializer_receive_info.key != leg.token_account || taker_deposit.mint != leg.mint
+            {
+                return Err(ErrorCode::BundleAccountMismatch.into());
+            }
+            validate_fee_token_account(fee_info, config, &leg.mint)?;
+            let fee = config.requested_leg_fee(leg.amount)?;
+
+            // Transfers leg.amount tokens (less the fee) from
+            // taker's deposit account -> initializer's receive account.
+            token::transfer(
+                CpiContext::new(
+                    cpi_program.clone(),
+                    Transfer {
+                        from: taker_deposit_info.clone(),
+                        to: initializer_receive_info.clone(),
+                        authority: ctx.accounts.taker.clone(),
+                    },
+                ),
+                leg.amount
+                    .checked_sub(fee)
+                    .ok_or(ErrorCode::NumericalOverflow)?,
+            )?;
+            if fee > 0 {
+                // Transfers fee tokens from
+                // taker's deposit account -> fee token account.
+                token::transfer(
+                    CpiContext::new(
+                        cpi_program.clone(),
+                        Transfer {
+                            from: taker_deposit_info.clone(),
+                            to: fee_info.clone(),
+                            authority: ctx.accounts.taker.clone(),
+                        },
+                    ),
+                    fee,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Gives the initializer back ownership of every deposit account. The deposit
+    // token accounts are passed in ctx.remaining_accounts, in the order of the
+    // bundle's offered legs.
+    pub fn cancel_bundle_escrow<'info>(
+        ctx: Context<'_, '_, '_, 'info, CancelBundleEscrow<'info>>,
+    ) -> ProgramResult {
+        let bundle_escrow_account = &ctx.accounts.bundle_escrow_account;
+        if ctx.remaining_accounts.len() != bundle_escrow_account.offered.len() {
+            return Err(ErrorCode::BundleAccountMismatch.into());
+        }
+
+        let bundle_key = bundle_escrow_account.to_account_info().key;
+        let seeds = &[
+            ESCROW_PDA_SEED,
+            bundle_key.as_ref(),
+            &[bundle_escrow_account.bump],
+        ];
+
+        for (leg, pda_deposit_info) in bundle_escrow_account
+            .offered
+            .iter()
+            .zip(ctx.remaining_accounts)
+        {
+            if *pda_deposit_info.key != leg.token_account {
+                return Err(ErrorCode::BundleAccountMismatch.into());
+            }
+
+            // Transfers ownership of the deposit account from
+            // pda_account -> initializer_key
+            token::set_authority(
+                CpiContext::new(
+                    ctx.accounts.token_program.to_account_info(),
+                    SetAuthority {
+                        account_or_mint: pda_deposit_info.clone(),
+                        current_authority: ctx.accounts.pda_account.clone(),
+                    },
+                )
+                .with_signer(&[&seeds[..]]),
+                AuthorityType::AccountOwner,
+                Some(bundle_escrow_account.initializer_key),
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(offered_leg_fee_bps: u16, requested_leg_fee_bps: u16, fee_recipient: Pubkey, bump: u8)]
+pub struct InitializeConfig<'info> {
+    #[account(
+        init,
+        seeds = [CONFIG_SEED],
+        bump = bump,
+        payer = admin,
+        space = 8 + EscrowConfig::LEN
+    )]
+    pub config: Account<'info, EscrowConfig>,
+    // Must be the program's upgrade authority.
+    #[account(signer, mut)]
+    pub admin: AccountInfo<'info>,
+    // This program, and its ProgramData account, which records the upgrade
+    // authority.
+    pub escrow_program: AccountInfo<'info>,
+    pub program_data: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateConfig<'info> {
+    #[account(
+        mut,
+        seeds = [CONFIG_SEED],
+        bump = config.bump,
+        constraint = config.admin == *admin.key @ ErrorCode::UnauthorizedAdmin
+    )]
+    pub config: Account<'info, EscrowConfig>,
+    #[account(signer)]
+    pub admin: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(bump: u8)]
+pub struct InitializeOrderBook<'info> {
+    #[account(signer, mut)]
+    pub payer: AccountInfo<'info>,
+    // Use spl_token::native_mint for a native SOL leg.
+    pub offered_mint: Account<'info, Mint>,
+    pub requested_mint: Account<'info, Mint>,
+    #[account(
+        init,
+        seeds = [
+            ORDER_BOOK_SEED,
+            offered_mint.to_account_info().key.as_ref(),
+            requested_mint.to_account_info().key.as_ref()
+        ],
+        bump = bump,
+        payer = payer,
+        space = 8 + OrderBook::LEN
+    )]
+    pub order_book: Account<'info, OrderBook>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ListEscrow<'info> {
+    pub escrow_account: Account<'info, EscrowAccount>,
+    // The order book for the escrow's mint pair.
+    #[account(
+        mut,
+        seeds = [
+            ORDER_BOOK_SEED,
+            escrow_account.offered_mint.as_ref(),
+            escrow_account.requested_mint.as_ref()
+        ],
+        bump = order_book.bump,
+    )]
+    pub order_book: Account<'info, OrderBook>,
+}
+
+#[derive(Accounts)]
+#[instruction(initializer_amount: u64, taker_amount: u64, bump: u8)]
+pub struct InitializeEscrow<'info> {
+    // The account of the person initializing the escrow.
+    #[account(signer)]
+    pub initializer: AccountInfo<'info>,
+
+    // A temporary token account that should be created prior to this instruction
+    // and owned by the initializer
+    //
+    // Ownership of this gets transferred from the initializer to this program's PDA.
+    #[account(
+        mut,
+        constraint = initializer_deposit_token_account.amount >= initializer_amount
+    )]
+    pub initializer_deposit_token_account: Account<'info, TokenAccount>,
+
+    // The initializer's token account for the token they will receive should
+    // the trade go through.
+    pub initializer_receive_token_account: Account<'info, TokenAccount>,
+
+    // The escrow account, it will hold all necessary info about the trade.
+    #[account(init, payer = initializer, space = 8 + EscrowAccount::LEN)]
+    pub escrow_account: Account<'info, EscrowAccount>,
+
+    // This escrow's PDA, which becomes the owner of initializer_deposit_token_account.
+    #[account(
+        seeds = [ESCROW_PDA_SEED, escrow_account.to_account_info().key.as_ref()],
+        bump = bump,
+    )]
+    pub pda_account: AccountInfo<'info>,
+
+    // The order book for this escrow's mint pair, which lists it while it's open.
+    #[account(
+        mut,
+        seeds = [
+            ORDER_BOOK_SEED,
+            initializer_deposit_token_account.mint.as_ref(),
+            initializer_receive_token_account.mint.as_ref()
+        ],
+        bump = order_book.bump,
+    )]
+    pub order_book: Account<'info, OrderBook>,
+    pub system_program: Program<'info, System>,
+
+    // The token program.
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+#[instruction(initializer_amount: u64, taker_amount: u64, bump: u8, vault_bump: u8)]
+pub struct InitializeEscrowWithVault<'info> {
+    // The account of the person initializing the escrow. Pays for the escrow
+    // account and the vault.
+    #[account(signer, mut)]
+    pub initializer: AccountInfo<'info>,
+
+    // The initializer's token account holding the tokens they will offer. Unlike
+    // initialize_escrow, ownership stays with the initializer.
+    #[account(
+        mut,
+        constraint = initializer_deposit_token_account.amount >= initializer_amount
+    )]
+    pub initializer_deposit_token_account: Account<'info, TokenAccount>,
+
+    // The initializer's token account for the token they will receive should
+    // the trade go through.
+    pub initializer_receive_token_account: Account<'info, TokenAccount>,
+
+    // The mint of the offered token.
+    #[account(constraint = initializer_deposit_token_account.mint == *mint.to_account_info().key)]
+    pub mint: Account<'info, Mint>,
+
+    // The escrow account, it will hold all necessary info about the trade.
+    #[account(init, payer = initializer, space = 8 + EscrowAccount::LEN)]
+    pub escrow_account: Account<'info, EscrowAccount>,
+
+    // This escrow's PDA, which owns the vault.
+    #[account(
+        seeds = [ESCROW_PDA_SEED, escrow_account.to_account_info().key.as_ref()],
+        bump = bump,
+    )]
+    pub pda_account: AccountInfo<'info>,
+
+    // The vault that holds the offered tokens until the escrow is exchanged or cancelled.
+    #[account(
+        init,
+        seeds = [VAULT_PDA_SEED, escrow_account.to_account_info().key.as_ref()],
+        bump = vault_bump,
+        payer = initializer,
+        token::mint = mint,
+        token::authority = pda_account,
+    )]
+    pub vault: Account<'info, TokenAccount>,
+
+    // The order book for this escrow's mint pair, which lists it while it's open.
+    #[account(
+        mut,
+        seeds = [
+            ORDER_BOOK_SEED,
+            initializer_deposit_token_account.mint.as_ref(),
+            initializer_receive_token_account.mint.as_ref()
+        ],
+        bump = order_book.bump,
+    )]
+    pub order_book: Account<'info, OrderBook>,
+    pub system_program: Program<'info, System>,
+
+    // The token program.
+    pub token_program: Program<'info, Token>,
+
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct Exchange<'info> {
+    #[account(
+        signer,
+        constraint = escrow_account.can_be_taken_by(taker.key) @ ErrorCode::TakerNotAllowed
+    )]
+    pub taker: AccountInfo<'info>,
+    // Must hold the mint the initializer asked for, otherwise the taker could pay
+    // with any token that happens to have the same decimals.
+    #[account(
+        mut,
+        constraint = taker_deposit_token_account.mint == escrow_account.requested_mint @ ErrorCode::RequestedMintMismatch
+    )]
+    pub taker_deposit_token_account: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = taker_receive_token_account.mint == escrow_account.offered_mint @ ErrorCode::OfferedMintMismatch
+    )]
+    pub taker_receive_token_account: Account<'info, TokenAccount>,
+    // The account holding the deposit (the vault in vault mode).
+    #[account(mut)]
+    pub pda_deposit_token_account: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub initializer_receive_token_account: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub initializer_main_account: AccountInfo<'info>,
+    // Always fully filled, so the escrow is closed once the exchange is done.
+    #[account(
+        mut,
+        constraint = escrow_account.vault == *pda_deposit_token_account.to_account_info().key,
+        constraint = escrow_account.initializer_receive_token_account == *initializer_receive_token_account.to_account_info().key,
+        constraint = escrow_account.initializer_key == *initializer_main_account.key,
+        constraint = escrow_account.native_leg == NativeLeg::None @ ErrorCode::NativeLegMismatch,
+        close = initializer_main_account
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+    #[account(
+        seeds = [ESCROW_PDA_SEED, escrow_account.to_account_info().key.as_ref()],
+        bump = escrow_account.bump,
+    )]
+    pub pda_account: AccountInfo<'info>,
+    // The order book listing this escrow.
+    #[account(
+        mut,
+        seeds = [
+            ORDER_BOOK_SEED,
+            escrow_account.offered_mint.as_ref(),
+            escrow_account.requested_mint.as_ref()
+        ],
+        bump = order_book.bump,
+    )]
+    pub order_book: Account<'info, OrderBook>,
+    // The protocol fee settings.
+    #[account(seeds = [CONFIG_SEED], bump = config.bump)]
+    pub config: Account<'info, EscrowConfig>,
+    // Receive the fee on each leg. Must belong to the config's fee_recipient.
+    #[account(
+        mut,
+        constraint = config.is_fee_token_account(&offered_fee_token_account, &escrow_account.offered_mint) @ ErrorCode::InvalidFeeAccount
+    )]
+    pub offered_fee_token_account: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = config.is_fee_token_account(&requested_fee_token_account, &escrow_account.requested_mint) @ ErrorCode::InvalidFeeAccount
+    )]
+    pub requested_fee_token_account: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+// The same accounts and checks as Exchange, except that the escrow stays open.
+#[derive(Accounts)]
+pub struct ExchangePartial<'info> {
+    #[account(
+        signer,
+        constraint = escrow_account.can_be_taken_by(taker.key) @ ErrorCode::TakerNotAllowed
+    )]
+    pub taker: AccountInfo<'info>,
+    // Must hold the mint the initializer asked for, otherwise the taker could pay
+    // with any token that happens to have the same decimals.
+    #[account(
+        mut,
+        constraint = taker_deposit_token_account.mint == escrow_account.requested_mint @ ErrorCode::RequestedMintMismatch
+    )]
+    pub taker_deposit_token_account: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = taker_receive_token_account.mint == escrow_account.offered_mint @ ErrorCode::OfferedMintMismatch
+    )]
+    pub taker_receive_token_account: Account<'info, TokenAccount>,
+    // The account holding the deposit (the vault in vault mode).
+    #[account(mut)]
+    pub pda_deposit_token_account: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub initializer_receive_token_account: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub initializer_main_account: AccountInfo<'info>,
+    // Left open, since a partial fill never takes everything that's left.
+    #[account(
+        mut,
+        constraint = escrow_account.vault == *pda_deposit_token_account.to_account_info().key,
+        constraint = escrow_account.initializer_receive_token_account == *initializer_receive_token_account.to_account_info().key,
+        constraint = escrow_account.initializer_key == *initializer_main_account.key,
+        constraint = escrow_account.native_leg == NativeLeg::None @ ErrorCode::NativeLegMismatch,
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+    #[account(
+        seeds = [ESCROW_PDA_SEED, escrow_account.to_account_info().key.as_ref()],
+        bump = escrow_account.bump,
+    )]
+    pub pda_account: AccountInfo<'info>,
+    // The order book listing this escrow.
+    #[account(
+        mut,
+        seeds = [
+            ORDER_BOOK_SEED,
+            escrow_account.offered_mint.as_ref(),
+            escrow_account.requested_mint.as_ref()
+        ],
+        bump = order_book.bump,
+    )]
+    pub order_book: Account<'info, OrderBook>,
+    // The protocol fee settings.
+    #[account(seeds = [CONFIG_SEED], bump = config.bump)]
+    pub config: Account<'info, EscrowConfig>,
+    // Receive the fee on each leg. Must belong to the config's fee_recipient.
+    #[account(
+        mut,
+        constraint = config.is_fee_token_account(&offered_fee_token_account, &escrow_account.offered_mint) @ ErrorCode::InvalidFeeAccount
+    )]
+    pub offered_fee_token_account: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = config.is_fee_token_account(&requested_fee_token_account, &escrow_account.requested_mint) @ ErrorCode::InvalidFeeAccount
+    )]
+    pub requested_fee_token_account: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+// The accounts for each exchange are passed in ctx.remaining_accounts, see
+// exchange_many.
+#[derive(Accounts)]
+pub struct ExchangeMany<'info> {
+    #[account(signer)]
+    pub taker: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CancelEscrow<'info> {
+    // Either the initializer or their delegated canceller. Without this, anyone
+    // could cancel someone else's escrow.
+    #[account(
+        signer,
+        constraint = escrow_account.can_be_cancelled_by(authority.key) @ ErrorCode::UnauthorizedCanceller
+    )]
+    pub authority: AccountInfo<'info>,
+    // Receives the escrow account's rent (and the vault's, in vault mode).
+    #[account(mut)]
+    pub initializer: AccountInfo<'info>,
+    // The account holding the deposit (the vault in vault mode).
+    #[account(mut)]
+    pub pda_deposit_token_account: Account<'info, TokenAccount>,
+    // Where the deposit is refunded to in vault mode. Outside of vault mode this
+    // is the same account as pda_deposit_token_account.
+    #[account(mut)]
+    pub initializer_deposit_token_account: Account<'info, TokenAccount>,
+    #[account(
+        seeds = [ESCROW_PDA_SEED, escrow_account.to_account_info().key.as_ref()],
+        bump = escrow_account.bump,
+    )]
+    pub pda_account: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = escrow_account.initializer_key == *initializer.key,
+        constraint = escrow_account.vault == *pda_deposit_token_account.to_account_info().key,
+        constraint = escrow_account.initializer_deposit_token_account == *initializer_deposit_token_account.to_account_info().key,
+        close = initializer
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+    // The order book listing this escrow.
+    #[account(
+        mut,
+        seeds = [
+            ORDER_BOOK_SEED,
+            escrow_account.offered_mint.as_ref(),
+            escrow_account.requested_mint.as_ref()
+        ],
+        bump = order_book.bump,
+    )]
+    pub order_book: Account<'info, OrderBook>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateEscrow<'info> {
+    #[account(signer)]
+    pub initializer: AccountInfo<'info>,
+    // In vault mode, top-ups come from here and withdrawals go back here. Outside
+    // of vault mode this is the same account as pda_deposit_token_account.
+    #[account(mut)]
+    pub initializer_deposit_token_account: Account<'info, TokenAccount>,
+    // The account holding the deposit (the vault in vault mode).
+    #[account(mut)]
+    pub pda_deposit_token_account: Account<'info, TokenAccount>,
+    #[account(
+        seeds = [ESCROW_PDA_SEED, escrow_account.to_account_info().key.as_ref()],
+        bump = escrow_account.bump,
+    )]
+    pub pda_account: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = escrow_account.initializer_key == *initializer.key,
+        constraint = escrow_account.vault == *pda_deposit_token_account.to_account_info().key,
+        constraint = escrow_account.initializer_deposit_token_account == *initializer_deposit_token_account.to_account_info().key,
+        constraint = escrow_account.native_leg == NativeLeg::None @ ErrorCode::NativeLegMismatch,
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct SetCanceller<'info> {
+    #[account(signer)]
+    pub initializer: AccountInfo<'info>,
+    #[account(mut, constraint = escrow_account.initializer_key == *initializer.key)]
+    pub escrow_account: Account<'info, EscrowAccount>,
+}
+
+#[derive(Accounts)]
+pub struct SetDutchAuction<'info> {
+    #[account(signer)]
+    pub initializer: AccountInfo<'info>,
+    // Auctions price the token leg, so escrows with a native leg aren't supported.
+    #[account(
+        mut,
+        constraint = escrow_account.initializer_key == *initializer.key,
+        constraint = escrow_account.native_leg == NativeLeg::None @ ErrorCode::NativeLegMismatch,
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+}
+
+#[derive(Accounts)]
+pub struct SetOraclePricing<'info> {
+    #[account(signer)]
+    pub initializer: AccountInfo<'info>,
+    // Oracles price the token leg, so escrows with a native leg aren't supported.
+    #[account(
+        mut,
+        constraint = escrow_account.initializer_key == *initializer.key,
+        constraint = escrow_account.native_leg == NativeLeg::None @ ErrorCode::NativeLegMismatch,
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+}
+
+#[derive(Accounts)]
+pub struct SetArbiter<'info> {
+    #[account(signer)]
+    pub initializer: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = escrow_account.initializer_key == *initializer.key,
+        constraint = escrow_account.native_leg == NativeLeg::None @ ErrorCode::NativeLegMismatch,
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+}
+
+#[derive(Accounts)]
+#[instruction(
+    expected_initializer_amount: u64,
+    expected_taker_amount: u64,
+    expected_arbiter: Pubkey,
+    max_fee_bps: u16,
+    taker_vault_bump: u8
+)]
+pub struct Fund<'info> {
+    // Pays for the taker vault, and gets its rent back once the escrow settles.
+    #[account(
+        signer,
+        mut,
+        constraint = escrow_account.can_be_taken_by(taker.key) @ ErrorCode::TakerNotAllowed
+    )]
+    pub taker: AccountInfo<'info>,
+    // The payment comes from here, and whatever the taker doesn't end up paying
+    // goes back here.
+    #[account(
+        mut,
+        constraint = taker_deposit_token_account.mint == escrow_account.requested_mint @ ErrorCode::RequestedMintMismatch
+    )]
+    pub taker_deposit_token_account: Account<'info, TokenAccount>,
+    #[account(
+        constraint = taker_receive_token_account.mint == escrow_account.offered_mint @ ErrorCode::OfferedMintMismatch
+    )]
+    pub taker_receive_token_account: Account<'info, TokenAccount>,
+    // The requested mint, which the taker vault holds.
+    #[account(constraint = *mint.to_account_info().key == escrow_account.requested_mint)]
+    pub mint: Account<'info, Mint>,
+    #[account(
+        init,
+        seeds = [TAKER_VAULT_SEED, escrow_account.to_account_info().key.as_ref()],
+        bump = taker_vault_bump,
+        payer = taker,
+        token::mint = mint,
+        token::authority = pda_account,
+    )]
+    pub taker_vault: Account<'info, TokenAccount>,
+    #[account(
+        seeds = [ESCROW_PDA_SEED, escrow_account.to_account_info().key.as_ref()],
+        bump = escrow_account.bump,
+    )]
+    pub pda_account: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = escrow_account.native_leg == NativeLeg::None @ ErrorCode::NativeLegMismatch,
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+    // The order book listing this escrow.
+    #[account(
+        mut,
+        seeds = [
+            ORDER_BOOK_SEED,
+            escrow_account.offered_mint.as_ref(),
+            escrow_account.requested_mint.as_ref()
+        ],
+        bump = order_book.bump,
+    )]
+    pub order_book: Account<'info, OrderBook>,
+    // The protocol fee settings.
+    #[account(seeds = [CONFIG_SEED], bump = config.bump)]
+    pub config: Account<'info, EscrowConfig>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct Approve<'info> {
+    // The initializer or the taker.
+    #[account(signer)]
+    pub authority: AccountInfo<'info>,
+    #[account(mut)]
+    pub escrow_account: Account<'info, EscrowAccount>,
+}
+
+#[derive(Accounts)]
+pub struct Dispute<'info> {
+    // The initializer or the taker.
+    #[account(signer)]
+    pub authority: AccountInfo<'info>,
+    #[account(mut)]
+    pub escrow_account: Account<'info, EscrowAccount>,
+}
+
+// Used by both release and resolve.
+#[derive(Accounts)]
+pub struct SettleArbitration<'info> {
+    // Anyone for release, the arbiter for resolve.
+    #[account(signer)]
+    pub authority: AccountInfo<'info>,
+    // Gets the taker vault's rent back.
+    #[account(
+        mut,
+        constraint = escrow_account.funding.map_or(false, |funding| funding.taker == *taker.key) @ ErrorCode::InvalidEscrowState
+    )]
+    pub taker: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = escrow_account.funding.map_or(false, |funding| funding.taker_vault == *taker_vault.to_account_info().key) @ ErrorCode::InvalidEscrowState
+    )]
+    pub taker_vault: Account<'info, TokenAccount>,
+    // The parties' token accounts are unchecked, since either party may have
+    // closed theirs (see payout_account).
+    #[account(
+        mut,
+        constraint = escrow_account.funding.map_or(false, |funding| funding.taker_deposit_token_account == *taker_deposit_token_account.key) @ ErrorCode::InvalidEscrowState
+    )]
+    pub taker_deposit_token_account: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = escrow_account.funding.map_or(false, |funding| funding.taker_receive_token_account == *taker_receive_token_account.key) @ ErrorCode::InvalidEscrowState
+    )]
+    pub taker_receive_token_account: AccountInfo<'info>,
+    // The vault holding the deposit.
+    #[account(mut)]
+    pub pda_deposit_token_account: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub initializer_deposit_token_account: AccountInfo<'info>,
+    #[account(mut)]
+    pub initializer_receive_token_account: AccountInfo<'info>,
+    #[account(mut)]
+    pub initializer_main_account: AccountInfo<'info>,
+    // Where each of the accounts above is paid instead if it's unusable.
+    #[account(
+        mut,
+        constraint = *taker_associated_deposit_token_account.key == get_associated_token_address(taker.key, requested_mint.key)
+    )]
+    pub taker_associated_deposit_token_account: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = *taker_associated_receive_token_account.key == get_associated_token_address(taker.key, offered_mint.key)
+    )]
+    pub taker_associated_receive_token_account: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = *initializer_associated_deposit_token_account.key == get_associated_token_address(initializer_main_account.key, offered_mint.key)
+    )]
+    pub initializer_associated_deposit_token_account: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = *initializer_associated_receive_token_account.key == get_associated_token_address(initializer_main_account.key, requested_mint.key)
+    )]
+    pub initializer_associated_receive_token_account: AccountInfo<'info>,
+    #[account(constraint = *offered_mint.key == escrow_account.offered_mint)]
+    pub offered_mint: AccountInfo<'info>,
+    #[account(constraint = *requested_mint.key == escrow_account.requested_mint)]
+    pub requested_mint: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = escrow_account.vault == *pda_deposit_token_account.to_account_info().key,
+        constraint = escrow_account.initializer_deposit_token_account == *initializer_deposit_token_account.key,
+        constraint = escrow_account.initializer_receive_token_account == *initializer_receive_token_account.key,
+        constraint = escrow_account.initializer_key == *initializer_main_account.key,
+        close = initializer_main_account
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+    #[account(
+        seeds = [ESCROW_PDA_SEED, escrow_account.to_account_info().key.as_ref()],
+        bump = escrow_account.bump,
+    )]
+    pub pda_account: AccountInfo<'info>,
+    // The protocol fee settings.
+    #[account(seeds = [CONFIG_SEED], bump = config.bump)]
+    pub config: Account<'info, EscrowConfig>,
+    // Receive the fee on each leg. Must belong to the config's fee_recipient.
+    #[account(
+        mut,
+        constraint = config.is_fee_token_account(&offered_fee_token_account, &escrow_account.offered_mint) @ ErrorCode::InvalidFeeAccount
+    )]
+    pub offered_fee_token_account: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = config.is_fee_token_account(&requested_fee_token_account, &escrow_account.requested_mint) @ ErrorCode::InvalidFeeAccount
+    )]
+    pub requested_fee_token_account: Account<'info, TokenAccount>,
+    // Pays for any associated token account release or resolve has to create.
+    #[account(signer, mut)]
+    pub payer: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct StartEnglishAuction<'info> {
+    #[account(signer)]
+    pub initializer: AccountInfo<'info>,
+    // Bids are token transfers, so escrows with a native leg aren't supported.
+    #[account(
+        mut,
+        constraint = escrow_account.initializer_key == *initializer.key,
+        constraint = escrow_account.native_leg == NativeLeg::None @ ErrorCode::NativeLegMismatch,
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+}
+
+#[derive(Accounts)]
+#[instruction(amount: u64, max_fee_bps: u16, bid_vault_bump: u8)]
+pub struct PlaceBid<'info> {
+    // Pays for the bid vault, and gets its rent back once the bid is refunded
+    // or settled. Also pays for the outbid bidder's associated token account,
+    // if the refund has to create it.
+    #[account(
+        signer,
+        mut,
+        constraint = escrow_account.can_be_taken_by(bidder.key) @ ErrorCode::TakerNotAllowed
+    )]
+    pub bidder: AccountInfo<'info>,
+    // The bid comes from here, and is refunded here if it's outbid.
+    #[account(
+        mut,
+        constraint = bidder_deposit_token_account.mint == escrow_account.requested_mint @ ErrorCode::RequestedMintMismatch
+    )]
+    pub bidder_deposit_token_account: Account<'info, TokenAccount>,
+    // Receives the deposit if the bid wins.
+    #[account(
+        constraint = bidder_receive_token_account.mint == escrow_account.offered_mint @ ErrorCode::OfferedMintMismatch
+    )]
+    pub bidder_receive_token_account: Account<'info, TokenAccount>,
+    // The requested mint, which the bid vault holds.
+    #[account(constraint = *mint.to_account_info().key == escrow_account.requested_mint)]
+    pub mint: Account<'info, Mint>,
+    #[account(
+        init,
+        seeds = [
+            BID_VAULT_SEED,
+            escrow_account.to_account_info().key.as_ref(),
+            bidder.key.as_ref()
+        ],
+        bump = bid_vault_bump,
+        payer = bidder,
+        token::mint = mint,
+        token::authority = pda_account,
+    )]
+    pub bid_vault: Account<'info, TokenAccount>,
+    #[account(
+        seeds = [ESCROW_PDA_SEED, escrow_account.to_account_info().key.as_ref()],
+        bump = escrow_account.bump,
+    )]
+    pub pda_account: AccountInfo<'info>,
+    #[account(mut)]
+    pub escrow_account: Account<'info, EscrowAccount>,
+    // The protocol fee settings.
+    #[account(seeds = [CONFIG_SEED], bump = config.bump)]
+    pub config: Account<'info, EscrowConfig>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+// Mostly the same accounts as Exchange, with the winning bid standing in for the
+// taker.
+#[derive(Accounts)]
+pub struct Settle<'info> {
+    // Gets the bid vault's rent back.
+    #[account(
+        mut,
+        constraint = escrow_account.best_bid().map_or(false, |bid| bid.bidder == *winner.key) @ ErrorCode::BidMismatch
+    )]
+    pub winner: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = escrow_account.best_bid().map_or(false, |bid| bid.vault == *bid_vault.to_account_info().key) @ ErrorCode::BidMismatch
+    )]
+    pub bid_vault: Account<'info, TokenAccount>,
+    // Unchecked, since the winner may have closed it (see payout_account).
+    #[account(
+        mut,
+        constraint = escrow_account.best_bid().map_or(false, |bid| bid.receive_token_account == *taker_receive_token_account.key) @ ErrorCode::BidMismatch
+    )]
+    pub taker_receive_token_account: AccountInfo<'info>,
+    // The account holding the deposit (the vault in vault mode).
+    #[account(mut)]
+    pub pda_deposit_token_account: Account<'info, TokenAccount>,
+    // Unchecked, since the initializer may have closed it.
+    #[account(mut)]
+    pub initializer_receive_token_account: AccountInfo<'info>,
+    #[account(mut)]
+    pub initializer_main_account: AccountInfo<'info>,
+    // Where each side is paid instead if its own account is unusable.
+    #[account(
+        mut,
+        constraint = *winner_associated_token_account.key == get_associated_token_address(winner.key, offered_mint.key)
+    )]
+    pub winner_associated_token_account: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = *initializer_associated_token_account.key == get_associated_token_address(initializer_main_account.key, requested_mint.key)
+    )]
+    pub initializer_associated_token_account: AccountInfo<'info>,
+    #[account(constraint = *offered_mint.key == escrow_account.offered_mint)]
+    pub offered_mint: AccountInfo<'info>,
+    #[account(constraint = *requested_mint.key == escrow_account.requested_mint)]
+    pub requested_mint: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = escrow_account.vault == *pda_deposit_token_account.to_account_info().key,
+        constraint = escrow_account.initializer_receive_token_account == *initializer_receive_token_account.key,
+        constraint = escrow_account.initializer_key == *initializer_main_account.key,
+        close = initializer_main_account
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+    #[account(
+        seeds = [ESCROW_PDA_SEED, escrow_account.to_account_info().key.as_ref()],
+        bump = escrow_account.bump,
+    )]
+    pub pda_account: AccountInfo<'info>,
+    // The order book listing this escrow.
+    #[account(
+        mut,
+        seeds = [
+            ORDER_BOOK_SEED,
+            escrow_account.offered_mint.as_ref(),
+            escrow_account.requested_mint.as_ref()
+        ],
+        bump = order_book.bump,
+    )]
+    pub order_book: Account<'info, OrderBook>,
+    // The protocol fee settings.
+    #[account(seeds = [CONFIG_SEED], bump = config.bump)]
+    pub config: Account<'info, EscrowConfig>,
+    // Receive the fee on each leg. Must belong to the config's fee_recipient.
+    #[account(
+        mut,
+        constraint = config.is_fee_token_account(&offered_fee_token_account, &escrow_account.offered_mint) @ ErrorCode::InvalidFeeAccount
+    )]
+    pub offered_fee_token_account: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = config.is_fee_token_account(&requested_fee_token_account, &escrow_account.requested_mint) @ ErrorCode::InvalidFeeAccount
+    )]
+    pub requested_fee_token_account: Account<'info, TokenAccount>,
+    // Pays for any associated token account settle has to create.
+    #[account(signer, mut)]
+    pub payer: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct LogDutchAuctionPrice<'info> {
+    pub escrow_account: Account<'info, EscrowAccount>,
+}
+
+// Same accounts as CancelEscrow, minus the authority. The initializer doesn't
+// need to be involved, since the deposit can only ever go back to them.
+#[derive(Accounts)]
+pub struct CrankExpired<'info> {
+    #[account(mut)]
+    pub initializer: AccountInfo<'info>,
+    #[account(mut)]
+    pub pda_deposit_token_account: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub initializer_deposit_token_account: Account<'info, TokenAccount>,
+    #[account(
+        seeds = [ESCROW_PDA_SEED, escrow_account.to_account_info().key.as_ref()],
+        bump = escrow_account.bump,
+    )]
+    pub pda_account: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = escrow_account.initializer_key == *initializer.key,
+        constraint = escrow_account.vault == *pda_deposit_token_account.to_account_info().key,
+        constraint = escrow_account.initializer_deposit_token_account == *initializer_deposit_token_account.to_account_info().key,
+        close = initializer
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+    // The order book listing this escrow.
+    #[account(
+        mut,
+        seeds = [
+            ORDER_BOOK_SEED,
+            escrow_account.offered_mint.as_ref(),
+            escrow_account.requested_mint.as_ref()
+        ],
+        bump = order_book.bump,
+    )]
+    pub order_book: Account<'info, OrderBook>,
+    pub token_program: Program<'info, Token>,
+}
+
+// Like CrankExpired, except the deposit accounts are unchecked, since the
+// deposit may have been closed. Escrows offering SOL keep their deposit in the
+// escrow account itself, so they can't go stale.
+#[derive(Accounts)]
+pub struct Reclaim<'info> {
+    #[account(mut)]
+    pub initializer: AccountInfo<'info>,
+    #[account(mut)]
+    pub pda_deposit_token_account: AccountInfo<'info>,
+    // Only read if there's something left in the deposit to refund.
+    #[account(mut)]
+    pub initializer_deposit_token_account: AccountInfo<'info>,
+    #[account(
+        seeds = [ESCROW_PDA_SEED, escrow_account.to_account_info().key.as_ref()],
+        bump = escrow_account.bump,
+    )]
+    pub pda_account: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = escrow_account.initializer_key == *initializer.key,
+        constraint = escrow_account.vault == *pda_deposit_token_account.key,
+        constraint = escrow_account.initializer_deposit_token_account == *initializer_deposit_token_account.key,
+        constraint = escrow_account.native_leg != NativeLeg::Offered @ ErrorCode::NativeLegMismatch,
+        close = initializer
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+    // The order book listing this escrow.
+    #[account(
+        mut,
+        seeds = [
+            ORDER_BOOK_SEED,
+            escrow_account.offered_mint.as_ref(),
+            escrow_account.requested_mint.as_ref()
+        ],
+        bump = order_book.bump,
+    )]
+    pub order_book: Account<'info, OrderBook>,
+    pub token_program: Program<'info, Token>,
+}
+
+// The same accounts as Exchange, for an escrow created by the original program.
+// The escrow itself is checked against them by the handler.
+#[derive(Accounts)]
+#[instruction(
+    expected_initializer_amount: u64,
+    expected_taker_amount: u64,
+    max_fee_bps: u16,
+    legacy_bump: u8
+)]
+pub struct ExchangeLegacyEscrow<'info> {
+    #[account(signer)]
+    pub taker: AccountInfo<'info>,
+    // v1 escrows don't store their mints, so the taker's accounts are checked
+    // against the initializer's.
+    #[account(
+        mut,
+        constraint = taker_deposit_token_account.mint == initializer_receive_token_account.mint @ ErrorCode::RequestedMintMismatch
+    )]
+    pub taker_deposit_token_account: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = taker_receive_token_account.mint == pda_deposit_token_account.mint @ ErrorCode::OfferedMintMismatch
+    )]
+    pub taker_receive_token_account: Account<'info, TokenAccount>,
+    // The deposit, owned by legacy_pda_account.
+    #[account(mut)]
+    pub pda_deposit_token_account: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub initializer_receive_token_account: Account<'info, TokenAccount>,
+    // Receives the escrow account's rent.
+    #[account(mut)]
+    pub initializer_main_account: AccountInfo<'info>,
+    // Too small to be an EscrowAccount, so it's checked and read by the handler
+    // (see EscrowAccountV1).
+    #[account(mut)]
+    pub legacy_escrow_account: AccountInfo<'info>,
+    // The PDA that owned every v1 escrow's deposit.
+    #[account(seeds = [ESCROW_PDA_SEED], bump = legacy_bump)]
+    pub legacy_pda_account: AccountInfo<'info>,
+    // The protocol fee settings.
+    #[account(seeds = [CONFIG_SEED], bump = config.bump)]
+    pub config: Account<'info, EscrowConfig>,
+    // Receive the fee on each leg. Must belong to the config's fee_recipient.
+    #[account(
+        mut,
+        constraint = config.is_fee_token_account(&offered_fee_token_account, &pda_deposit_token_account.mint) @ ErrorCode::InvalidFeeAccount
+    )]
+    pub offered_fee_token_account: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = config.is_fee_token_account(&requested_fee_token_account, &initializer_receive_token_account.mint) @ ErrorCode::InvalidFeeAccount
+    )]
+    pub requested_fee_token_account: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+#[instruction(legacy_bump: u8)]
+pub struct CancelLegacyEscrow<'info> {
+    // Receives the deposit and the escrow account's rent.
+    #[account(signer, mut)]
+    pub initializer: AccountInfo<'info>,
+    // The deposit, owned by legacy_pda_account.
+    #[account(mut)]
+    pub pda_deposit_token_account: Account<'info, TokenAccount>,
+    // Only read for its mint, for EscrowCancelled.
+    pub initializer_receive_token_account: Account<'info, TokenAccount>,
+    // Too small to be an EscrowAccount, so it's checked and read by the handler
+    // (see EscrowAccountV1).
+    #[account(mut)]
+    pub legacy_escrow_account: AccountInfo<'info>,
+    // The PDA that owned every v1 escrow's deposit.
+    #[account(seeds = [ESCROW_PDA_SEED], bump = legacy_bump)]
+    pub legacy_pda_account: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+#[instruction(initializer_amount: u64)]
+pub struct InitializeEscrowOfferingSol<'info> {
+    // The account of the person initializing the escrow. Pays for the escrow
+    // account, and the lamports being offered.
+    #[account(
+        signer,
+        mut,
+        constraint = initializer.lamports() >= initializer_amount
+    )]
+    pub initializer: AccountInfo<'info>,
+
+    // The initializer's token account for the token they will receive should
+    // the trade go through.
+    pub initializer_receive_token_account: Account<'info, TokenAccount>,
+
+    // The escrow account. Holds the offered lamports as well as the trade's info.
+    #[account(init, payer = initializer, space = 8 + EscrowAccount::LEN)]
+    pub escrow_account: Account<'info, EscrowAccount>,
+
+    // The order book for this escrow's mint pair, which lists it while it's open.
+    #[account(
+        mut,
+        seeds = [
+            ORDER_BOOK_SEED,
+            spl_token::native_mint::ID.as_ref(),
+            initializer_receive_token_account.mint.as_ref()
+        ],
+        bump = order_book.bump,
+    )]
+    pub order_book: Account<'info, OrderBook>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(initializer_amount: u64, taker_amount: u64, bump: u8, vault_bump: u8)]
+pub struct InitializeEscrowRequestingSol<'info> {
+    // The account of the person initializing the escrow. Pays for the escrow
+    // account and the vault, and receives the lamports should the trade go through.
+    #[account(signer, mut)]
+    pub initializer: AccountInfo<'info>,
+
+    // The initializer's token account holding the tokens they will offer.
+    #[account(
+        mut,
+        constraint = initializer_deposit_token_account.amount >= initializer_amount
+    )]
+    pub initializer_deposit_token_account: Account<'info, TokenAccount>,
+
+    // The mint of the offered token.
+    #[account(constraint = initializer_deposit_token_account.mint == *mint.to_account_info().key)]
+    pub mint: Account<'info, Mint>,
+
+    // The escrow account, it will hold all necessary info about the trade.
+    #[account(init, payer = initializer, space = 8 + EscrowAccount::LEN)]
+    pub escrow_account: Account<'info, EscrowAccount>,
+
+    // This escrow's PDA, which owns the vault.
+    #[account(
+        seeds = [ESCROW_PDA_SEED, escrow_account.to_account_info().key.as_ref()],
+        bump = bump,
+    )]
+    pub pda_account: AccountInfo<'info>,
+
+    // The vault that holds the offered tokens until the escrow is exchanged or cancelled.
+    #[account(
+        init,
+        seeds = [VAULT_PDA_SEED, escrow_account.to_account_info().key.as_ref()],
+        bump = vault_bump,
+        payer = initializer,
+        token::mint = mint,
+        token::authority = pda_account,
+    )]
+    pub vault: Account<'info, TokenAccount>,
+
+    // The order book for this escrow's mint pair, which lists it while it's open.
+    #[account(
+        mut,
+        seeds = [
+            ORDER_BOOK_SEED,
+            initializer_deposit_token_account.mint.as_ref(),
+            spl_token::native_mint::ID.as_ref()
+        ],
+        bump = order_book.bump,
+    )]
+    pub order_book: Account<'info, OrderBook>,
+    pub system_program: Program<'info, System>,
+
+    // The token program.
+    pub token_program: Program<'info, Token>,
+
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct ExchangeSolOffer<'info> {
+    // Receives the escrowed lamports.
+    #[account(
+        signer,
+        mut,
+        constraint = escrow_account.can_be_taken_by(taker.key) @ ErrorCode::TakerNotAllowed
+    )]
+    pub taker: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = taker_deposit_token_account.mint == escrow_account.requested_mint @ ErrorCode::RequestedMintMismatch
+    )]
+    pub taker_deposit_token_account: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub initializer_receive_token_account: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub initializer_main_account: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = escrow_account.native_leg == NativeLeg::Offered @ ErrorCode::NativeLegMismatch,
+        constraint = escrow_account.initializer_receive_token_account == *initializer_receive_token_account.to_account_info().key,
+        constraint = escrow_account.initializer_key == *initializer_main_account.key,
+        close = initializer_main_account
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+    // The order book listing this escrow.
+    #[account(
+        mut,
+        seeds = [
+            ORDER_BOOK_SEED,
+            escrow_account.offered_mint.as_ref(),
+            escrow_account.requested_mint.as_ref()
+        ],
+        bump = order_book.bump,
+    )]
+    pub order_book: Account<'info, OrderBook>,
+    // The protocol fee settings.
+    #[account(seeds = [CONFIG_SEED], bump = config.bump)]
+    pub config: Account<'info, EscrowConfig>,
+    // Receives the fee on the lamports.
+    #[account(
+        mut,
+        constraint = *fee_recipient.key == config.fee_recipient @ ErrorCode::InvalidFeeAccount
+    )]
+    pub fee_recipient: AccountInfo<'info>,
+    // Receives the fee on the requested leg. Must belong to the config's fee_recipient.
+    #[account(
+        mut,
+        constraint = config.is_fee_token_account(&requested_fee_token_account, &escrow_account.requested_mint) @ ErrorCode::InvalidFeeAccount
+    )]
+    pub requested_fee_token_account: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct ExchangeSolRequest<'info> {
+    // Pays the initializer in lamports.
+    #[account(
+        signer,
+        mut,
+        constraint = escrow_account.can_be_taken_by(taker.key) @ ErrorCode::TakerNotAllowed,
+        constraint = taker.lamports() >= escrow_account.taker_amount @ ErrorCode::InsufficientTakerFunds
+    )]
+    pub taker: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = taker_receive_token_account.mint == escrow_account.offered_mint @ ErrorCode::OfferedMintMismatch
+    )]
+    pub taker_receive_token_account: Account<'info, TokenAccount>,
+    // The vault holding the deposit.
+    #[account(mut)]
+    pub pda_deposit_token_account: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub initializer_main_account: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = escrow_account.native_leg == NativeLeg::Requested @ ErrorCode::NativeLegMismatch,
+        constraint = escrow_account.vault == *pda_deposit_token_account.to_account_info().key,
+        constraint = escrow_account.initializer_key == *initializer_main_account.key,
+        close = initializer_main_account
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+    #[account(
+        seeds = [ESCROW_PDA_SEED, escrow_account.to_account_info().key.as_ref()],
+        bump = escrow_account.bump,
+    )]
+    pub pda_account: AccountInfo<'info>,
+    // The order book listing this escrow.
+    #[account(
+        mut,
+        seeds = [
+            ORDER_BOOK_SEED,
+            escrow_account.offered_mint.as_ref(),
+            escrow_account.requested_mint.as_ref()
+        ],
+        bump = order_book.bump,
+    )]
+    pub order_book: Account<'info, OrderBook>,
+    // The protocol fee settings.
+    #[account(seeds = [CONFIG_SEED], bump = config.bump)]
+    pub config: Account<'info, EscrowConfig>,
+    // Receives the fee on the lamports.
+    #[account(
+        mut,
+        constraint = *fee_recipient.key == config.fee_recipient @ ErrorCode::InvalidFeeAccount
+    )]
+    pub fee_recipient: AccountInfo<'info>,
+    // Receives the fee on the offered leg. Must belong to the config's fee_recipient.
+    #[account(
+        mut,
+        constraint = config.is_fee_token_account(&offered_fee_token_account, &escrow_account.offered_mint) @ ErrorCode::InvalidFeeAccount
+    )]
+    pub offered_fee_token_account: Account<'info, TokenAccount>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct CancelSolOffer<'info> {
+    // The initializer or their delegated canceller. Only has to sign if the
+    // escrow hasn't expired (see cancel_sol_offer).
+    pub authority: AccountInfo<'info>,
+    // Receives the escrowed lamports, along with the escrow account's rent.
+    #[account(mut)]
+    pub initializer: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = escrow_account.native_leg == NativeLeg::Offered @ ErrorCode::NativeLegMismatch,
+        constraint = escrow_account.initializer_key == *initializer.key,
+        close = initializer
+    )]
+    pub escrow_account: Account<'info, EscrowAccount>,
+    // The order book listing this escrow.
+    #[account(
+        mut,
+        seeds = [
+            ORDER_BOOK_SEED,
+            escrow_account.offered_mint.as_ref(),
+            escrow_account.requested_mint.as_ref()
+        ],
+        bump = order_book.bump,
+    )]
+    pub order_book: Account<'info, OrderBook>,
+}
+
+#[derive(Accounts)]
+#[instruction(offered_amounts: Vec<u64>, requested_amounts: Vec<u64>, bump: u8)]
+pub struct InitializeBundleEscrow<'info> {
+    #[account(signer, mut)]
+    pub initializer: AccountInfo<'info>,
+    #[account(init, payer = initializer, space = 8 + BundleEscrowAccount::LEN)]
+    pub bundle_escrow_account: Account<'info, BundleEscrowAccount>,
+    // This bundle's PDA, which becomes the owner of every deposit account.
+    #[account(
+        seeds = [ESCROW_PDA_SEED, bundle_escrow_account.to_account_info().key.as_ref()],
+        bump = bump,
+    )]
+    pub pda_account: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct ExchangeBundle<'info> {
+    #[account(signer)]
+    pub taker: AccountInfo<'info>,
+    #[account(mut)]
+    pub initializer_main_account: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = bundle_escrow_account.initializer_key == *initializer_main_account.key,
+        close = initializer_main_account
+    )]
+    pub bundle_escrow_account: Account<'info, BundleEscrowAccount>,
+    #[account(
+        seeds = [ESCROW_PDA_SEED, bundle_escrow_account.to_account_info().key.as_ref()],
+        bump = bundle_escrow_account.bump,
+    )]
+    pub pda_account: AccountInfo<'info>,
+    // The protocol fee settings.
+    #[account(seeds = [CONFIG_SEED], bump = config.bump)]
+    pub config: Account<'info, EscrowConfig>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct CancelBundleEscrow<'info> {
+    #[account(signer, mut)]
+    pub initializer: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = bundle_escrow_account.initializer_key == *initializer.key,
+        close = initializer
+    )]
+    pub bundle_escrow_account: Account<'info, BundleEscrowAccount>,
+    #[account(
+        seeds = [ESCROW_PDA_SEED, bundle_escrow_account.to_account_info().key.as_ref()],
+        bump = bundle_escrow_account.bump,
+    )]
+    pub pda_account: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+// Which leg of the trade, if any, is native SOL rather than an SPL token. The
+// mint recorded for a native leg is spl_token::native_mint.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq)]
+pub enum NativeLeg {
+    None,
+    Offered,
+    Requested,
+}
+
+// Which leg of the trade is an NFT (see NftTerms).
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq)]
+pub enum NftLeg {
+    Offered,
+    Requested,
+}
+
+// Makes one leg of an escrow an NFT. The NFT's mint must be passed as the first
+// remaining account to initialize_escrow(_with_vault), exchange and
+// exchange_partial, followed by its Metaplex metadata account if a collection
+// is set.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct NftTerms {
+    pub leg: NftLeg,
+    // If set, the NFT must be a verified member of this collection.
+    pub collection: Option<Pubkey>,
+}
+
+impl NftTerms {
+    pub const LEN: usize = 1 + (1 + 32);
+
+    // An NFT is traded one at a time.
+    pub fn is_valid_amount(&self, initializer_amount: u64, taker_amount: u64) -> bool {
+        match self.leg {
+            NftLeg::Offered => initializer_amount == 1,
+            NftLeg::Requested => taker_amount == 1,
+        }
+    }
+
+    pub fn verify(
+        &self,
+        offered_mint: &Pubkey,
+        requested_mint: &Pubkey,
+        initializer_amount: u64,
+        taker_amount: u64,
+        remaining_accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        if !self.is_valid_amount(initializer_amount, taker_amount) {
+            return Err(ErrorCode::InvalidNftAmount.into());
+        }
+        let nft_mint = match self.leg {
+            NftLeg::Offered => offered_mint,
+            NftLeg::Requested => requested_mint,
+        };
+
+        let mint_info = remaining_accounts
+            .get(0)
+            .ok_or(ErrorCode::MissingNftAccounts)?;
+        if mint_info.key != nft_mint || *mint_info.owner != spl_token::ID {
+            return Err(ErrorCode::MissingNftAccounts.into());
+        }
+        // The supply is checked every time, since whoever holds the mint
+        // authority could have minted more since the escrow was initialized.
+        let mint = spl_token::state::Mint::unpack(&mint_info.try_borrow_data()?)?;
+        if mint.decimals != 0 || mint.supply != 1 {
+            return Err(ErrorCode::NotAnNft.into());
+        }
+
+        if let Some(collection) = &self.collection {
+            let metadata = remaining_accounts
+                .get(1)
+                .ok_or(ErrorCode::MissingNftAccounts)?;
+            metadata::verify_collection(metadata, nft_mint, collection)?;
+        }
+        Ok(())
+    }
+}
+
+// A taker_amount that falls linearly from start_taker_amount at start_time to
+// end_taker_amount at end_time, and stays there.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct DutchAuction {
+    pub start_taker_amount: u64,
+    pub end_taker_amount: u64,
+    pub start_time: i64,
+    pub end_time: i64,
+}
+
+impl DutchAuction {
+    pub const LEN: usize = 8 + 8 + 8 + 8;
+
+    pub fn is_valid(&self) -> bool {
+        self.end_taker_amount > 0
+            && self.start_taker_amount >= self.end_taker_amount
+            && self.start_time < self.end_time
+    }
+
+    pub fn price_at(&self, now: i64) -> Result<u64, ProgramError> {
+        if now <= self.start_time {
+            return Ok(self.start_taker_amount);
+        }
+        if now >= self.end_time {
+            return Ok(self.end_taker_amount);
+        }
+        // Rounded down, in the taker's favour by at most one unit.
+        let decay = (self.start_taker_amount - self.end_taker_amount) as u128
+            * (now - self.start_time) as u128
+            / (self.end_time - self.start_time) as u128;
+        u64::try_from(decay)
+            .map(|decay| self.start_taker_amount - decay)
+            .map_err(|_| ErrorCode::NumericalOverflow.into())
+    }
+}
+
+// Where an escrow is in its lifecycle. Escrows without an arbiter stay Open
+// until they're closed; see set_arbiter for the others. Closing is terminal
+// in every state, so no state records it.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq)]
+pub enum EscrowState {
+    Open,
+    Funded,
+    Approved,
+    Disputed,
+}
+
+// The taker's side of an arbitrated escrow, recorded by fund.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct Funding {
+    pub taker: Pubkey,
+    // Holds the taker's payment (see TAKER_VAULT_SEED).
+    pub taker_vault: Pubkey,
+    // Where unpaid tokens go back to.
+    pub taker_deposit_token_account: Pubkey,
+    // Where the taker's share of the deposit goes.
+    pub taker_receive_token_account: Pubkey,
+    pub initializer_approved: bool,
+    pub taker_approved: bool,
+    // The most protocol fee the taker signed for (see check_max_fee), checked
+    // again when the escrow is released or resolved.
+    pub max_fee_bps: u16,
+}
+
+impl Funding {
+    pub const LEN: usize = 32 + 32 + 32 + 32 + 1 + 1 + 2;
+}
+
+// Prices an escrow off an oracle: exchange charges initializer_amount at the
+// price in price_account (see oracle::PriceFeed), moved by spread_bps, which is
+// negative for a discount. price_account must be owned by oracle_program and
+// have been published no more than max_staleness seconds before the exchange.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct OraclePricing {
+    pub oracle_program: Pubkey,
+    pub price_account: Pubkey,
+    pub spread_bps: i16,
+    pub max_staleness: i64,
+}
+
+impl OraclePricing {
+    pub const LEN: usize = 32 + 32 + 2 + 8;
+
+    pub fn is_valid(&self) -> bool {
+        self.spread_bps > -(BASIS_POINTS as i16)
+            && self.spread_bps <= BASIS_POINTS as i16
+            && self.max_staleness > 0
+    }
+
+    // What a taker pays for initializer_amount at the oracle's current price,
+    // rounded up.
+    pub fn taker_amount(
+        &self,
+        price_account: &AccountInfo,
+        initializer_amount: u64,
+        now: i64,
+    ) -> Result<u64, ProgramError> {
+        if *price_account.key != self.price_account {
+            return Err(ErrorCode::InvalidOracleAccount.into());
+        }
+        let feed = oracle::read_price_feed(price_account, &self.oracle_program)?;
+        if now.saturating_sub(feed.publish_time) > self.max_staleness {
+            return Err(ErrorCode::StaleOraclePrice.into());
+        }
+
+        // Multiplies everything out before dividing once, rounding up, so the
+        // initializer is never paid less than the price.
+        let scale = feed
+            .expo
+            .checked_abs()
+            .and_then(|expo| 10u128.checked_pow(expo as u32))
+            .ok_or(ErrorCode::NumericalOverflow)?;
+        let (numerator_scale, denominator_scale) = if feed.expo >= 0 {
+            (scale, 1)
+        } else {
+            (1, scale)
+        };
+        let denominator = denominator_scale
+            .checked_mul(BASIS_POINTS as u128)
+            .ok_or(ErrorCode::NumericalOverflow)?;
+        let amount = (initializer_amount as u128)
+            .checked_mul(feed.price as u128)
+            .and_then(|amount| {
+                amount.checked_mul((BASIS_POINTS as i32 + self.spread_bps as i32) as u128)
+            })
+            .and_then(|amount| amount.checked_mul(numerator_scale))
+            .and_then(|amount| amount.checked_add(denominator - 1))
+            .map(|amount| amount / denominator)
+            .ok_or(ErrorCode::NumericalOverflow)?;
+        let amount = u64::try_from(amount).map_err(|_| ErrorCode::NumericalOverflow)?;
+        if amount == 0 {
+            return Err(ErrorCode::InvalidOraclePrice.into());
+        }
+        Ok(amount)
+    }
+}
+
+// The best bid so far on an English auction.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct Bid {
+    pub bidder: Pubkey,
+    pub amount: u64,
+    // The bid vault holding amount (see BID_VAULT_SEED).
+    pub vault: Pubkey,
+    // Where the bid goes back to if it's outbid.
+    pub refund_token_account: Pubkey,
+    // Where the deposit goes if the bid wins.
+    pub receive_token_account: Pubkey,
+    // The most protocol fee the bidder signed for, checked again if the bid
+    // wins.
+    pub max_fee_bps: u16,
+}
+
+impl Bid {
+    pub const LEN: usize = 32 + 8 + 32 + 32 + 32 + 2;
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct EnglishAuction {
+    pub min_bid: u64,
+    pub ends_at: i64,
+    pub best_bid: Option<Bid>,
+}
+
+impl EnglishAuction {
+    pub const LEN: usize = 8 + 8 + (1 + Bid::LEN);
+}
+
+#[account]
+pub struct EscrowAccount {
+    // Always ESCROW_ACCOUNT_VERSION. Kept first, so later layouts can be told
+    // apart by reading a single byte.
+    pub version: u8,
+    pub initializer_key: Pubkey,
+    pub initializer_deposit_token_account: Pubkey,
+    pub initializer_receive_token_account: Pubkey,
+    // The mint the initializer deposited (X) and the mint they want in return (Y).
+    pub offered_mint: Pubkey,
+    pub requested_mint: Pubkey,
+    // The amounts still outstanding. These start out as the full terms of the
+    // trade and go down with every partial fill.
+    pub initializer_amount: u64,
+    pub taker_amount: u64,
+    // Unix timestamp after which the escrow can no longer be exchanged, and
+    // anyone can return the deposit with crank_expired. None means never.
+    pub expires_at: Option<i64>,
+    // If set, the only taker allowed to exchange. None means anyone can.
+    pub allowed_taker: Option<Pubkey>,
+    // If set, can cancel the escrow on the initializer's behalf.
+    pub canceller: Option<Pubkey>,
+    pub native_leg: NativeLeg,
+    // Set if one leg is an NFT.
+    pub nft: Option<NftTerms>,
+    // Set if the escrow is priced as a Dutch auction, in which case
+    // taker_amount is only updated when the auction is set or exchanged.
+    pub auction: Option<DutchAuction>,
+    // Set if the escrow is sold through an English auction, in which case
+    // taker_amount is the best bid so far (or the minimum bid).
+    pub english_auction: Option<EnglishAuction>,
+    // Set if the escrow is priced off an oracle, in which case taker_amount is
+    // only updated when it's exchanged.
+    pub oracle: Option<OraclePricing>,
+    // If set, the escrow is taken with fund, and this key resolves disputes.
+    pub arbiter: Option<Pubkey>,
+    pub state: EscrowState,
+    // Set once an arbitrated escrow has been funded.
+    pub funding: Option<Funding>,
+    // Bump for this escrow's PDA (see ESCROW_PDA_SEED).
+    pub bump: u8,
+    // The token account the PDA holds the deposit in. Same as
+    // initializer_deposit_token_account unless the escrow uses a vault.
+    pub vault: Pubkey,
+    // Zeroed space for future fields, so they can be added without changing the
+    // account's size. The Option fields above vary in length, so this doesn't
+    // sit at a fixed offset, but every handler that writes an escrow back zeroes
+    // it first (see zero_escrow_data): everything past the serialized escrow,
+    // these bytes included, is always zero. A field carved out of this
+    // therefore reads as None or 0 on existing escrows.
+    pub reserved: [u8; 64],
+}
+
+impl EscrowAccount {
+    pub const LEN: usize = 1
+        + 32
+        + 32
+        + 32
+        + 32
+        + 32
+        + 8
+        + 8
+        + (1 + 8)
+        + (1 + 32)
+        + (1 + 32)
+        + 1
+        + (1 + NftTerms::LEN)
+        + (1 + DutchAuction::LEN)
+        + (1 + EnglishAuction::LEN)
+        + (1 + OraclePricing::LEN)
+        + (1 + 32)
+        + 1
+        + (1 + Funding::LEN)
+        + 1
+        + 32
+        + 64;
+
+    // Whether the deposit sits in a program-created vault (see
+    // initialize_escrow_with_vault), rather than in the initializer's own
+    // token account.
+    pub fn uses_vault(&self) -> bool {
+        self.vault != self.initializer_deposit_token_account
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires_at
+            .map_or(false, |expires_at| now >= expires_at)
+    }
+
+    // For a funded escrow, whether key is the initializer and whether it's the
+    // taker (it can be both). Errors if it's neither.
+    pub fn funded_party(&self, key: &Pubkey) -> Result<(bool, bool), ProgramError> {
+        let funding = match (self.state, &self.funding) {
+            (EscrowState::Funded, Some(funding)) => funding,
+            _ => return Err(ErrorCode::InvalidEscrowState.into()),
+        };
+        let is_initializer = *key == self.initializer_key;
+        let is_taker = *key == funding.taker;
+        if !is_initializer && !is_taker {
+            return Err(ErrorCode::NotAParty.into());
+        }
+        Ok((is_initializer, is_taker))
+    }
+
+    pub fn best_bid(&self) -> Option<&Bid> {
+        self.english_auction
+            .as_ref()
+            .and_then(|auction| auction.best_bid.as_ref())
+    }
+
+    // What a taker would pay for everything left in the escrow right now.
+    pub fn current_taker_amount(&self, now: i64) -> Result<u64, ProgramError> {
+        match &self.auction {
+            Some(auction) => auction.price_at(now),
+            None => Ok(self.taker_amount),
+        }
+    }
+
+    pub fn verify_nft(&self, remaining_accounts: &[AccountInfo]) -> ProgramResult {
+        match &self.nft {
+            Some(nft) => nft.verify(
+                &self.offered_mint,
+                &self.requested_mint,
+                self.initializer_amount,
+                self.taker_amount,
+                remaining_accounts,
+            ),
+            None => Ok(()),
+        }
+    }
+
+    pub fn can_be_taken_by(&self, taker: &Pubkey) -> bool {
+        self.allowed_taker
+            .map_or(true, |allowed_taker| allowed_taker == *taker)
+    }
+
+    pub fn created_event(&self, escrow: Pubkey, slot: u64) -> EscrowCreated {
+        EscrowCreated {
+            escrow,
+            initializer: self.initializer_key,
+            initializer_deposit_token_account: self.initializer_deposit_token_account,
+            initializer_receive_token_account: self.initializer_receive_token_account,
+            vault: self.vault,
+            offered_mint: self.offered_mint,
+            requested_mint: self.requested_mint,
+            initializer_amount: self.initializer_amount,
+            taker_amount: self.taker_amount,
+            expires_at: self.expires_at,
+            allowed_taker: self.allowed_taker,
+            slot,
+        }
+    }
+
+    // Expects the remaining amounts to have been updated for the fill already.
+    // Fees are left at 0 for the caller to fill in.
+    pub fn exchanged_event(
+        &self,
+        escrow: Pubkey,
+        taker: Pubkey,
+        initializer_amount: u64,
+        taker_amount: u64,
+        slot: u64,
+    ) -> EscrowExchanged {
+        EscrowExchanged {
+            escrow,
+            initializer: self.initializer_key,
+            taker,
+            offered_mint: self.offered_mint,
+            requested_mint: self.requested_mint,
+            initializer_amount,
+            taker_amount,
+            offered_fee: 0,
+            requested_fee: 0,
+            remaining_initializer_amount: self.initializer_amount,
+            remaining_taker_amount: self.taker_amount,
+            slot,
+        }
+    }
+
+    pub fn cancelled_event(&self, escrow: Pubkey, expired: bool, slot: u64) -> EscrowCancelled {
+        EscrowCancelled {
+            escrow,
+            initializer: self.initializer_key,
+            offered_mint: self.offered_mint,
+            requested_mint: self.requested_mint,
+            initializer_amount: self.initializer_amount,
+            taker_amount: self.taker_amount,
+            expired,
+            slot,
+        }
+    }
+
+    pub fn can_be_cancelled_by(&self, authority: &Pubkey) -> bool {
+        self.initializer_key == *authority || self.canceller == Some(*authority)
+    }
+}
+
+// The EscrowAccount layout of the original program, which exchange_legacy_escrow
+// and cancel_legacy_escrow read old escrows as. Its deposit was owned by a
+// single PDA for the whole program, derived from [ESCROW_PDA_SEED] alone.
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct EscrowAccountV1 {
+    pub initializer_key: Pubkey,
+    pub initializer_deposit_token_account: Pubkey,
+    pub initializer_receive_token_account: Pubkey,
+    pub initializer_amount: u64,
+    pub taker_amount: u64,
+}
+
+impl EscrowAccountV1 {
+    pub const LEN: usize = 32 + 32 + 32 + 8 + 8;
+
+    // v1 accounts have EscrowAccount's discriminator but no version byte, so
+    // they're recognized by their size.
+    pub fn try_from_account_info(
+        account: &AccountInfo,
+        program_id: &Pubkey,
+    ) -> Result<Self, ProgramError> {
+        let data = account.try_borrow_data()?;
+        if account.owner != program_id
+            || data.len() != 8 + Self::LEN
+            || data[..8] != EscrowAccount::discriminator()[..]
+        {
+            return Err(ErrorCode::NotALegacyEscrow.into());
+        }
+        Self::deserialize(&mut &data[8..]).map_err(|_| ErrorCode::NotALegacyEscrow.into())
+    }
+
+    // v1 didn't store the mints, so they're taken from the token accounts.
+    pub fn exchanged_event(
+        &self,
+        escrow: Pubkey,
+        taker: Pubkey,
+        offered_mint: Pubkey,
+        requested_mint: Pubkey,
+        slot: u64,
+    ) -> EscrowExchanged {
+        EscrowExchanged {
+            escrow,
+            initializer: self.initializer_key,
+            taker,
+            offered_mint,
+            requested_mint,
+            initializer_amount: self.initializer_amount,
+            taker_amount: self.taker_amount,
+            offered_fee: 0,
+            requested_fee: 0,
+            remaining_initializer_amount: 0,
+            remaining_taker_amount: 0,
+            slot,
+        }
+    }
+
+    pub fn cancelled_event(
+        &self,
+        escrow: Pubkey,
+        offered_mint: Pubkey,
+        requested_mint: Pubkey,
+        slot: u64,
+    ) -> EscrowCancelled {
+        EscrowCancelled {
+            escrow,
+            initializer: self.initializer_key,
+            offered_mint,
+            requested_mint,
+            initializer_amount: self.initializer_amount,
+            taker_amount: self.taker_amount,
+            expired: false,
+            slot,
+        }
+    }
+}
+
+// Lists the open escrows offering offered_mint in exchange for requested_mint.
+#[account]
+pub struct OrderBook {
+    pub offered_mint: Pubkey,
+    pub requested_mint: Pubkey,
+    pub bump: u8,
+    // Unordered: removing an escrow moves the last one into its slot.
+    pub escrows: Vec<Pubkey>,
+}
+
+impl OrderBook {
+    pub const LEN: usize = 32 + 32 + 1 + 4 + 32 * MAX_ORDER_BOOK_ENTRIES;
+
+    // Lists escrow, unless the book is full. Anyone can fill a book with dust
+    // escrows, so a full book must not stop escrows from being created: they
+    // go unlisted instead (see list_escrow).
+    pub fn insert(&mut self, escrow: Pubkey) {
+        if self.escrows.len() >= MAX_ORDER_BOOK_ENTRIES {
+            msg!("The order book is full, so the escrow isn't listed");
+            return;
+        }
+        self.escrows.push(escrow);
+    }
+
+    pub fn remove(&mut self, escrow: &Pubkey) {
+        if let Some(index) = self.escrows.iter().position(|key| key == escrow) {
+            self.escrows.swap_remove(index);
+        }
+    }
+}
+
+// Global settings for the deployment, at the PDA derived from [CONFIG_SEED].
+#[account]
+pub struct EscrowConfig {
+    // Can change these settings with update_config.
+    pub admin: Pubkey,
+    // Fee on the offered leg, taken out of what the taker receives.
+    pub offered_leg_fee_bps: u16,
+    // Fee on the requested leg, taken out of what the initializer receives.
+    pub requested_leg_fee_bps: u16,
+    // Owner of the token accounts fees are paid to.
+    pub fee_recipient: Pubkey,
+    pub bump: u8,
+}
+
+impl EscrowConfig {
+    pub const LEN: usize = 32 + 2 + 2 + 32 + 1;
+
+    pub fn offered_leg_fee(&self, amount: u64) -> Result<u64, ProgramError> {
+        fee(amount, self.offered_leg_fee_bps)
+    }
+
+    pub fn requested_leg_fee(&self, amount: u64) -> Result<u64, ProgramError> {
+        fee(amount, self.requested_leg_fee_bps)
+    }
+
+    // Fails if either leg's fee is above the max_fee_bps the taker signed for.
+    pub fn check_max_fee(&self, max_fee_bps: u16) -> ProgramResult {
+        if self.offered_leg_fee_bps.max(self.requested_leg_fee_bps) > max_fee_bps {
+            return Err(ErrorCode::FeeAboveMaximum.into());
+        }
+        Ok(())
+    }
+
+    // Whether fees on mint can be paid to fee_token_account: it must belong to
+    // fee_recipient and hold mint. Every instruction that charges a fee checks
+    // its fee token accounts with this.
+    pub fn is_fee_token_account(&self, fee_token_account: &TokenAccount, mint: &Pubkey) -> bool {
+        fee_token_account.owner == self.fee_recipient && fee_token_account.mint == *mint
+    }
+}
+
+// Rounds down, so dust amounts pay no fee.
+fn fee(amount: u64, fee_bps: u16) -> Result<u64, ProgramError> {
+    let fee = (amount as u128)
+        .checked_mul(fee_bps as u128)
+        .and_then(|fee| fee.checked_div(BASIS_POINTS as u128))
+        .ok_or(ErrorCode::NumericalOverflow)?;
+    u64::try_from(fee).map_err(|_| ErrorCode::NumericalOverflow.into())
+}
+
+fn validate_fee_bps(fee_bps: u16) -> ProgramResult {
+    if fee_bps > MAX_FEE_BPS {
+        return Err(ErrorCode::InvalidFeeBps.into());
+    }
+    Ok(())
+}
+
+// Checks that a fee token account passed in ctx.remaining_accounts belongs to
+// the config's fee_recipient and holds mint.
+fn validate_fee_token_account(
+    fee_token_account: &AccountInfo,
+    config: &EscrowConfig,
+    mint: &Pubkey,
+) -> ProgramResult {
+    let fee_token_account: Account<TokenAccount> = Account::try_from(fee_token_account)?;
+    if !config.is_fee_token_account(&fee_token_account, mint) {
+        return Err(ErrorCode::InvalidFeeAccount.into());
+    }
+    Ok(())
+}
+
+// Checks that admin can create the config. A program deployed through the
+// upgradeable loader has a ProgramData account, at the address derived from
+// the program's id, recording its upgrade authority; only that authority can.
+// A program with no upgrade authority, whether it's been made immutable or
+// wasn't deployed through the upgradeable loader at all, can't have a config.
+fn validate_config_admin(
+    escrow_program: &AccountInfo,
+    program_data: &AccountInfo,
+    admin: &Pubkey,
+    program_id: &Pubkey,
+) -> ProgramResult {
+    if escrow_program.key != program_id {
+        return Err(ErrorCode::InvalidProgramData.into());
+    }
+    if *escrow_program.owner != bpf_loader_upgradeable::id() {
+        return Err(ErrorCode::NotUpgradeAuthority.into());
+    }
+
+    let (program_data_address, _) =
+        Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    if *program_data.key != program_data_address {
+        return Err(ErrorCode::InvalidProgramData.into());
+    }
+    let upgrade_authority = match limited_deserialize(&program_data.try_borrow_data()?) {
+        Ok(UpgradeableLoaderState::ProgramData {
+            upgrade_authority_address,
+            ..
+        }) => upgrade_authority_address,
+        _ => return Err(ErrorCode::InvalidProgramData.into()),
+    };
+    if upgrade_authority != Some(*admin) {
+        return Err(ErrorCode::NotUpgradeAuthority.into());
+    }
+    Ok(())
+}
+
+// One token on one side of a bundle escrow.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct Leg {
+    pub mint: Pubkey,
+    // For offered legs, the deposit token account owned by the bundle's PDA. For
+    // requested legs, the initializer's token account to receive the tokens in.
+    pub token_account: Pubkey,
+    pub amount: u64,
+}
+
+impl Leg {
+    pub const LEN: usize = 32 + 32 + 8;
+}
+
+// The most legs a bundle escrow can have on either side. Bounded so the
+// account's size (and the exchange's compute) is known up front.
+pub const MAX_BUNDLE_LEGS: usize = 4;
+
+#[account]
+pub struct BundleEscrowAccount {
+    pub initializer_key: Pubkey,
+    // Bump for this bundle's PDA (see ESCROW_PDA_SEED).
+    pub bump: u8,
+    pub offered: Vec<Leg>,
+    pub requested: Vec<Leg>,
+}
+
+impl BundleEscrowAccount {
+    pub const LEN: usize = 32 + 1 + 2 * (4 + MAX_BUNDLE_LEGS * Leg::LEN);
+
+    pub fn is_valid_leg_count(len: usize) -> bool {
+        len > 0 && len <= MAX_BUNDLE_LEGS
+    }
+}
+
+#[event]
+pub struct EscrowCreated {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    pub initializer_deposit_token_account: Pubkey,
+    pub initializer_receive_token_account: Pubkey,
+    pub vault: Pubkey,
+    pub offered_mint: Pubkey,
+    pub requested_mint: Pubkey,
+    pub initializer_amount: u64,
+    pub taker_amount: u64,
+    pub expires_at: Option<i64>,
+    pub allowed_taker: Option<Pubkey>,
+    pub slot: u64,
+}
+
+// Emitted for every fill, partial or not.
+#[event]
+pub struct EscrowExchanged {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    pub taker: Pubkey,
+    pub offered_mint: Pubkey,
+    pub requested_mint: Pubkey,
+    // What this fill traded, fees included.
+    pub initializer_amount: u64,
+    pub taker_amount: u64,
+    pub offered_fee: u64,
+    pub requested_fee: u64,
+    // What's left to trade. Both are 0 once the escrow has been fully filled.
+    pub remaining_initializer_amount: u64,
+    pub remaining_taker_amount: u64,
+    pub slot: u64,
+}
+
+// Emitted when the initializer changes the escrow's amounts with update_escrow.
+#[event]
+pub struct EscrowUpdated {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    // The new amounts left to trade.
+    pub initializer_amount: u64,
+    pub taker_amount: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct EscrowCancelled {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    pub offered_mint: Pubkey,
+    pub requested_mint: Pubkey,
+    // What was left to trade when the escrow was cancelled.
+    pub initializer_amount: u64,
+    pub taker_amount: u64,
+    // True if the escrow was cancelled by anyone after it expired, rather than
+    // by the initializer or their canceller.
+    pub expired: bool,
+    pub slot: u64,
+}
+
+#[event]
+pub struct BidPlaced {
+    pub escrow: Pubkey,
+    pub bidder: Pubkey,
+    pub amount: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct EscrowReclaimed {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    // What the escrow was still offering, and what was actually left of it.
+    pub initializer_amount: u64,
+    pub remaining: u64,
+    pub slot: u64,
+}
+
+#[error]
+pub enum ErrorCode {
+    #[msg("The taker's deposit token account does not hold the requested mint.")]
+    RequestedMintMismatch,
+    #[msg("The taker's receive token account does not hold the offered mint.")]
+    OfferedMintMismatch,
+    #[msg("The fill amount is zero, too small to receive any tokens, or not less than what is left (exchange takes the rest).")]
+    InvalidFillAmount,
+    #[msg("The taker's deposit token account does not hold enough tokens.")]
+    InsufficientTakerFunds,
+    #[msg("The expiry must be in the future.")]
+    InvalidExpiry,
+    #[msg("The escrow has expired.")]
+    EscrowExpired,
+    #[msg("The escrow has not expired yet.")]
+    EscrowNotExpired,
+    #[msg("This escrow can only be taken by its designated taker.")]
+    TakerNotAllowed,
+    #[msg("Only the initializer or their delegated canceller can cancel this escrow.")]
+    UnauthorizedCanceller,
+    #[msg("This instruction does not support the escrow's native SOL leg, if any.")]
+    NativeLegMismatch,
+    #[msg("A bundle needs 1 to MAX_BUNDLE_LEGS legs per side, each with a funded token account.")]
+    InvalidBundle,
+    #[msg("The token accounts passed in do not match the bundle's legs.")]
+    BundleAccountMismatch,
+    #[msg("Fees can't be more than 500 basis points.")]
+    InvalidFeeBps,
+    #[msg("Only the config's admin can update it.")]
+    UnauthorizedAdmin,
+    #[msg("The fee token account doesn't belong to the fee recipient, or holds the wrong mint.")]
+    InvalidFeeAccount,
+    #[msg("Numerical overflow.")]
+    NumericalOverflow,
+    #[msg("The order book for this mint pair is full, so the escrow can't be listed yet.")]
+    OrderBookFull,
+    #[msg("Escrow amounts must be greater than zero.")]
+    InvalidEscrowAmount,
+    #[msg("The initializer's deposit token account does not hold enough tokens.")]
+    InsufficientInitializerFunds,
+    #[msg("The escrow's terms don't match what the taker expected.")]
+    TermsMismatch,
+    #[msg("An NFT leg must trade exactly one token.")]
+    InvalidNftAmount,
+    #[msg("The NFT's mint (and metadata account, if a collection is required) must be passed in the remaining accounts.")]
+    MissingNftAccounts,
+    #[msg("The NFT leg's mint must have 0 decimals and a supply of 1.")]
+    NotAnNft,
+    #[msg("The metadata account is not the NFT's Metaplex metadata.")]
+    InvalidNftMetadata,
+    #[msg("The NFT is not a verified member of the escrow's collection.")]
+    CollectionNotVerified,
+    #[msg("The escrow's deposit still holds the offered amount.")]
+    EscrowNotStale,
+    #[msg("A Dutch auction's price must fall from start to a non-zero end price, between a start and a later end time, and can't be used to request an NFT or with oracle pricing.")]
+    InvalidDutchAuction,
+    #[msg(
+        "Dutch auctions can only be exchanged in full, and are repriced with set_dutch_auction."
+    )]
+    DutchAuctionUnsupported,
+    #[msg("An English auction needs a non-zero minimum bid and a future end, on an escrow with no expiry, other auction or oracle pricing that doesn't request an NFT.")]
+    InvalidEnglishAuction,
+    #[msg("This escrow is an English auction: bid on it with place_bid and close it with settle.")]
+    EnglishAuctionUnsupported,
+    #[msg("This escrow is not an English auction.")]
+    NotAnEnglishAuction,
+    #[msg("The auction has ended.")]
+    AuctionEnded,
+    #[msg("The auction has not ended yet.")]
+    AuctionNotEnded,
+    #[msg("A bid must be at least the minimum bid, and more than the best bid so far.")]
+    BidTooLow,
+    #[msg("The accounts passed in do not match the auction's best bid.")]
+    BidMismatch,
+    #[msg("The auction has no bids.")]
+    NoBids,
+    #[msg("The auction has bids, so it can only be settled.")]
+    AuctionHasBids,
+    #[msg("Oracle pricing needs a spread between -10000 (exclusive) and 10000 basis points and a positive staleness limit, on an escrow with no auction that doesn't request an NFT.")]
+    InvalidOraclePricing,
+    #[msg("The price account is missing, isn't the escrow's, or isn't a price feed owned by its oracle program.")]
+    InvalidOracleAccount,
+    #[msg("The oracle's price is older than the escrow's staleness limit.")]
+    StaleOraclePrice,
+    #[msg("The oracle's price is too small to price the escrow.")]
+    InvalidOraclePrice,
+    #[msg("Oracle-priced escrows can only be exchanged in full.")]
+    OraclePricingUnsupported,
+    #[msg("An arbiter can only be set on an open vault-mode escrow with no auction or oracle pricing.")]
+    InvalidArbiter,
+    #[msg("This escrow has an arbiter: take it with fund.")]
+    ArbitratedEscrowUnsupported,
+    #[msg("This escrow has no arbiter.")]
+    NotArbitrated,
+    #[msg("The escrow isn't in the right state for this instruction, or the accounts passed in don't match its funding.")]
+    InvalidEscrowState,
+    #[msg("Only the initializer or the taker can do this.")]
+    NotAParty,
+    #[msg("Only the escrow's arbiter can resolve a dispute.")]
+    UnauthorizedArbiter,
+    #[msg("The taker has funded this escrow, so it can only be released or resolved.")]
+    EscrowFunded,
+    #[msg("The account isn't an escrow from before EscrowAccount was versioned.")]
+    NotALegacyEscrow,
+    #[msg("The accounts passed in don't match the legacy escrow.")]
+    LegacyEscrowMismatch,
+    #[msg("exchange_many needs one set of exchange accounts, by the same taker, per pair of expected amounts.")]
+    ExchangeManyAccountMismatch,
+    #[msg("Only the program's upgrade authority can create the config.")]
+    NotUpgradeAuthority,
+    #[msg("The program or program data account passed in isn't this program's.")]
+    InvalidProgramData,
+    #[msg("The protocol fee is higher than the taker's max_fee_bps.")]
+    FeeAboveMaximum,
+    #[msg("Outside of vault mode, an escrow's initializer_amount can't be lowered: cancel and re-create it instead.")]
+    DepositDecreaseUnsupported,
+}
+
+fn validate_expiry(expires_at: Option<i64>) -> ProgramResult {
+    if let Some(expires_at) = expires_at {
+        if expires_at <= Clock::get()?.unix_timestamp {
+            return Err(ErrorCode::InvalidExpiry.into());
+        }
+    }
+    Ok(())
+}
+
+// Hands the deposit back to the initializer. Outside of vault mode, that means
+// giving them back ownership of their deposit token account. In vault mode,
+// everything in the vault is sent back to initializer_deposit_token_account and
+// the vault is closed.
+fn refund_deposit<'info>(
+    escrow_account: &Account<'info, EscrowAccount>,
+    pda_deposit_token_account: &Account<'info, TokenAccount>,
+    initializer_deposit_token_account: &Account<'info, TokenAccount>,
+    initializer: &AccountInfo<'info>,
+    pda_account: &AccountInfo<'info>,
+    token_program: &Program<'info, Token>,
+) -> ProgramResult {
+    if escrow_account.uses_vault() {
+        let escrow_key = escrow_account.to_account_info().key;
+        let seeds = &[ESCROW_PDA_SEED, escrow_key.as_ref(), &[escrow_account.bump]];
+
+        // Transfers everything in the vault from
+        // pda_deposit_token_account -> initializer_deposit_token_account.
         let cpi_accounts = Transfer {
-            from: self.taker_deposit_token_account.to_account_info().clone(),
+            from: pda_deposit_token_account.to_account_info().clone(),
+            to: initializer_deposit_token_account.to_account_info().clone(),
+            authority: pda_account.clone(),
+        };
+        token::transfer(
+            CpiContext::new(token_program.to_account_info(), cpi_accounts)
+                .with_signer(&[&seeds[..]]),
+            pda_deposit_token_account.amount,
+        )?;
+    }
+
+    close_deposit(
+        escrow_account,
+        pda_deposit_token_account,
+        initializer,
+        pda_account,
+        token_program,
+    )
+}
+
+// Once nothing of the deposit is left to trade, hands what's left of it back to
+// the initializer: outside of vault mode, ownership of their deposit token
+// account; in vault mode, the (now empty) vault's rent, as the vault is closed.
+fn close_deposit<'info>(
+    escrow_account: &Account<'info, EscrowAccount>,
+    pda_deposit_token_account: &Account<'info, TokenAccount>,
+    initializer: &AccountInfo<'info>,
+    pda_account: &AccountInfo<'info>,
+    token_program: &Program<'info, Token>,
+) -> ProgramResult {
+    let escrow_key = escrow_account.to_account_info().key;
+    let seeds = &[ESCROW_PDA_SEED, escrow_key.as_ref(), &[escrow_account.bump]];
+    let cpi_program = token_program.to_account_info();
+
+    if escrow_account.uses_vault() {
+        // Closes the (now empty) vault, sending its rent back to the initializer,
+        // who paid for it.
+        let cpi_accounts = CloseAccount {
+            account: pda_deposit_token_account.to_account_info().clone(),
+            destination: initializer.clone(),
+            authority: pda_account.clone(),
+        };
+        token::close_account(CpiContext::new(cpi_program, cpi_accounts).with_signer(&[&seeds[..]]))
+    } else {
+        // Transfers ownership of pda_deposit_token_account from
+        // pda_account -> escrow_account.initializer_key (the person who initialized the escrow).
+        let cpi_accounts = SetAuthority {
+            // The account whose authority should be changed.
+            account_or_mint: pda_deposit_token_account.to_account_info().clone(),
+            // Current authority (owner) of account_or_mint.
+            current_authority: pda_account.clone(),
+        };
+        token::set_authority(
+            CpiContext::new(cpi_program, cpi_accounts).with_signer(&[&seeds[..]]),
+            AuthorityType::AccountOwner,
+            Some(escrow_account.initializer_key),
+        )
+    }
+}
+
+// Transfers amount out of transfer.from, charging the protocol fee on it: fee
+// goes to fee_token_account and the rest to transfer.to, so the fee always
+// comes out of what the receiving side gets. signer_seeds sign for
+// transfer.authority when it's a PDA, and are empty when the taker signs.
+fn transfer_less_fee<'info>(
+    token_program: &Program<'info, Token>,
+    transfer: Transfer<'info>,
+    fee_token_account: &Account<'info, TokenAccount>,
+    signer_seeds: &[&[&[u8]]],
+    amount: u64,
+    fee: u64,
+) -> ProgramResult {
+    let cpi_program = token_program.to_account_info();
+    let fee_transfer = Transfer {
+        from: transfer.from.clone(),
+        to: fee_token_account.to_account_info(),
+        authority: transfer.authority.clone(),
+    };
+    token::transfer(
+        CpiContext::new(cpi_program.clone(), transfer).with_signer(signer_seeds),
+        amount
+            .checked_sub(fee)
+            .ok_or(ErrorCode::NumericalOverflow)?,
+    )?;
+    if fee > 0 {
+        token::transfer(
+            CpiContext::new(cpi_program, fee_transfer).with_signer(signer_seeds),
+            fee,
+        )?;
+    }
+    Ok(())
+}
+
+// Closes a v1 escrow account, sending its rent to destination. The account's
+// data is zeroed so it can't be read as an escrow again in the same
+// transaction.
+fn close_legacy_escrow<'info>(
+    legacy_escrow_account: &AccountInfo<'info>,
+    destination: &AccountInfo<'info>,
+) -> ProgramResult {
+    let lamports = legacy_escrow_account.lamports();
+    **legacy_escrow_account.try_borrow_mut_lamports()? = 0;
+    **destination.try_borrow_mut_lamports()? += lamports;
+    legacy_escrow_account.try_borrow_mut_data()?.fill(0);
+    Ok(())
+}
+
+// Anchor writes an escrow back over its old bytes without clearing the rest of
+// the account, so a field going from Some back to None would leave the end of
+// its old value behind the shorter serialization. Handlers that change an
+// escrow without closing it call this first; the escrow is then written back
+// into zeroed space.
+fn zero_escrow_data(escrow_account: &Account<EscrowAccount>) -> ProgramResult {
+    let escrow_info = escrow_account.to_account_info();
+    let mut data = escrow_info.try_borrow_mut_data()?;
+    for byte in data[8..].iter_mut() {
+        *byte = 0;
+    }
+    Ok(())
+}
+
+// Sends everything in a bid vault back to refund_token_account and closes it,
+// returning its rent to the bidder who paid for it.
+fn refund_bid<'info>(
+    escrow_account: &Account<'info, EscrowAccount>,
+    bid_vault: &Account<'info, TokenAccount>,
+    refund_token_account: &AccountInfo<'info>,
+    bidder: &AccountInfo<'info>,
+    pda_account: &AccountInfo<'info>,
+    token_program: &Program<'info, Token>,
+) -> ProgramResult {
+    let escrow_key = escrow_account.to_account_info().key;
+    let seeds = &[ESCROW_PDA_SEED, escrow_key.as_ref(), &[escrow_account.bump]];
+    let cpi_program = token_program.to_account_info();
+
+    // Transfers everything in the vault (not just the bid, so a dust transfer
+    // can't block the close) from bid_vault -> refund_token_account.
+    let cpi_accounts = Transfer {
+        from: bid_vault.to_account_info().clone(),
+        to: refund_token_account.clone(),
+        authority: pda_account.clone(),
+    };
+    token::transfer(
+        CpiContext::new(cpi_program.clone(), cpi_accounts).with_signer(&[&seeds[..]]),
+        bid_vault.amount,
+    )?;
+
+    let cpi_accounts = CloseAccount {
+        account: bid_vault.to_account_info().clone(),
+        destination: bidder.clone(),
+        authority: pda_account.clone(),
+    };
+    token::close_account(CpiContext::new(cpi_program, cpi_accounts).with_signer(&[&seeds[..]]))
+}
+
+// Where one side of a settlement is paid: the token account it named, if that's
+// still an unfrozen account of the right mint, or else the associated token
+// account that create_context would create, created here if need be. Either
+// side could otherwise keep the settlement (and the other side's tokens) locked
+// up by closing its account.
+fn payout_account<'info>(
+    token_account: &AccountInfo<'info>,
+    create_context: CpiContext<'_, '_, '_, 'info, Create<'info>>,
+) -> Result<AccountInfo<'info>, ProgramError> {
+    let mint = *create_context.accounts.mint.key;
+    let usable = Account::<TokenAccount>::try_from(token_account).map_or(false, |account| {
+        account.mint == mint && !account.is_frozen()
+    });
+    if usable {
+        return Ok(token_account.clone());
+    }
+    let associated_token_account = create_context.accounts.associated_token.clone();
+    if associated_token_account.data_is_empty() {
+        associated_token::create(create_context)?;
+    }
+    Ok(associated_token_account)
+}
+
+impl<'info> From<&mut InitializeEscrow<'info>>
+    for CpiContext<'_, '_, '_, 'info, SetAuthority<'info>>
+{
+    fn from(accounts: &mut InitializeEscrow<'info>) -> Self {
+        let cpi_accounts = SetAuthority {
+            // The account whose authority should be changed.
+            account_or_mint: accounts
+                .initializer_deposit_token_account
+                .to_account_info()
+                .clone(),
+            // Current authority (owner) of account_or_mint.
+            current_authority: accounts.initializer.clone(),
+        };
+        let cpi_program = accounts.token_program.to_account_info();
+        CpiContext::new(cpi_program, cpi_accounts)
+    }
+}
+
+impl<'info> ExchangeLegacyEscrow<'info> {
+    // Reads the escrow, checking that it's a v1 escrow for these accounts.
+    fn legacy_escrow(&self, program_id: &Pubkey) -> Result<EscrowAccountV1, ProgramError> {
+        let legacy =
+            EscrowAccountV1::try_from_account_info(&self.legacy_escrow_account, program_id)?;
+        if legacy.initializer_key != *self.initializer_main_account.key
+            || legacy.initializer_deposit_token_account
+                != *self.pda_deposit_token_account.to_account_info().key
+            || legacy.initializer_receive_token_account
+                != *self.initializer_receive_token_account.to_account_info().key
+        {
+            return Err(ErrorCode::LegacyEscrowMismatch.into());
+        }
+        Ok(legacy)
+    }
+
+    fn into_set_authority_context(&self) -> CpiContext<'_, '_, '_, 'info, SetAuthority<'info>> {
+        let cpi_accounts = SetAuthority {
+            account_or_mint: self.pda_deposit_token_account.to_account_info().clone(),
+            current_authority: self.legacy_pda_account.clone(),
+        };
+        let cpi_program = self.token_program.to_account_info();
+        CpiContext::new(cpi_program, cpi_accounts)
+    }
+}
+
+impl<'info> CancelLegacyEscrow<'info> {
+    fn into_set_authority_context(&self) -> CpiContext<'_, '_, '_, 'info, SetAuthority<'info>> {
+        let cpi_accounts = SetAuthority {
+            account_or_mint: self.pda_deposit_token_account.to_account_info().clone(),
+            current_authority: self.legacy_pda_account.clone(),
+        };
+        let cpi_program = self.token_program.to_account_info();
+        CpiContext::new(cpi_program, cpi_accounts)
+    }
+}
+
+impl<'info> InitializeEscrowWithVault<'info> {
+    fn into_transfer_to_vault_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self
+                .initializer_deposit_token_account
+                .to_account_info()
+                .clone(),
+            to: self.vault.to_account_info().clone(),
+            authority: self.initializer.clone(),
+        };
+        let cpi_program = self.token_program.to_account_info();
+        CpiContext::new(cpi_program, cpi_accounts)
+    }
+}
+
+impl<'info> UpdateEscrow<'info> {
+    fn into_top_up_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self
+                .initializer_deposit_token_account
+                .to_account_info()
+                .clone(),
+            to: self.pda_deposit_token_account.to_account_info().clone(),
+            authority: self.initializer.clone(),
+        };
+        let cpi_program = self.token_program.to_account_info();
+        CpiContext::new(cpi_program, cpi_accounts)
+    }
+
+    fn into_withdraw_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.pda_deposit_token_account.to_account_info().clone(),
             to: self
-                .initializer_receive_token_account
+                .initializer_deposit_token_account
+                .to_account_info()
+                .clone(),
+            authority: self.pda_account.clone(),
+        };
+        let cpi_program = self.token_program.to_account_info();
+        CpiContext::new(cpi_program, cpi_accounts)
+    }
+}
+
+impl<'info> InitializeEscrowRequestingSol<'info> {
+    fn into_transfer_to_vault_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self
+                .initializer_deposit_token_account
                 .to_account_info()
                 .clone(),
+            to: self.vault.to_account_info().clone(),
+            authority: self.initializer.clone(),
+        };
+        let cpi_program = self.token_program.to_account_info();
+        CpiContext::new(cpi_program, cpi_accounts)
+    }
+}
+
+impl<'info> Exchange<'info> {
+    // Exchanges everything left in the escrow, as long as it still matches the
+    // amounts the taker expects. For a Dutch auction or an oracle-priced escrow,
+    // expected_taker_amount is the most the taker is willing to pay, since the
+    // price can move until the exchange lands. remaining_accounts holds the
+    // oracle's price account (for an oracle-priced escrow), then the NFT's
+    // accounts (for an NFT escrow).
+    fn exchange_in_full(
+        &mut self,
+        expected_initializer_amount: u64,
+        expected_taker_amount: u64,
+        max_fee_bps: u16,
+        remaining_accounts: &[AccountInfo<'info>],
+    ) -> ProgramResult {
+        let now = Clock::get()?.unix_timestamp;
+        let escrow_account = &mut self.escrow_account;
+        let mut taker_amount = escrow_account.current_taker_amount(now)?;
+        let mut nft_accounts = remaining_accounts;
+        if let Some(oracle) = &escrow_account.oracle {
+            let price_account = nft_accounts.get(0).ok_or(ErrorCode::InvalidOracleAccount)?;
+            taker_amount =
+                oracle.taker_amount(price_account, escrow_account.initializer_amount, now)?;
+            nft_accounts = &nft_accounts[1..];
+        }
+        let price_matches = if escrow_account.auction.is_some() || escrow_account.oracle.is_some() {
+            taker_amount <= expected_taker_amount
+        } else {
+            taker_amount == expected_taker_amount
+        };
+        if escrow_account.initializer_amount != expected_initializer_amount || !price_matches {
+            return Err(ErrorCode::TermsMismatch.into());
+        }
+        escrow_account.verify_nft(nft_accounts)?;
+
+        // A full exchange is just a fill of everything that's left, at the
+        // current price.
+        escrow_account.taker_amount = taker_amount;
+        self.fill_accounts().fill(taker_amount, max_fee_bps)
+    }
+
+    fn fill_accounts(&mut self) -> Fill<'_, 'info> {
+        Fill {
+            taker: &self.taker,
+            taker_deposit_token_account: &self.taker_deposit_token_account,
+            taker_receive_token_account: &self.taker_receive_token_account,
+            pda_deposit_token_account: &self.pda_deposit_token_account,
+            initializer_receive_token_account: &self.initializer_receive_token_account,
+            initializer_main_account: &self.initializer_main_account,
+            escrow_account: &mut self.escrow_account,
+            pda_account: &self.pda_account,
+            order_book: &mut self.order_book,
+            config: &self.config,
+            offered_fee_token_account: &self.offered_fee_token_account,
+            requested_fee_token_account: &self.requested_fee_token_account,
+            token_program: &self.token_program,
+        }
+    }
+}
+
+impl<'info> ExchangePartial<'info> {
+    fn fill_accounts(&mut self) -> Fill<'_, 'info> {
+        Fill {
+            taker: &self.taker,
+            taker_deposit_token_account: &self.taker_deposit_token_account,
+            taker_receive_token_account: &self.taker_receive_token_account,
+            pda_deposit_token_account: &self.pda_deposit_token_account,
+            initializer_receive_token_account: &self.initializer_receive_token_account,
+            initializer_main_account: &self.initializer_main_account,
+            escrow_account: &mut self.escrow_account,
+            pda_account: &self.pda_account,
+            order_book: &mut self.order_book,
+            config: &self.config,
+            offered_fee_token_account: &self.offered_fee_token_account,
+            requested_fee_token_account: &self.requested_fee_token_account,
+            token_program: &self.token_program,
+        }
+    }
+}
+
+// The accounts of an Exchange or an ExchangePartial, borrowed from either so
+// that both fill the escrow through the same code.
+struct Fill<'a, 'info> {
+    taker: &'a AccountInfo<'info>,
+    taker_deposit_token_account: &'a Account<'info, TokenAccount>,
+    taker_receive_token_account: &'a Account<'info, TokenAccount>,
+    pda_deposit_token_account: &'a Account<'info, TokenAccount>,
+    initializer_receive_token_account: &'a Account<'info, TokenAccount>,
+    initializer_main_account: &'a AccountInfo<'info>,
+    escrow_account: &'a mut Account<'info, EscrowAccount>,
+    pda_account: &'a AccountInfo<'info>,
+    order_book: &'a mut Account<'info, OrderBook>,
+    config: &'a Account<'info, EscrowConfig>,
+    offered_fee_token_account: &'a Account<'info, TokenAccount>,
+    requested_fee_token_account: &'a Account<'info, TokenAccount>,
+    token_program: &'a Program<'info, Token>,
+}
+
+impl<'a, 'info> Fill<'a, 'info> {
+    // Exchanges taker_amount of the outstanding taker_amount for the matching
+    // share of the deposit. Once nothing is left, the deposit is handed back and
+    // the escrow is taken off the order book; the escrow account itself is closed
+    // by Exchange's `close` constraint. Closing it here instead would be undone
+    // when the account is written back on exit.
+    fn fill(mut self, taker_amount: u64, max_fee_bps: u16) -> ProgramResult {
+        self.config.check_max_fee(max_fee_bps)?;
+        if self.escrow_account.english_auction.is_some() {
+            return Err(ErrorCode::EnglishAuctionUnsupported.into());
+        }
+        if self.escrow_account.arbiter.is_some() {
+            return Err(ErrorCode::ArbitratedEscrowUnsupported.into());
+        }
+        if self.escrow_account.is_expired(Clock::get()?.unix_timestamp) {
+            return Err(ErrorCode::EscrowExpired.into());
+        }
+        let fully_filled = taker_amount == self.escrow_account.taker_amount;
+        if !fully_filled && (taker_amount == 0 || taker_amount > self.escrow_account.taker_amount) {
+            return Err(ErrorCode::InvalidFillAmount.into());
+        }
+        if self.taker_deposit_token_account.amount < taker_amount {
+            return Err(ErrorCode::InsufficientTakerFunds.into());
+        }
+
+        // The last fill takes whatever is left. Otherwise the share is rounded
+        // down, in the initializer's favour. Because the share is computed from
+        // the remaining amounts, rounding never compounds across fills: the
+        // remaining price only ever moves in the initializer's favour.
+        let initializer_amount = if fully_filled {
+            self.escrow_account.initializer_amount
+        } else {
+            (taker_amount as u128 * self.escrow_account.initializer_amount as u128
+                / self.escrow_account.taker_amount as u128) as u64
+        };
+        if !fully_filled && initializer_amount == 0 {
+            return Err(ErrorCode::InvalidFillAmount.into());
+        }
+
+        // Transferring from initializer to taker
+        let escrow_key = *self.escrow_account.to_account_info().key;
+        let seeds = &[
+            ESCROW_PDA_SEED,
+            escrow_key.as_ref(),
+            &[self.escrow_account.bump],
+        ];
+
+        // Transfers initializer_amount tokens (less the fee) from
+        // pda_deposit_token_account -> taker_receive_token_account.
+        //
+        // In vault mode the vault is closed after the last fill, which requires it
+        // to be empty, so anything sent to the vault on top of the deposit goes to
+        // the taker as well (otherwise anyone could block the exchange with a dust transfer).
+        let amount = if fully_filled && self.escrow_account.uses_vault() {
+            self.pda_deposit_token_account.amount
+        } else {
+            initializer_amount
+        };
+
+        // The protocol fee on each leg comes out of what the other side receives.
+        let offered_fee = self.config.offered_leg_fee(initializer_amount)?;
+        let requested_fee = self.config.requested_leg_fee(taker_amount)?;
+
+        transfer_less_fee(
+            self.token_program,
+            Transfer {
+                from: self.pda_deposit_token_account.to_account_info(),
+                to: self.taker_receive_token_account.to_account_info(),
+                authority: self.pda_account.clone(),
+            },
+            self.offered_fee_token_account,
+            &[&seeds[..]],
+            amount,
+            offered_fee,
+        )?;
+
+        // Transfers taker_amount tokens (less the fee) from
+        // taker_deposit_token_account -> initializer_receive_token_account.
+        transfer_less_fee(
+            self.token_program,
+            Transfer {
+                from: self.taker_deposit_token_account.to_account_info(),
+                to: self.initializer_receive_token_account.to_account_info(),
+                authority: self.taker.clone(),
+            },
+            self.requested_fee_token_account,
+            &[],
+            taker_amount,
+            requested_fee,
+        )?;
+
+        self.escrow_account.initializer_amount -= initializer_amount;
+        self.escrow_account.taker_amount -= taker_amount;
+
+        let mut event = self.escrow_account.exchanged_event(
+            escrow_key,
+            *self.taker.key,
+            initializer_amount,
+            taker_amount,
+            Clock::get()?.slot,
+        );
+        event.offered_fee = offered_fee;
+        event.requested_fee = requested_fee;
+        emit!(event);
+
+        if !fully_filled {
+            return Ok(());
+        }
+
+        close_deposit(
+            self.escrow_account,
+            self.pda_deposit_token_account,
+            self.initializer_main_account,
+            self.pda_account,
+            self.token_program,
+        )?;
+
+        // Nothing left to trade, so take the escrow off the order book.
+        self.order_book.remove(&escrow_key);
+        Ok(())
+    }
+}
+
+impl<'info> PlaceBid<'info> {
+    fn into_transfer_to_bid_vault_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.bidder_deposit_token_account.to_account_info().clone(),
+            to: self.bid_vault.to_account_info().clone(),
+            authority: self.bidder.clone(),
+        };
+        let cpi_program = self.token_program.to_account_info();
+        CpiContext::new(cpi_program, cpi_accounts)
+    }
+
+    fn into_create_associated_token_account_context(
+        &self,
+        associated_token_account: &AccountInfo<'info>,
+        authority: &AccountInfo<'info>,
+    ) -> CpiContext<'_, '_, '_, 'info, Create<'info>> {
+        let cpi_accounts = Create {
+            payer: self.bidder.clone(),
+            associated_token: associated_token_account.clone(),
+            authority: authority.clone(),
+            mint: self.mint.to_account_info(),
+            system_program: self.system_program.to_account_info(),
+            token_program: self.token_program.to_account_info(),
+            rent: self.rent.to_account_info(),
+        };
+        let cpi_program = self.associated_token_program.to_account_info();
+        CpiContext::new(cpi_program, cpi_accounts)
+    }
+}
+
+impl<'info> Settle<'info> {
+    // Like Exchange::fill for a full fill, except the taker's side is paid from
+    // the bid vault by the escrow's PDA.
+    fn settle(&mut self, bid: &Bid) -> ProgramResult {
+        let escrow_key = *self.escrow_account.to_account_info().key;
+        let seeds = &[
+            ESCROW_PDA_SEED,
+            escrow_key.as_ref(),
+            &[self.escrow_account.bump],
+        ];
+        // The admin may have raised fees since the bid was placed.
+        self.config.check_max_fee(bid.max_fee_bps)?;
+        let initializer_amount = self.escrow_account.initializer_amount;
+        let offered_fee = self.config.offered_leg_fee(initializer_amount)?;
+        let requested_fee = self.config.requested_leg_fee(bid.amount)?;
+        let winner_receive_token_account = payout_account(
+            &self.taker_receive_token_account,
+            self.into_create_associated_token_account_context(
+                &self.winner_associated_token_account,
+                &self.winner,
+                &self.offered_mint,
+            ),
+        )?;
+        let initializer_receive_token_account = payout_account(
+            &self.initializer_receive_token_account,
+            self.into_create_associated_token_account_context(
+                &self.initializer_associated_token_account,
+                &self.initializer_main_account,
+                &self.requested_mint,
+            ),
+        )?;
+
+        // Transfers the deposit (less the fee) from
+        // pda_deposit_token_account -> winner_receive_token_account. As in
+        // Exchange::fill, a vault is emptied entirely so it can be closed.
+        let amount = if self.escrow_account.uses_vault() {
+            self.pda_deposit_token_account.amount
+        } else {
+            initializer_amount
+        };
+        transfer_less_fee(
+            &self.token_program,
+            Transfer {
+                from: self.pda_deposit_token_account.to_account_info(),
+                to: winner_receive_token_account,
+                authority: self.pda_account.clone(),
+            },
+            &self.offered_fee_token_account,
+            &[&seeds[..]],
+            amount,
+            offered_fee,
+        )?;
+
+        // Transfers the bid (less the fee) from
+        // bid_vault -> initializer_receive_token_account. Anything sent to the
+        // vault on top of the bid goes to the initializer too.
+        transfer_less_fee(
+            &self.token_program,
+            Transfer {
+                from: self.bid_vault.to_account_info(),
+                to: initializer_receive_token_account,
+                authority: self.pda_account.clone(),
+            },
+            &self.requested_fee_token_account,
+            &[&seeds[..]],
+            self.bid_vault.amount,
+            requested_fee,
+        )?;
+        // Closes the (now empty) bid vault, sending its rent to the winner.
+        token::close_account(
+            self.into_close_bid_vault_context()
+                .with_signer(&[&seeds[..]]),
+        )?;
+
+        self.escrow_account.initializer_amount = 0;
+        self.escrow_account.taker_amount = 0;
+
+        let mut event = self.escrow_account.exchanged_event(
+            escrow_key,
+            bid.bidder,
+            initializer_amount,
+            bid.amount,
+            Clock::get()?.slot,
+        );
+        event.offered_fee = offered_fee;
+        event.requested_fee = requested_fee;
+        emit!(event);
+
+        close_deposit(
+            &self.escrow_account,
+            &self.pda_deposit_token_account,
+            &self.initializer_main_account,
+            &self.pda_account,
+            &self.token_program,
+        )?;
+
+        self.order_book.remove(&escrow_key);
+        Ok(())
+    }
+
+    fn into_create_associated_token_account_context(
+        &self,
+        associated_token_account: &AccountInfo<'info>,
+        authority: &AccountInfo<'info>,
+        mint: &AccountInfo<'info>,
+    ) -> CpiContext<'_, '_, '_, 'info, Create<'info>> {
+        let cpi_accounts = Create {
+            payer: self.payer.clone(),
+            associated_token: associated_token_account.clone(),
+            authority: authority.clone(),
+            mint: mint.clone(),
+            system_program: self.system_program.to_account_info(),
+            token_program: self.token_program.to_account_info(),
+            rent: self.rent.to_account_info(),
+        };
+        let cpi_program = self.associated_token_program.to_account_info();
+        CpiContext::new(cpi_program, cpi_accounts)
+    }
+
+    fn into_close_bid_vault_context(&self) -> CpiContext<'_, '_, '_, 'info, CloseAccount<'info>> {
+        let cpi_accounts = CloseAccount {
+            account: self.bid_vault.to_account_info().clone(),
+            destination: self.winner.clone(),
+            authority: self.pda_account.clone(),
+        };
+        let cpi_program = self.token_program.to_account_info();
+        CpiContext::new(cpi_program, cpi_accounts)
+    }
+}
+
+impl<'info> Fund<'info> {
+    fn into_transfer_to_taker_vault_context(
+        &self,
+    ) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.taker_deposit_token_account.to_account_info().clone(),
+            to: self.taker_vault.to_account_info().clone(),
             authority: self.taker.clone(),
         };
         let cpi_program = self.token_program.to_account_info();
         CpiContext::new(cpi_program, cpi_accounts)
     }
 }
+
+impl<'info> SettleArbitration<'info> {
+    // Sends to_taker of the deposit to the taker and to_initializer of the
+    // payment to the initializer (each less the protocol fee), returns the rest
+    // of both to whoever put them in, and closes both vaults and the escrow.
+    fn settle(&mut self, to_taker: u64, to_initializer: u64) -> ProgramResult {
+        let escrow_key = *self.escrow_account.to_account_info().key;
+        let seeds = &[
+            ESCROW_PDA_SEED,
+            escrow_key.as_ref(),
+            &[self.escrow_account.bump],
+        ];
+        // The admin may have raised fees since the escrow was funded.
+        let funding = self
+            .escrow_account
+            .funding
+            .ok_or(ErrorCode::InvalidEscrowState)?;
+        self.config.check_max_fee(funding.max_fee_bps)?;
+        let offered_fee = self.config.offered_leg_fee(to_taker)?;
+        let requested_fee = self.config.requested_leg_fee(to_initializer)?;
+
+        if to_taker > 0 {
+            // Transfers to_taker tokens (less the fee) from
+            // pda_deposit_token_account -> taker_receive_token_account.
+            let taker_receive_token_account = payout_account(
+                &self.taker_receive_token_account,
+                self.into_create_associated_token_account_context(
+                    &self.taker_associated_receive_token_account,
+                    &self.taker,
+                    &self.offered_mint,
+                ),
+            )?;
+            transfer_less_fee(
+                &self.token_program,
+                Transfer {
+                    from: self.pda_deposit_token_account.to_account_info(),
+                    to: taker_receive_token_account,
+                    authority: self.pda_account.clone(),
+                },
+                &self.offered_fee_token_account,
+                &[&seeds[..]],
+                to_taker,
+                offered_fee,
+            )?;
+        }
+        // Everything else in the vault goes back to the initializer, so the vault
+        // can be closed.
+        let refund = self
+            .pda_deposit_token_account
+            .amount
+            .checked_sub(to_taker)
+            .ok_or(ErrorCode::NumericalOverflow)?;
+        if refund > 0 {
+            let initializer_deposit_token_account = payout_account(
+                &self.initializer_deposit_token_account,
+                self.into_create_associated_token_account_context(
+                    &self.initializer_associated_deposit_token_account,
+                    &self.initializer_main_account,
+                    &self.offered_mint,
+                ),
+            )?;
+            token::transfer(
+                self.transfer_context(
+                    &self.pda_deposit_token_account,
+                    initializer_deposit_token_account,
+                )
+                .with_signer(&[&seeds[..]]),
+                refund,
+            )?;
+        }
+
+        if to_initializer > 0 {
+            // Transfers to_initializer tokens (less the fee) from
+            // taker_vault -> initializer_receive_token_account.
+            let initializer_receive_token_account = payout_account(
+                &self.initializer_receive_token_account,
+                self.into_create_associated_token_account_context(
+                    &self.initializer_associated_receive_token_account,
+                    &self.initializer_main_account,
+                    &self.requested_mint,
+                ),
+            )?;
+            transfer_less_fee(
+                &self.token_program,
+                Transfer {
+                    from: self.taker_vault.to_account_info(),
+                    to: initializer_receive_token_account,
+                    authority: self.pda_account.clone(),
+                },
+                &self.requested_fee_token_account,
+                &[&seeds[..]],
+                to_initializer,
+                requested_fee,
+            )?;
+        }
+        // And everything else in the taker vault goes back to the taker.
+        let refund = self
+            .taker_vault
+            .amount
+            .checked_sub(to_initializer)
+            .ok_or(ErrorCode::NumericalOverflow)?;
+        if refund > 0 {
+            let taker_deposit_token_account = payout_account(
+                &self.taker_deposit_token_account,
+                self.into_create_associated_token_account_context(
+                    &self.taker_associated_deposit_token_account,
+                    &self.taker,
+                    &self.requested_mint,
+                ),
+            )?;
+            token::transfer(
+                self.transfer_context(&self.taker_vault, taker_deposit_token_account)
+                    .with_signer(&[&seeds[..]]),
+                refund,
+            )?;
+        }
+
+        // Closes both (now empty) vaults, sending their rent back to whoever paid
+        // for them.
+        token::close_account(
+            self.close_context(
+                &self.pda_deposit_token_account,
+                &self.initializer_main_account,
+            )
+            .with_signer(&[&seeds[..]]),
+        )?;
+        token::close_account(
+            self.close_context(&self.taker_vault, &self.taker)
+                .with_signer(&[&seeds[..]]),
+        )?;
+
+        let taker = *self.taker.key;
+        self.escrow_account.initializer_amount = 0;
+        self.escrow_account.taker_amount = 0;
+        let mut event = self.escrow_account.exchanged_event(
+            escrow_key,
+            taker,
+            to_taker,
+            to_initializer,
+            Clock::get()?.slot,
+        );
+        event.offered_fee = offered_fee;
+        event.requested_fee = requested_fee;
+        emit!(event);
+        Ok(())
+    }
+
+    fn transfer_context(
+        &self,
+        from: &Account<'info, TokenAccount>,
+        to: AccountInfo<'info>,
+    ) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: from.to_account_info().clone(),
+            to,
+            authority: self.pda_account.clone(),
+        };
+        let cpi_program = self.token_program.to_account_info();
+        CpiContext::new(cpi_program, cpi_accounts)
+    }
+
+    fn into_create_associated_token_account_context(
+        &self,
+        associated_token_account: &AccountInfo<'info>,
+        authority: &AccountInfo<'info>,
+        mint: &AccountInfo<'info>,
+    ) -> CpiContext<'_, '_, '_, 'info, Create<'info>> {
+        let cpi_accounts = Create {
+            payer: self.payer.clone(),
+            associated_token: associated_token_account.clone(),
+            authority: authority.clone(),
+            mint: mint.clone(),
+            system_program: self.system_program.to_account_info(),
+            token_program: self.token_program.to_account_info(),
+            rent: self.rent.to_account_info(),
+        };
+        let cpi_program = self.associated_token_program.to_account_info();
+        CpiContext::new(cpi_program, cpi_accounts)
+    }
+
+    fn close_context(
+        &self,
+        account: &Account<'info, TokenAccount>,
+        destination: &AccountInfo<'info>,
+    ) -> CpiContext<'_, '_, '_, 'info, CloseAccount<'info>> {
+        let cpi_accounts = CloseAccount {
+            account: account.to_account_info().clone(),
+            destination: destination.clone(),
+            authority: self.pda_account.clone(),
+        };
+        let cpi_program = self.token_program.to_account_info();
+        CpiContext::new(cpi_program, cpi_accounts)
+    }
+}
@@ -0,0 +1,86 @@
+//! Just enough of the Metaplex token metadata format to check which collection
+//! an NFT belongs to, without depending on the metadata program's crate.
+
+use crate::ErrorCode;
+use anchor_lang::prelude::*;
+
+pub mod metadata_program {
+    anchor_lang::solana_program::declare_id!("metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s");
+}
+
+// The metadata PDA for a mint is derived from [METADATA_SEED, metadata program, mint].
+pub const METADATA_SEED: &[u8] = b"metadata";
+
+// The Key the metadata program tags metadata accounts with (Key::MetadataV1).
+const METADATA_V1_KEY: u8 = 4;
+
+#[derive(AnchorDeserialize)]
+#[allow(dead_code)]
+struct Creator {
+    address: Pubkey,
+    verified: bool,
+    share: u8,
+}
+
+#[derive(AnchorDeserialize)]
+#[allow(dead_code)]
+struct Data {
+    name: String,
+    symbol: String,
+    uri: String,
+    seller_fee_basis_points: u16,
+    creators: Option<Vec<Creator>>,
+}
+
+#[derive(AnchorDeserialize)]
+struct Collection {
+    verified: bool,
+    key: Pubkey,
+}
+
+// The leading fields of a metadata account, up to the collection. Accounts
+// written before collections existed are zero-padded, so they read as None.
+#[derive(AnchorDeserialize)]
+#[allow(dead_code)]
+struct Metadata {
+    key: u8,
+    update_authority: Pubkey,
+    mint: Pubkey,
+    data: Data,
+    primary_sale_happened: bool,
+    is_mutable: bool,
+    edition_nonce: Option<u8>,
+    token_standard: Option<u8>,
+    collection: Option<Collection>,
+}
+
+// Checks that metadata is mint's metadata account, and that it lists mint as a
+// verified member of collection.
+pub fn verify_collection(
+    metadata: &AccountInfo,
+    mint: &Pubkey,
+    collection: &Pubkey,
+) -> ProgramResult {
+    let (expected_key, _) = Pubkey::find_program_address(
+        &[METADATA_SEED, metadata_program::ID.as_ref(), mint.as_ref()],
+        &metadata_program::ID,
+    );
+    if *metadata.key != expected_key || *metadata.owner != metadata_program::ID {
+        return Err(ErrorCode::InvalidNftMetadata.into());
+    }
+
+    let data = metadata.try_borrow_data()?;
+    let parsed = Metadata::deserialize(&mut &data[..])
+        .map_err(|_| ProgramError::from(ErrorCode::InvalidNftMetadata))?;
+    if parsed.key != METADATA_V1_KEY || parsed.mint != *mint {
+        return Err(ErrorCode::InvalidNftMetadata.into());
+    }
+
+    match parsed.collection {
+        Some(Collection {
+            verified: true,
+            key,
+        }) if key == *collection => Ok(()),
+        _ => Err(ErrorCode::CollectionNotVerified.into()),
+    }
+}
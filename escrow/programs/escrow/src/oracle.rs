@@ -0,0 +1,37 @@
+//! Just enough of an oracle price account to price an escrow (see OraclePricing).
+//! Price accounts are read in the layout of the workspace's mock-oracle
+//! PriceFeed, parsed here rather than by depending on that crate, so an escrow
+//! can point at any oracle program that publishes the same layout.
+
+use crate::ErrorCode;
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::hash::hash;
+
+// The price of one base unit of the offered mint is price * 10^expo base units
+// of the requested mint, as of publish_time.
+#[derive(AnchorDeserialize)]
+pub struct PriceFeed {
+    pub authority: Pubkey,
+    pub price: u64,
+    pub expo: i32,
+    pub publish_time: i64,
+}
+
+// Reads the PriceFeed in price_account, which must be owned by oracle_program.
+pub fn read_price_feed(
+    price_account: &AccountInfo,
+    oracle_program: &Pubkey,
+) -> Result<PriceFeed, ProgramError> {
+    if price_account.owner != oracle_program {
+        return Err(ErrorCode::InvalidOracleAccount.into());
+    }
+
+    // Anchor prefixes accounts with the first 8 bytes of sha256("account:<name>").
+    let data = price_account.try_borrow_data()?;
+    let discriminator = &hash(b"account:PriceFeed").to_bytes()[..8];
+    if data.len() < 8 || &data[..8] != discriminator {
+        return Err(ErrorCode::InvalidOracleAccount.into());
+    }
+    PriceFeed::deserialize(&mut &data[8..])
+        .map_err(|_| ProgramError::from(ErrorCode::InvalidOracleAccount))
+}
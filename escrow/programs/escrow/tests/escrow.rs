@@ -0,0 +1,1918 @@
+//! Runs the escrow program in solana-program-test's BanksClient. Run with
+//! `cargo test-bpf`, which builds the program and enables the test-bpf feature.
+#![cfg(feature = "test-bpf")]
+
+use anchor_lang::__private::{ErrorCode as AnchorErrorCode, CLOSED_ACCOUNT_DISCRIMINATOR};
+use anchor_lang::{
+    AccountDeserialize, AnchorSerialize, Discriminator, InstructionData, ToAccountMetas,
+};
+use escrow::{
+    accounts, client, instruction, ErrorCode, EscrowAccount, EscrowAccountV1, EscrowState,
+    NativeLeg, OrderBook, ESCROW_ACCOUNT_VERSION, MAX_FEE_BPS, MAX_ORDER_BOOK_ENTRIES,
+};
+use solana_program_test::{find_file, read_file, ProgramTest, ProgramTestContext};
+use solana_sdk::{
+    account::Account,
+    bpf_loader_upgradeable::{self, UpgradeableLoaderState},
+    instruction::{AccountMeta, Instruction, InstructionError},
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+    rent::Rent,
+    signature::{Keypair, Signer},
+    system_instruction, system_program,
+    transaction::{Transaction, TransactionError},
+    transport::TransportError,
+};
+
+const INITIALIZER_AMOUNT: u64 = 500;
+const TAKER_AMOUNT: u64 = 1000;
+
+// Two mints, funded token accounts for both sides, the config and the order
+// book for the pair, and an open escrow offering INITIALIZER_AMOUNT of mint A
+// for TAKER_AMOUNT of mint B.
+struct Setup {
+    context: ProgramTestContext,
+    // The program's upgrade authority, and so the config's admin.
+    admin: Keypair,
+    mint_a: Pubkey,
+    mint_b: Pubkey,
+    initializer: Keypair,
+    initializer_token_account_a: Pubkey,
+    initializer_token_account_b: Pubkey,
+    taker: Keypair,
+    taker_token_account_a: Pubkey,
+    taker_token_account_b: Pubkey,
+    fee_token_account_a: Pubkey,
+    fee_token_account_b: Pubkey,
+    escrow: Keypair,
+}
+
+async fn setup() -> Setup {
+    let admin = Keypair::new();
+    let mut context = program_test(&admin.pubkey()).start_with_context().await;
+
+    let initializer = Keypair::new();
+    let taker = Keypair::new();
+    let fee_recipient = Keypair::new();
+    let payer = context.payer.pubkey();
+    process(
+        &mut context,
+        &[system_instruction::transfer(
+            &payer,
+            &initializer.pubkey(),
+            1_000_000_000,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    let mint_a = create_mint(&mut context).await;
+    let mint_b = create_mint(&mut context).await;
+    let initializer_token_account_a =
+        create_token_account(&mut context, &mint_a, &initializer.pubkey()).await;
+    let initializer_token_account_b =
+        create_token_account(&mut context, &mint_b, &initializer.pubkey()).await;
+    let taker_token_account_a = create_token_account(&mut context, &mint_a, &taker.pubkey()).await;
+    let taker_token_account_b = create_token_account(&mut context, &mint_b, &taker.pubkey()).await;
+    let fee_token_account_a =
+        create_token_account(&mut context, &mint_a, &fee_recipient.pubkey()).await;
+    let fee_token_account_b =
+        create_token_account(&mut context, &mint_b, &fee_recipient.pubkey()).await;
+    mint_to(
+        &mut context,
+        &mint_a,
+        &initializer_token_account_a,
+        INITIALIZER_AMOUNT,
+    )
+    .await;
+    mint_to(&mut context, &mint_b, &taker_token_account_b, TAKER_AMOUNT).await;
+
+    process(
+        &mut context,
+        &[
+            client::initialize_config_ix(&admin.pubkey(), 0, 0, &fee_recipient.pubkey()),
+            client::initialize_order_book_ix(&payer, &mint_a, &mint_b),
+        ],
+        &[&admin],
+    )
+    .await
+    .unwrap();
+
+    let escrow = Keypair::new();
+    process(
+        &mut context,
+        &[client::initialize_escrow_ix(
+            &initializer.pubkey(),
+            &initializer_token_account_a,
+            &initializer_token_account_b,
+            &mint_a,
+            &mint_b,
+            &escrow.pubkey(),
+            INITIALIZER_AMOUNT,
+            TAKER_AMOUNT,
+            None,
+            None,
+            None,
+        )],
+        &[&initializer, &escrow],
+    )
+    .await
+    .unwrap();
+
+    Setup {
+        context,
+        admin,
+        mint_a,
+        mint_b,
+        initializer,
+        initializer_token_account_a,
+        initializer_token_account_b,
+        taker,
+        taker_token_account_a,
+        taker_token_account_b,
+        fee_token_account_a,
+        fee_token_account_b,
+        escrow,
+    }
+}
+
+impl Setup {
+    async fn escrow_account(&mut self) -> EscrowAccount {
+        let account = self
+            .context
+            .banks_client
+            .get_account(self.escrow.pubkey())
+            .await
+            .unwrap()
+            .expect("escrow account should exist");
+        EscrowAccount::try_deserialize(&mut account.data.as_slice()).unwrap()
+    }
+
+    // The accounts for a valid exchange of the escrow by the taker. Tests
+    // replace one of them to check the matching constraint.
+    async fn exchange_accounts(&mut self) -> accounts::Exchange {
+        let escrow_account = self.escrow_account().await;
+        accounts::Exchange {
+            taker: self.taker.pubkey(),
+            taker_deposit_token_account: self.taker_token_account_b,
+            taker_receive_token_account: self.taker_token_account_a,
+            pda_deposit_token_account: escrow_account.vault,
+            initializer_receive_token_account: escrow_account.initializer_receive_token_account,
+            initializer_main_account: escrow_account.initializer_key,
+            escrow_account: self.escrow.pubkey(),
+            pda_account: client::escrow_pda(&self.escrow.pubkey()).0,
+            order_book: client::order_book_address(&self.mint_a, &self.mint_b).0,
+            config: client::config_address().0,
+            offered_fee_token_account: self.fee_token_account_a,
+            requested_fee_token_account: self.fee_token_account_b,
+            token_program: spl_token::ID,
+        }
+    }
+
+    async fn exchange(&mut self, accounts: accounts::Exchange) -> Result<(), TransportError> {
+        let data = instruction::Exchange {
+            expected_initializer_amount: INITIALIZER_AMOUNT,
+            expected_taker_amount: TAKER_AMOUNT,
+            max_fee_bps: MAX_FEE_BPS,
+        };
+        process(
+            &mut self.context,
+            &[build_instruction(accounts, data)],
+            &[&self.taker],
+        )
+        .await
+    }
+
+    // The accounts for a valid cancellation of the escrow by the initializer.
+    async fn cancel_accounts(&mut self) -> accounts::CancelEscrow {
+        let escrow_account = self.escrow_account().await;
+        accounts::CancelEscrow {
+            authority: self.initializer.pubkey(),
+            initializer: escrow_account.initializer_key,
+            pda_deposit_token_account: escrow_account.vault,
+            initializer_deposit_token_account: escrow_account.initializer_deposit_token_account,
+            pda_account: client::escrow_pda(&self.escrow.pubkey()).0,
+            escrow_account: self.escrow.pubkey(),
+            order_book: client::order_book_address(&self.mint_a, &self.mint_b).0,
+            token_program: spl_token::ID,
+        }
+    }
+
+    // Cancels as the initializer.
+    async fn cancel(&mut self, accounts: accounts::CancelEscrow) -> Result<(), TransportError> {
+        process(
+            &mut self.context,
+            &[build_instruction(accounts, instruction::CancelEscrow {})],
+            &[&self.initializer],
+        )
+        .await
+    }
+
+    // Opens another escrow for the same pair, for tests that need a second one.
+    async fn initialize_other_escrow(&mut self) -> Keypair {
+        mint_to(
+            &mut self.context,
+            &self.mint_a,
+            &self.taker_token_account_a,
+            INITIALIZER_AMOUNT,
+        )
+        .await;
+        let other_escrow = Keypair::new();
+        let taker = self.taker.pubkey();
+        let payer = self.context.payer.pubkey();
+        process(
+            &mut self.context,
+            &[
+                system_instruction::transfer(&payer, &taker, 1_000_000_000),
+                client::initialize_escrow_ix(
+                    &taker,
+                    &self.taker_token_account_a,
+                    &self.taker_token_account_b,
+                    &self.mint_a,
+                    &self.mint_b,
+                    &other_escrow.pubkey(),
+                    INITIALIZER_AMOUNT,
+                    TAKER_AMOUNT,
+                    None,
+                    None,
+                    None,
+                ),
+            ],
+            &[&self.taker, &other_escrow],
+        )
+        .await
+        .unwrap();
+        other_escrow
+    }
+
+    // Opens another escrow by the initializer, on the same terms, from a new
+    // deposit account. Also funds the taker to exchange it.
+    async fn initialize_second_escrow(&mut self) -> Keypair {
+        let initializer = self.initializer.pubkey();
+        let deposit = create_token_account(&mut self.context, &self.mint_a, &initializer).await;
+        mint_to(
+            &mut self.context,
+            &self.mint_a,
+            &deposit,
+            INITIALIZER_AMOUNT,
+        )
+        .await;
+        mint_to(
+            &mut self.context,
+            &self.mint_b,
+            &self.taker_token_account_b,
+            TAKER_AMOUNT,
+        )
+        .await;
+        let second_escrow = Keypair::new();
+        process(
+            &mut self.context,
+            &[client::initialize_escrow_ix(
+                &initializer,
+                &deposit,
+                &self.initializer_token_account_b,
+                &self.mint_a,
+                &self.mint_b,
+                &second_escrow.pubkey(),
+                INITIALIZER_AMOUNT,
+                TAKER_AMOUNT,
+                None,
+                None,
+                None,
+            )],
+            &[&self.initializer, &second_escrow],
+        )
+        .await
+        .unwrap();
+        second_escrow
+    }
+}
+
+// An escrow as the original program left it: a v1 EscrowAccount, whose deposit
+// is owned by the program-wide legacy PDA. The program can't create these any
+// more, so they're added (along with the mints and token accounts) before the
+// test validator starts. The taker is funded to exchange the escrow, and the
+// config exists.
+struct LegacySetup {
+    context: ProgramTestContext,
+    initializer: Keypair,
+    initializer_token_account_a: Pubkey,
+    initializer_token_account_b: Pubkey,
+    taker: Keypair,
+    taker_token_account_a: Pubkey,
+    taker_token_account_b: Pubkey,
+    fee_token_account_a: Pubkey,
+    fee_token_account_b: Pubkey,
+    legacy_escrow: Pubkey,
+    legacy_escrow_account: EscrowAccountV1,
+}
+
+async fn setup_v1() -> LegacySetup {
+    let admin = Keypair::new();
+    let mut program_test = program_test(&admin.pubkey());
+    let initializer = Keypair::new();
+    let taker = Keypair::new();
+    let (legacy_pda, _) = client::legacy_escrow_pda();
+
+    let mint_a = add_mint(&mut program_test, INITIALIZER_AMOUNT);
+    let mint_b = add_mint(&mut program_test, TAKER_AMOUNT);
+    let initializer_token_account_a =
+        add_token_account(&mut program_test, &mint_a, &legacy_pda, INITIALIZER_AMOUNT);
+    let initializer_token_account_b =
+        add_token_account(&mut program_test, &mint_b, &initializer.pubkey(), 0);
+    let taker_token_account_a = add_token_account(&mut program_test, &mint_a, &taker.pubkey(), 0);
+    let taker_token_account_b =
+        add_token_account(&mut program_test, &mint_b, &taker.pubkey(), TAKER_AMOUNT);
+
+    let legacy_escrow = Pubkey::new_unique();
+    let legacy_escrow_account = EscrowAccountV1 {
+        initializer_key: initializer.pubkey(),
+        initializer_deposit_token_account: initializer_token_account_a,
+        initializer_receive_token_account: initializer_token_account_b,
+        initializer_amount: INITIALIZER_AMOUNT,
+        taker_amount: TAKER_AMOUNT,
+    };
+    let mut data = EscrowAccount::discriminator().to_vec();
+    data.extend(legacy_escrow_account.try_to_vec().unwrap());
+    program_test.add_account(
+        legacy_escrow,
+        Account {
+            lamports: Rent::default().minimum_balance(data.len()),
+            data,
+            owner: escrow::id(),
+            executable: false,
+            rent_epoch: 0,
+        },
+    );
+
+    let mut context = program_test.start_with_context().await;
+    let fee_recipient = Keypair::new();
+    let payer = context.payer.pubkey();
+    process(
+        &mut context,
+        &[
+            system_instruction::transfer(&payer, &initializer.pubkey(), 1_000_000_000),
+            client::initialize_config_ix(&admin.pubkey(), 0, 0, &fee_recipient.pubkey()),
+        ],
+        &[&admin],
+    )
+    .await
+    .unwrap();
+    let fee_token_account_a =
+        create_token_account(&mut context, &mint_a, &fee_recipient.pubkey()).await;
+    let fee_token_account_b =
+        create_token_account(&mut context, &mint_b, &fee_recipient.pubkey()).await;
+
+    LegacySetup {
+        context,
+        initializer,
+        initializer_token_account_a,
+        initializer_token_account_b,
+        taker,
+        taker_token_account_a,
+        taker_token_account_b,
+        fee_token_account_a,
+        fee_token_account_b,
+        legacy_escrow,
+        legacy_escrow_account,
+    }
+}
+
+impl LegacySetup {
+    // The accounts for exchanging the escrow. Tests replace one of them to check
+    // the handler's checks.
+    fn exchange_accounts(&self) -> accounts::ExchangeLegacyEscrow {
+        accounts::ExchangeLegacyEscrow {
+            taker: self.taker.pubkey(),
+            taker_deposit_token_account: self.taker_token_account_b,
+            taker_receive_token_account: self.taker_token_account_a,
+            pda_deposit_token_account: self.initializer_token_account_a,
+            initializer_receive_token_account: self.initializer_token_account_b,
+            initializer_main_account: self.initializer.pubkey(),
+            legacy_escrow_account: self.legacy_escrow,
+            legacy_pda_account: client::legacy_escrow_pda().0,
+            config: client::config_address().0,
+            offered_fee_token_account: self.fee_token_account_a,
+            requested_fee_token_account: self.fee_token_account_b,
+            token_program: spl_token::ID,
+        }
+    }
+
+    async fn exchange(
+        &mut self,
+        accounts: accounts::ExchangeLegacyEscrow,
+    ) -> Result<(), TransportError> {
+        let data = instruction::ExchangeLegacyEscrow {
+            expected_initializer_amount: INITIALIZER_AMOUNT,
+            expected_taker_amount: TAKER_AMOUNT,
+            max_fee_bps: MAX_FEE_BPS,
+            legacy_bump: client::legacy_escrow_pda().1,
+        };
+        process(
+            &mut self.context,
+            &[build_instruction(accounts, data)],
+            &[&self.taker],
+        )
+        .await
+    }
+}
+
+// A listed vault-mode escrow whose vault was drained below initializer_amount
+// (holding vault_amount), or closed outright (None). The program itself never
+// leaves an escrow like this, so it's added before the test validator starts.
+struct StaleSetup {
+    context: ProgramTestContext,
+    mint_a: Pubkey,
+    mint_b: Pubkey,
+    initializer: Pubkey,
+    initializer_token_account_a: Pubkey,
+    escrow: Pubkey,
+    vault: Pubkey,
+}
+
+async fn setup_stale(vault_amount: Option<u64>) -> StaleSetup {
+    let mut program_test = program_test(&Pubkey::new_unique());
+    let initializer = Pubkey::new_unique();
+    let escrow = Pubkey::new_unique();
+    let (pda, bump) = client::escrow_pda(&escrow);
+
+    let mint_a = add_mint(&mut program_test, vault_amount.unwrap_or(0));
+    let mint_b = add_mint(&mut program_test, 0);
+    let initializer_token_account_a =
+        add_token_account(&mut program_test, &mint_a, &initializer, 0);
+    let initializer_token_account_b =
+        add_token_account(&mut program_test, &mint_b, &initializer, 0);
+    let vault = match vault_amount {
+        Some(amount) => add_token_account(&mut program_test, &mint_a, &pda, amount),
+        None => Pubkey::new_unique(),
+    };
+
+    let mut data = EscrowAccount::discriminator().to_vec();
+    data.extend(
+        EscrowAccount {
+            version: ESCROW_ACCOUNT_VERSION,
+            initializer_key: initializer,
+            initializer_deposit_token_account: initializer_token_account_a,
+            initializer_receive_token_account: initializer_token_account_b,
+            offered_mint: mint_a,
+            requested_mint: mint_b,
+            initializer_amount: INITIALIZER_AMOUNT,
+            taker_amount: TAKER_AMOUNT,
+            expires_at: None,
+            allowed_taker: None,
+            canceller: None,
+            native_leg: NativeLeg::None,
+            nft: None,
+            auction: None,
+            english_auction: None,
+            oracle: None,
+            arbiter: None,
+            state: EscrowState::Open,
+            funding: None,
+            bump,
+            vault,
+            reserved: [0; 64],
+        }
+        .try_to_vec()
+        .unwrap(),
+    );
+    data.resize(8 + EscrowAccount::LEN, 0);
+    add_program_account(&mut program_test, escrow, data);
+    add_order_book(&mut program_test, &mint_a, &mint_b, vec![escrow]);
+
+    StaleSetup {
+        context: program_test.start_with_context().await,
+        mint_a,
+        mint_b,
+        initializer,
+        initializer_token_account_a,
+        escrow,
+        vault,
+    }
+}
+
+impl StaleSetup {
+    async fn reclaim(&mut self) -> Result<(), TransportError> {
+        let accounts = accounts::Reclaim {
+            initializer: self.initializer,
+            pda_deposit_token_account: self.vault,
+            initializer_deposit_token_account: self.initializer_token_account_a,
+            pda_account: client::escrow_pda(&self.escrow).0,
+            escrow_account: self.escrow,
+            order_book: client::order_book_address(&self.mint_a, &self.mint_b).0,
+            token_program: spl_token::ID,
+        };
+        process(
+            &mut self.context,
+            &[build_instruction(accounts, instruction::Reclaim {})],
+            &[],
+        )
+        .await
+    }
+
+    // Everything the initializer holds in lamports, which starts out at zero.
+    async fn initializer_lamports(&mut self) -> u64 {
+        self.context
+            .banks_client
+            .get_balance(self.initializer)
+            .await
+            .unwrap()
+    }
+}
+
+// A ProgramTest with the escrow program deployed through the upgradeable
+// loader, as it is on a real cluster, with upgrade_authority (funded to pay for
+// the config) as its upgrade authority. ProgramTest::new would load it at
+// genesis instead, with no upgrade authority to create the config.
+fn program_test(upgrade_authority: &Pubkey) -> ProgramTest {
+    let mut program_test = ProgramTest::default();
+    let elf = read_file(find_file("escrow.so").expect("escrow.so not found, run cargo build-bpf"));
+    let rent = Rent::default();
+
+    let mut program = Account::new_data(
+        rent.minimum_balance(UpgradeableLoaderState::program_len().unwrap()),
+        &UpgradeableLoaderState::Program {
+            programdata_address: client::program_data_address(),
+        },
+        &bpf_loader_upgradeable::id(),
+    )
+    .unwrap();
+    program.executable = true;
+    program_test.add_account(escrow::id(), program);
+
+    let offset = UpgradeableLoaderState::programdata_data_offset().unwrap();
+    let mut program_data = Account::new_data_with_space(
+        rent.minimum_balance(offset + elf.len()),
+        &UpgradeableLoaderState::ProgramData {
+            slot: 0,
+            upgrade_authority_address: Some(*upgrade_authority),
+        },
+        offset + elf.len(),
+        &bpf_loader_upgradeable::id(),
+    )
+    .unwrap();
+    program_data.data[offset..].copy_from_slice(&elf);
+    program_test.add_account(client::program_data_address(), program_data);
+
+    program_test.add_account(
+        *upgrade_authority,
+        Account::new(1_000_000_000, 0, &system_program::id()),
+    );
+    program_test
+}
+
+fn build_instruction(accounts: impl ToAccountMetas, data: impl InstructionData) -> Instruction {
+    Instruction {
+        program_id: escrow::id(),
+        accounts: accounts.to_account_metas(None),
+        data: data.data(),
+    }
+}
+
+// Sends the instructions in a transaction paid for (and signed by) the
+// context's payer, along with any other signers.
+async fn process(
+    context: &mut ProgramTestContext,
+    instructions: &[Instruction],
+    signers: &[&Keypair],
+) -> Result<(), TransportError> {
+    let mut all_signers = vec![&context.payer];
+    all_signers.extend_from_slice(signers);
+    let recent_blockhash = context.banks_client.get_recent_blockhash().await.unwrap();
+    let transaction = Transaction::new_signed_with_payer(
+        instructions,
+        Some(&context.payer.pubkey()),
+        &all_signers,
+        recent_blockhash,
+    );
+    context.banks_client.process_transaction(transaction).await
+}
+
+// Creates a mint whose authority is the context's payer.
+async fn create_mint(context: &mut ProgramTestContext) -> Pubkey {
+    let mint = Keypair::new();
+    let payer = context.payer.pubkey();
+    let rent = context.banks_client.get_rent().await.unwrap();
+    process(
+        context,
+        &[
+            system_instruction::create_account(
+                &payer,
+                &mint.pubkey(),
+                rent.minimum_balance(spl_token::state::Mint::LEN),
+                spl_token::state::Mint::LEN as u64,
+                &spl_token::ID,
+            ),
+            spl_token::instruction::initialize_mint(
+                &spl_token::ID,
+                &mint.pubkey(),
+                &payer,
+                None,
+                0,
+            )
+            .unwrap(),
+        ],
+        &[&mint],
+    )
+    .await
+    .unwrap();
+    mint.pubkey()
+}
+
+async fn create_token_account(
+    context: &mut ProgramTestContext,
+    mint: &Pubkey,
+    owner: &Pubkey,
+) -> Pubkey {
+    let account = Keypair::new();
+    let payer = context.payer.pubkey();
+    let rent = context.banks_client.get_rent().await.unwrap();
+    process(
+        context,
+        &[
+            system_instruction::create_account(
+                &payer,
+                &account.pubkey(),
+                rent.minimum_balance(spl_token::state::Account::LEN),
+                spl_token::state::Account::LEN as u64,
+                &spl_token::ID,
+            ),
+            spl_token::instruction::initialize_account(
+                &spl_token::ID,
+                &account.pubkey(),
+                mint,
+                owner,
+            )
+            .unwrap(),
+        ],
+        &[&account],
+    )
+    .await
+    .unwrap();
+    account.pubkey()
+}
+
+async fn mint_to(context: &mut ProgramTestContext, mint: &Pubkey, account: &Pubkey, amount: u64) {
+    let payer = context.payer.pubkey();
+    process(
+        context,
+        &[
+            spl_token::instruction::mint_to(&spl_token::ID, mint, account, &payer, &[], amount)
+                .unwrap(),
+        ],
+        &[],
+    )
+    .await
+    .unwrap();
+}
+
+// Adds a mint with no mint authority, so its supply is fixed.
+fn add_mint(program_test: &mut ProgramTest, supply: u64) -> Pubkey {
+    let mint = Pubkey::new_unique();
+    program_test.add_packable_account(
+        mint,
+        Rent::default().minimum_balance(spl_token::state::Mint::LEN),
+        &spl_token::state::Mint {
+            supply,
+            is_initialized: true,
+            ..spl_token::state::Mint::default()
+        },
+        &spl_token::ID,
+    );
+    mint
+}
+
+fn add_token_account(
+    program_test: &mut ProgramTest,
+    mint: &Pubkey,
+    owner: &Pubkey,
+    amount: u64,
+) -> Pubkey {
+    let account = Pubkey::new_unique();
+    program_test.add_packable_account(
+        account,
+        Rent::default().minimum_balance(spl_token::state::Account::LEN),
+        &spl_token::state::Account {
+            mint: *mint,
+            owner: *owner,
+            amount,
+            state: spl_token::state::AccountState::Initialized,
+            ..spl_token::state::Account::default()
+        },
+        &spl_token::ID,
+    );
+    account
+}
+
+// Adds the order book for the pair, listing escrows.
+fn add_order_book(
+    program_test: &mut ProgramTest,
+    mint_a: &Pubkey,
+    mint_b: &Pubkey,
+    escrows: Vec<Pubkey>,
+) {
+    let (order_book, bump) = client::order_book_address(mint_a, mint_b);
+    let mut data = OrderBook::discriminator().to_vec();
+    data.extend(
+        OrderBook {
+            offered_mint: *mint_a,
+            requested_mint: *mint_b,
+            bump,
+            escrows,
+        }
+        .try_to_vec()
+        .unwrap(),
+    );
+    data.resize(8 + OrderBook::LEN, 0);
+    add_program_account(program_test, order_book, data);
+}
+
+// Adds an account owned by the escrow program, funded to be rent exempt.
+fn add_program_account(program_test: &mut ProgramTest, address: Pubkey, data: Vec<u8>) {
+    program_test.add_account(
+        address,
+        Account {
+            lamports: Rent::default().minimum_balance(data.len()),
+            data,
+            owner: escrow::id(),
+            executable: false,
+            rent_epoch: 0,
+        },
+    );
+}
+
+async fn token_account(
+    context: &mut ProgramTestContext,
+    account: &Pubkey,
+) -> spl_token::state::Account {
+    let account = context
+        .banks_client
+        .get_account(*account)
+        .await
+        .unwrap()
+        .expect("token account should exist");
+    spl_token::state::Account::unpack(&account.data).unwrap()
+}
+
+async fn order_book(
+    context: &mut ProgramTestContext,
+    mint_a: &Pubkey,
+    mint_b: &Pubkey,
+) -> OrderBook {
+    let account = context
+        .banks_client
+        .get_account(client::order_book_address(mint_a, mint_b).0)
+        .await
+        .unwrap()
+        .expect("order book should exist");
+    OrderBook::try_deserialize(&mut account.data.as_slice()).unwrap()
+}
+
+async fn account_exists(context: &mut ProgramTestContext, account: &Pubkey) -> bool {
+    context
+        .banks_client
+        .get_account(*account)
+        .await
+        .unwrap()
+        .is_some()
+}
+
+fn assert_error(result: Result<(), TransportError>, expected: impl Into<ProgramError>) {
+    let expected = match expected.into() {
+        ProgramError::Custom(code) => code,
+        error => panic!("expected a custom program error, got {:?}", error),
+    };
+    match result {
+        Err(TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(code),
+        ))) => assert_eq!(code, expected),
+        result => panic!("expected custom error {}, got {:?}", expected, result),
+    }
+}
+
+#[tokio::test]
+async fn initialize_escrow() {
+    let mut setup = setup().await;
+
+    let escrow_account = setup.escrow_account().await;
+    assert_eq!(escrow_account.version, ESCROW_ACCOUNT_VERSION);
+    assert_eq!(escrow_account.initializer_key, setup.initializer.pubkey());
+    assert_eq!(
+        escrow_account.initializer_deposit_token_account,
+        setup.initializer_token_account_a
+    );
+    assert_eq!(
+        escrow_account.initializer_receive_token_account,
+        setup.initializer_token_account_b
+    );
+    assert_eq!(escrow_account.offered_mint, setup.mint_a);
+    assert_eq!(escrow_account.requested_mint, setup.mint_b);
+    assert_eq!(escrow_account.initializer_amount, INITIALIZER_AMOUNT);
+    assert_eq!(escrow_account.taker_amount, TAKER_AMOUNT);
+
+    // The escrow's PDA now owns the deposit account.
+    let (pda, bump) = client::escrow_pda(&setup.escrow.pubkey());
+    assert_eq!(escrow_account.bump, bump);
+    let deposit = token_account(&mut setup.context, &setup.initializer_token_account_a).await;
+    assert_eq!(deposit.owner, pda);
+}
+
+#[tokio::test]
+async fn initialize_config_rejects_non_upgrade_authority() {
+    let mut context = program_test(&Pubkey::new_unique())
+        .start_with_context()
+        .await;
+    let payer = context.payer.pubkey();
+    let result = process(
+        &mut context,
+        &[client::initialize_config_ix(&payer, 0, 0, &payer)],
+        &[],
+    )
+    .await;
+    assert_error(result, ErrorCode::NotUpgradeAuthority);
+}
+
+#[tokio::test]
+async fn initialize_config_rejects_program_without_upgrade_authority() {
+    // Loaded at genesis, the program has no upgrade authority at all.
+    let mut context = ProgramTest::new("escrow", escrow::id(), None)
+        .start_with_context()
+        .await;
+    let payer = context.payer.pubkey();
+    let result = process(
+        &mut context,
+        &[client::initialize_config_ix(&payer, 0, 0, &payer)],
+        &[],
+    )
+    .await;
+    assert_error(result, ErrorCode::NotUpgradeAuthority);
+}
+
+#[tokio::test]
+async fn exchange() {
+    let mut setup = setup().await;
+
+    let escrow_account = setup.escrow_account().await;
+    process(
+        &mut setup.context,
+        &[client::exchange_ix(
+            &setup.escrow.pubkey(),
+            &escrow_account,
+            &setup.taker.pubkey(),
+            &setup.taker_token_account_b,
+            &setup.taker_token_account_a,
+            &setup.fee_token_account_a,
+            &setup.fee_token_account_b,
+            MAX_FEE_BPS,
+        )],
+        &[&setup.taker],
+    )
+    .await
+    .unwrap();
+
+    let taker_a = token_account(&mut setup.context, &setup.taker_token_account_a).await;
+    let taker_b = token_account(&mut setup.context, &setup.taker_token_account_b).await;
+    let initializer_a = token_account(&mut setup.context, &setup.initializer_token_account_a).await;
+    let initializer_b = token_account(&mut setup.context, &setup.initializer_token_account_b).await;
+    assert_eq!(taker_a.amount, INITIALIZER_AMOUNT);
+    assert_eq!(taker_b.amount, 0);
+    assert_eq!(initializer_a.amount, 0);
+    assert_eq!(initializer_b.amount, TAKER_AMOUNT);
+    // The initializer gets their deposit account back, and the escrow is closed.
+    assert_eq!(initializer_a.owner, setup.initializer.pubkey());
+    assert!(!account_exists(&mut setup.context, &setup.escrow.pubkey()).await);
+}
+
+#[tokio::test]
+async fn exchange_leaves_escrow_closed() {
+    let mut setup = setup().await;
+
+    // Topping the escrow account back up in the same transaction keeps it alive,
+    // but it must stay marked as closed rather than being a usable escrow again.
+    let escrow_account = setup.escrow_account().await;
+    let payer = setup.context.payer.pubkey();
+    let rent = setup.context.banks_client.get_rent().await.unwrap();
+    process(
+        &mut setup.context,
+        &[
+            client::exchange_ix(
+                &setup.escrow.pubkey(),
+                &escrow_account,
+                &setup.taker.pubkey(),
+                &setup.taker_token_account_b,
+                &setup.taker_token_account_a,
+                &setup.fee_token_account_a,
+                &setup.fee_token_account_b,
+                MAX_FEE_BPS,
+            ),
+            system_instruction::transfer(
+                &payer,
+                &setup.escrow.pubkey(),
+                rent.minimum_balance(8 + EscrowAccount::LEN),
+            ),
+        ],
+        &[&setup.taker],
+    )
+    .await
+    .unwrap();
+
+    let account = setup
+        .context
+        .banks_client
+        .get_account(setup.escrow.pubkey())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(account.data[..8], CLOSED_ACCOUNT_DISCRIMINATOR);
+    assert!(EscrowAccount::try_deserialize(&mut account.data.as_slice()).is_err());
+}
+
+#[tokio::test]
+async fn exchange_partial_rejects_taking_everything_left() {
+    let mut setup = setup().await;
+
+    let escrow_account = setup.escrow_account().await;
+    let result = process(
+        &mut setup.context,
+        &[client::exchange_partial_ix(
+            &setup.escrow.pubkey(),
+            &escrow_account,
+            &setup.taker.pubkey(),
+            &setup.taker_token_account_b,
+            &setup.taker_token_account_a,
+            &setup.fee_token_account_a,
+            &setup.fee_token_account_b,
+            TAKER_AMOUNT,
+            MAX_FEE_BPS,
+        )],
+        &[&setup.taker],
+    )
+    .await;
+    assert_error(result, ErrorCode::InvalidFillAmount);
+}
+
+#[tokio::test]
+async fn exchange_partial_rejects_terms_mismatch() {
+    let mut setup = setup().await;
+
+    // The taker fetches the escrow, then the initializer doubles the price
+    // before the fill lands.
+    let stale_escrow_account = setup.escrow_account().await;
+    let accounts = accounts::UpdateEscrow {
+        initializer: setup.initializer.pubkey(),
+        initializer_deposit_token_account: setup.initializer_token_account_a,
+        pda_deposit_token_account: setup.initializer_token_account_a,
+        pda_account: client::escrow_pda(&setup.escrow.pubkey()).0,
+        escrow_account: setup.escrow.pubkey(),
+        token_program: spl_token::ID,
+    };
+    let data = instruction::UpdateEscrow {
+        initializer_amount: INITIALIZER_AMOUNT,
+        taker_amount: 2 * TAKER_AMOUNT,
+    };
+    process(
+        &mut setup.context,
+        &[build_instruction(accounts, data)],
+        &[&setup.initializer],
+    )
+    .await
+    .unwrap();
+
+    let result = process(
+        &mut setup.context,
+        &[client::exchange_partial_ix(
+            &setup.escrow.pubkey(),
+            &stale_escrow_account,
+            &setup.taker.pubkey(),
+            &setup.taker_token_account_b,
+            &setup.taker_token_account_a,
+            &setup.fee_token_account_a,
+            &setup.fee_token_account_b,
+            TAKER_AMOUNT / 2,
+            MAX_FEE_BPS,
+        )],
+        &[&setup.taker],
+    )
+    .await;
+    assert_error(result, ErrorCode::TermsMismatch);
+
+    // At the new price, the same payment buys half as much.
+    let escrow_account = setup.escrow_account().await;
+    process(
+        &mut setup.context,
+        &[client::exchange_partial_ix(
+            &setup.escrow.pubkey(),
+            &escrow_account,
+            &setup.taker.pubkey(),
+            &setup.taker_token_account_b,
+            &setup.taker_token_account_a,
+            &setup.fee_token_account_a,
+            &setup.fee_token_account_b,
+            TAKER_AMOUNT / 2,
+            MAX_FEE_BPS,
+        )],
+        &[&setup.taker],
+    )
+    .await
+    .unwrap();
+    let taker_a = token_account(&mut setup.context, &setup.taker_token_account_a).await;
+    assert_eq!(taker_a.amount, INITIALIZER_AMOUNT / 4);
+}
+
+#[tokio::test]
+async fn cancel_escrow() {
+    let mut setup = setup().await;
+
+    let escrow_account = setup.escrow_account().await;
+    process(
+        &mut setup.context,
+        &[client::cancel_escrow_ix(
+            &setup.escrow.pubkey(),
+            &escrow_account,
+            &setup.initializer.pubkey(),
+        )],
+        &[&setup.initializer],
+    )
+    .await
+    .unwrap();
+
+    let initializer_a = token_account(&mut setup.context, &setup.initializer_token_account_a).await;
+    assert_eq!(initializer_a.owner, setup.initializer.pubkey());
+    assert_eq!(initializer_a.amount, INITIALIZER_AMOUNT);
+    assert!(!account_exists(&mut setup.context, &setup.escrow.pubkey()).await);
+}
+
+#[tokio::test]
+async fn update_rejects_decrease_outside_vault_mode() {
+    let mut setup = setup().await;
+
+    // The PDA holds the whole deposit account, so there's nowhere to send the
+    // difference back to.
+    let accounts = accounts::UpdateEscrow {
+        initializer: setup.initializer.pubkey(),
+        initializer_deposit_token_account: setup.initializer_token_account_a,
+        pda_deposit_token_account: setup.initializer_token_account_a,
+        pda_account: client::escrow_pda(&setup.escrow.pubkey()).0,
+        escrow_account: setup.escrow.pubkey(),
+        token_program: spl_token::ID,
+    };
+    let data = instruction::UpdateEscrow {
+        initializer_amount: INITIALIZER_AMOUNT - 1,
+        taker_amount: TAKER_AMOUNT,
+    };
+    let result = process(
+        &mut setup.context,
+        &[build_instruction(accounts, data)],
+        &[&setup.initializer],
+    )
+    .await;
+    assert_error(result, ErrorCode::DepositDecreaseUnsupported);
+
+    let escrow_account = setup.escrow_account().await;
+    assert_eq!(escrow_account.initializer_amount, INITIALIZER_AMOUNT);
+}
+
+#[tokio::test]
+async fn clearing_a_field_leaves_no_stale_bytes() {
+    let mut setup = setup().await;
+
+    // Setting a canceller lengthens the escrow's serialization by 32 bytes, and
+    // clearing it shortens it again.
+    for canceller in &[Some(Pubkey::new_unique()), None] {
+        let accounts = accounts::SetCanceller {
+            initializer: setup.initializer.pubkey(),
+            escrow_account: setup.escrow.pubkey(),
+        };
+        process(
+            &mut setup.context,
+            &[build_instruction(
+                accounts,
+                instruction::SetCanceller {
+                    canceller: *canceller,
+                },
+            )],
+            &[&setup.initializer],
+        )
+        .await
+        .unwrap();
+    }
+
+    let escrow_account = setup.escrow_account().await;
+    assert!(escrow_account.canceller.is_none());
+    let len = 8 + escrow_account.try_to_vec().unwrap().len();
+    let account = setup
+        .context
+        .banks_client
+        .get_account(setup.escrow.pubkey())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(account.data[len..].iter().all(|&byte| byte == 0));
+}
+
+#[tokio::test]
+async fn exchange_rejects_terms_mismatch() {
+    let mut setup = setup().await;
+
+    let accounts = setup.exchange_accounts().await;
+    let data = instruction::Exchange {
+        expected_initializer_amount: INITIALIZER_AMOUNT,
+        expected_taker_amount: TAKER_AMOUNT - 1,
+        max_fee_bps: MAX_FEE_BPS,
+    };
+    let result = process(
+        &mut setup.context,
+        &[build_instruction(accounts, data)],
+        &[&setup.taker],
+    )
+    .await;
+    assert_error(result, ErrorCode::TermsMismatch);
+}
+
+#[tokio::test]
+async fn exchange_rejects_fee_above_maximum() {
+    let mut setup = setup().await;
+
+    // The admin raises the requested leg's fee to 1% after the taker has
+    // settled on paying at most 0.99%.
+    let admin = setup.admin.pubkey();
+    let fee_recipient = token_account(&mut setup.context, &setup.fee_token_account_b)
+        .await
+        .owner;
+    let update_config = build_instruction(
+        accounts::UpdateConfig {
+            config: client::config_address().0,
+            admin,
+        },
+        instruction::UpdateConfig {
+            offered_leg_fee_bps: 0,
+            requested_leg_fee_bps: 100,
+            fee_recipient,
+            admin,
+        },
+    );
+    process(&mut setup.context, &[update_config], &[&setup.admin])
+        .await
+        .unwrap();
+
+    let accounts = setup.exchange_accounts().await;
+    let data = instruction::Exchange {
+        expected_initializer_amount: INITIALIZER_AMOUNT,
+        expected_taker_amount: TAKER_AMOUNT,
+        max_fee_bps: 99,
+    };
+    let result = process(
+        &mut setup.context,
+        &[build_instruction(accounts, data)],
+        &[&setup.taker],
+    )
+    .await;
+    assert_error(result, ErrorCode::FeeAboveMaximum);
+
+    // A taker who accepts the new fee pays it.
+    let accounts = setup.exchange_accounts().await;
+    let data = instruction::Exchange {
+        expected_initializer_amount: INITIALIZER_AMOUNT,
+        expected_taker_amount: TAKER_AMOUNT,
+        max_fee_bps: 100,
+    };
+    process(
+        &mut setup.context,
+        &[build_instruction(accounts, data)],
+        &[&setup.taker],
+    )
+    .await
+    .unwrap();
+    let initializer_b = token_account(&mut setup.context, &setup.initializer_token_account_b).await;
+    let fee_b = token_account(&mut setup.context, &setup.fee_token_account_b).await;
+    assert_eq!(initializer_b.amount, TAKER_AMOUNT - 10);
+    assert_eq!(fee_b.amount, 10);
+}
+
+#[tokio::test]
+async fn exchange_rejects_wrong_taker() {
+    let mut setup = setup().await;
+
+    // A private escrow that only the initializer's chosen taker can take.
+    let private_escrow = Keypair::new();
+    let initializer = setup.initializer.pubkey();
+    let mint_a = setup.mint_a;
+    let private_deposit_token_account =
+        create_token_account(&mut setup.context, &mint_a, &initializer).await;
+    mint_to(
+        &mut setup.context,
+        &mint_a,
+        &private_deposit_token_account,
+        INITIALIZER_AMOUNT,
+    )
+    .await;
+    process(
+        &mut setup.context,
+        &[client::initialize_escrow_ix(
+            &initializer,
+            &private_deposit_token_account,
+            &setup.initializer_token_account_b,
+            &setup.mint_a,
+            &setup.mint_b,
+            &private_escrow.pubkey(),
+            INITIALIZER_AMOUNT,
+            TAKER_AMOUNT,
+            None,
+            Some(Pubkey::new_unique()),
+            None,
+        )],
+        &[&setup.initializer, &private_escrow],
+    )
+    .await
+    .unwrap();
+
+    let accounts = accounts::Exchange {
+        pda_deposit_token_account: private_deposit_token_account,
+        escrow_account: private_escrow.pubkey(),
+        pda_account: client::escrow_pda(&private_escrow.pubkey()).0,
+        ..setup.exchange_accounts().await
+    };
+    let result = setup.exchange(accounts).await;
+    assert_error(result, ErrorCode::TakerNotAllowed);
+}
+
+#[tokio::test]
+async fn exchange_rejects_wrong_deposit_mint() {
+    let mut setup = setup().await;
+
+    let taker = setup.taker.pubkey();
+    let mint_a = setup.mint_a;
+    let taker_deposit_token_account =
+        create_token_account(&mut setup.context, &mint_a, &taker).await;
+    let accounts = accounts::Exchange {
+        taker_deposit_token_account,
+        ..setup.exchange_accounts().await
+    };
+    let result = setup.exchange(accounts).await;
+    assert_error(result, ErrorCode::RequestedMintMismatch);
+}
+
+#[tokio::test]
+async fn exchange_rejects_wrong_receive_mint() {
+    let mut setup = setup().await;
+
+    let accounts = accounts::Exchange {
+        taker_receive_token_account: setup.taker_token_account_b,
+        ..setup.exchange_accounts().await
+    };
+    let result = setup.exchange(accounts).await;
+    assert_error(result, ErrorCode::OfferedMintMismatch);
+}
+
+#[tokio::test]
+async fn exchange_rejects_wrong_deposit_account() {
+    let mut setup = setup().await;
+
+    let accounts = accounts::Exchange {
+        pda_deposit_token_account: setup.fee_token_account_a,
+        ..setup.exchange_accounts().await
+    };
+    let result = setup.exchange(accounts).await;
+    assert_error(result, AnchorErrorCode::ConstraintRaw);
+}
+
+#[tokio::test]
+async fn exchange_rejects_wrong_initializer_receive_account() {
+    let mut setup = setup().await;
+
+    let accounts = accounts::Exchange {
+        initializer_receive_token_account: setup.taker_token_account_b,
+        ..setup.exchange_accounts().await
+    };
+    let result = setup.exchange(accounts).await;
+    assert_error(result, AnchorErrorCode::ConstraintRaw);
+}
+
+#[tokio::test]
+async fn exchange_rejects_wrong_initializer() {
+    let mut setup = setup().await;
+
+    let accounts = accounts::Exchange {
+        initializer_main_account: setup.taker.pubkey(),
+        ..setup.exchange_accounts().await
+    };
+    let result = setup.exchange(accounts).await;
+    assert_error(result, AnchorErrorCode::ConstraintRaw);
+}
+
+// Exchange also rejects escrows with a native SOL leg (NativeLegMismatch), but
+// those escrows can't get that far: their vault and receive accounts never pass
+// as the token accounts Exchange expects.
+
+#[tokio::test]
+async fn exchange_rejects_wrong_pda() {
+    let mut setup = setup().await;
+
+    let other_escrow = setup.initialize_other_escrow().await;
+    let accounts = accounts::Exchange {
+        pda_account: client::escrow_pda(&other_escrow.pubkey()).0,
+        ..setup.exchange_accounts().await
+    };
+    let result = setup.exchange(accounts).await;
+    assert_error(result, AnchorErrorCode::ConstraintSeeds);
+}
+
+#[tokio::test]
+async fn exchange_rejects_wrong_order_book() {
+    let mut setup = setup().await;
+
+    // The order book for the opposite direction, B for A.
+    let payer = setup.context.payer.pubkey();
+    let (mint_a, mint_b) = (setup.mint_a, setup.mint_b);
+    process(
+        &mut setup.context,
+        &[client::initialize_order_book_ix(&payer, &mint_b, &mint_a)],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    let accounts = accounts::Exchange {
+        order_book: client::order_book_address(&mint_b, &mint_a).0,
+        ..setup.exchange_accounts().await
+    };
+    let result = setup.exchange(accounts).await;
+    assert_error(result, AnchorErrorCode::ConstraintSeeds);
+}
+
+#[tokio::test]
+async fn exchange_rejects_fee_account_of_wrong_owner() {
+    let mut setup = setup().await;
+
+    let accounts = accounts::Exchange {
+        offered_fee_token_account: setup.initializer_token_account_a,
+        ..setup.exchange_accounts().await
+    };
+    let result = setup.exchange(accounts).await;
+    assert_error(result, ErrorCode::InvalidFeeAccount);
+}
+
+#[tokio::test]
+async fn exchange_rejects_fee_account_of_wrong_mint() {
+    let mut setup = setup().await;
+
+    let accounts = accounts::Exchange {
+        requested_fee_token_account: setup.fee_token_account_a,
+        ..setup.exchange_accounts().await
+    };
+    let result = setup.exchange(accounts).await;
+    assert_error(result, ErrorCode::InvalidFeeAccount);
+}
+
+#[tokio::test]
+async fn cancel_rejects_unauthorized_canceller() {
+    let mut setup = setup().await;
+
+    let stranger = Keypair::new();
+    let accounts = accounts::CancelEscrow {
+        authority: stranger.pubkey(),
+        ..setup.cancel_accounts().await
+    };
+    let result = process(
+        &mut setup.context,
+        &[build_instruction(accounts, instruction::CancelEscrow {})],
+        &[&stranger],
+    )
+    .await;
+    assert_error(result, ErrorCode::UnauthorizedCanceller);
+}
+
+#[tokio::test]
+async fn cancel_rejects_unsigned_authority() {
+    let mut setup = setup().await;
+
+    let accounts = setup.cancel_accounts().await;
+    let mut instruction = build_instruction(accounts, instruction::CancelEscrow {});
+    instruction.accounts[0].is_signer = false;
+    let result = process(&mut setup.context, &[instruction], &[]).await;
+    assert_error(result, AnchorErrorCode::ConstraintSigner);
+}
+
+#[tokio::test]
+async fn cancel_rejects_wrong_initializer() {
+    let mut setup = setup().await;
+
+    let accounts = accounts::CancelEscrow {
+        initializer: setup.taker.pubkey(),
+        ..setup.cancel_accounts().await
+    };
+    let result = setup.cancel(accounts).await;
+    assert_error(result, AnchorErrorCode::ConstraintRaw);
+}
+
+#[tokio::test]
+async fn cancel_rejects_wrong_deposit_account() {
+    let mut setup = setup().await;
+
+    let accounts = accounts::CancelEscrow {
+        pda_deposit_token_account: setup.fee_token_account_a,
+        ..setup.cancel_accounts().await
+    };
+    let result = setup.cancel(accounts).await;
+    assert_error(result, AnchorErrorCode::ConstraintRaw);
+}
+
+#[tokio::test]
+async fn cancel_rejects_wrong_refund_account() {
+    let mut setup = setup().await;
+
+    let accounts = accounts::CancelEscrow {
+        initializer_deposit_token_account: setup.taker_token_account_a,
+        ..setup.cancel_accounts().await
+    };
+    let result = setup.cancel(accounts).await;
+    assert_error(result, AnchorErrorCode::ConstraintRaw);
+}
+
+#[tokio::test]
+async fn cancel_rejects_wrong_pda() {
+    let mut setup = setup().await;
+
+    let other_escrow = setup.initialize_other_escrow().await;
+    let accounts = accounts::CancelEscrow {
+        pda_account: client::escrow_pda(&other_escrow.pubkey()).0,
+        ..setup.cancel_accounts().await
+    };
+    let result = setup.cancel(accounts).await;
+    assert_error(result, AnchorErrorCode::ConstraintSeeds);
+}
+
+#[tokio::test]
+async fn cancel_rejects_wrong_order_book() {
+    let mut setup = setup().await;
+
+    let payer = setup.context.payer.pubkey();
+    let (mint_a, mint_b) = (setup.mint_a, setup.mint_b);
+    process(
+        &mut setup.context,
+        &[client::initialize_order_book_ix(&payer, &mint_b, &mint_a)],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    let accounts = accounts::CancelEscrow {
+        order_book: client::order_book_address(&mint_b, &mint_a).0,
+        ..setup.cancel_accounts().await
+    };
+    let result = setup.cancel(accounts).await;
+    assert_error(result, AnchorErrorCode::ConstraintSeeds);
+}
+
+#[tokio::test]
+async fn exchange_legacy_escrow() {
+    let mut setup = setup_v1().await;
+
+    let initializer = setup.initializer.pubkey();
+    let initializer_lamports = setup
+        .context
+        .banks_client
+        .get_balance(initializer)
+        .await
+        .unwrap();
+    let escrow_lamports = setup
+        .context
+        .banks_client
+        .get_balance(setup.legacy_escrow)
+        .await
+        .unwrap();
+    process(
+        &mut setup.context,
+        &[client::exchange_legacy_escrow_ix(
+            &setup.legacy_escrow,
+            &setup.legacy_escrow_account,
+            &setup.taker.pubkey(),
+            &setup.taker_token_account_b,
+            &setup.taker_token_account_a,
+            &setup.fee_token_account_a,
+            &setup.fee_token_account_b,
+            MAX_FEE_BPS,
+        )],
+        &[&setup.taker],
+    )
+    .await
+    .unwrap();
+
+    // The escrow is exchanged at its own address: the deposit goes back to the
+    // initializer, and the escrow account is closed with its rent refunded.
+    let taker_a = token_account(&mut setup.context, &setup.taker_token_account_a).await;
+    let initializer_a = token_account(&mut setup.context, &setup.initializer_token_account_a).await;
+    let initializer_b = token_account(&mut setup.context, &setup.initializer_token_account_b).await;
+    assert_eq!(taker_a.amount, INITIALIZER_AMOUNT);
+    assert_eq!(initializer_b.amount, TAKER_AMOUNT);
+    assert_eq!(initializer_a.owner, initializer);
+    assert!(!account_exists(&mut setup.context, &setup.legacy_escrow).await);
+    let balance = setup
+        .context
+        .banks_client
+        .get_balance(initializer)
+        .await
+        .unwrap();
+    assert_eq!(balance, initializer_lamports + escrow_lamports);
+}
+
+#[tokio::test]
+async fn cancel_legacy_escrow() {
+    let mut setup = setup_v1().await;
+
+    process(
+        &mut setup.context,
+        &[client::cancel_legacy_escrow_ix(
+            &setup.legacy_escrow,
+            &setup.legacy_escrow_account,
+        )],
+        &[&setup.initializer],
+    )
+    .await
+    .unwrap();
+
+    let initializer_a = token_account(&mut setup.context, &setup.initializer_token_account_a).await;
+    assert_eq!(initializer_a.amount, INITIALIZER_AMOUNT);
+    assert_eq!(initializer_a.owner, setup.initializer.pubkey());
+    assert!(!account_exists(&mut setup.context, &setup.legacy_escrow).await);
+}
+
+#[tokio::test]
+async fn exchange_legacy_rejects_terms_mismatch() {
+    let mut setup = setup_v1().await;
+
+    let data = instruction::ExchangeLegacyEscrow {
+        expected_initializer_amount: INITIALIZER_AMOUNT,
+        expected_taker_amount: TAKER_AMOUNT - 1,
+        max_fee_bps: MAX_FEE_BPS,
+        legacy_bump: client::legacy_escrow_pda().1,
+    };
+    let accounts = setup.exchange_accounts();
+    let result = process(
+        &mut setup.context,
+        &[build_instruction(accounts, data)],
+        &[&setup.taker],
+    )
+    .await;
+    assert_error(result, ErrorCode::TermsMismatch);
+}
+
+#[tokio::test]
+async fn exchange_legacy_rejects_wrong_initializer() {
+    let mut setup = setup_v1().await;
+
+    let accounts = accounts::ExchangeLegacyEscrow {
+        initializer_main_account: setup.taker.pubkey(),
+        ..setup.exchange_accounts()
+    };
+    let result = setup.exchange(accounts).await;
+    assert_error(result, ErrorCode::LegacyEscrowMismatch);
+}
+
+#[tokio::test]
+async fn exchange_legacy_rejects_wrong_deposit_account() {
+    let mut setup = setup_v1().await;
+
+    // A token account of the right mint that the legacy PDA doesn't own.
+    let accounts = accounts::ExchangeLegacyEscrow {
+        pda_deposit_token_account: setup.taker_token_account_a,
+        ..setup.exchange_accounts()
+    };
+    let result = setup.exchange(accounts).await;
+    assert_error(result, ErrorCode::LegacyEscrowMismatch);
+}
+
+#[tokio::test]
+async fn cancel_legacy_rejects_wrong_initializer() {
+    let mut setup = setup_v1().await;
+
+    let other = Keypair::new();
+    let accounts = accounts::CancelLegacyEscrow {
+        initializer: other.pubkey(),
+        pda_deposit_token_account: setup.initializer_token_account_a,
+        initializer_receive_token_account: setup.initializer_token_account_b,
+        legacy_escrow_account: setup.legacy_escrow,
+        legacy_pda_account: client::legacy_escrow_pda().0,
+        token_program: spl_token::ID,
+    };
+    let data = instruction::CancelLegacyEscrow {
+        legacy_bump: client::legacy_escrow_pda().1,
+    };
+    let result = process(
+        &mut setup.context,
+        &[build_instruction(accounts, data)],
+        &[&other],
+    )
+    .await;
+    assert_error(result, ErrorCode::LegacyEscrowMismatch);
+}
+
+#[tokio::test]
+async fn exchange_legacy_rejects_current_escrow() {
+    let mut setup = setup().await;
+
+    // An escrow on the current layout isn't a v1 escrow, even though its
+    // discriminator matches.
+    let escrow_account = setup.escrow_account().await;
+    let legacy_escrow_account = EscrowAccountV1 {
+        initializer_key: escrow_account.initializer_key,
+        initializer_deposit_token_account: escrow_account.initializer_deposit_token_account,
+        initializer_receive_token_account: escrow_account.initializer_receive_token_account,
+        initializer_amount: escrow_account.initializer_amount,
+        taker_amount: escrow_account.taker_amount,
+    };
+    let result = process(
+        &mut setup.context,
+        &[client::exchange_legacy_escrow_ix(
+            &setup.escrow.pubkey(),
+            &legacy_escrow_account,
+            &setup.taker.pubkey(),
+            &setup.taker_token_account_b,
+            &setup.taker_token_account_a,
+            &setup.fee_token_account_a,
+            &setup.fee_token_account_b,
+            MAX_FEE_BPS,
+        )],
+        &[&setup.taker],
+    )
+    .await;
+    assert_error(result, ErrorCode::NotALegacyEscrow);
+}
+
+#[tokio::test]
+async fn exchange_many() {
+    let mut setup = setup().await;
+    let second_escrow = setup.initialize_second_escrow().await;
+
+    let first_accounts = setup.exchange_accounts().await;
+    let first_escrow_account = setup.escrow_account().await;
+    let second_escrow_account = {
+        let account = setup
+            .context
+            .banks_client
+            .get_account(second_escrow.pubkey())
+            .await
+            .unwrap()
+            .unwrap();
+        EscrowAccount::try_deserialize(&mut account.data.as_slice()).unwrap()
+    };
+    let second_accounts = accounts::Exchange {
+        pda_deposit_token_account: second_escrow_account.vault,
+        escrow_account: second_escrow.pubkey(),
+        pda_account: client::escrow_pda(&second_escrow.pubkey()).0,
+        ..setup.exchange_accounts().await
+    };
+    process(
+        &mut setup.context,
+        &[client::exchange_many_ix(
+            &setup.taker.pubkey(),
+            &[
+                (&first_escrow_account, first_accounts),
+                (&second_escrow_account, second_accounts),
+            ],
+            MAX_FEE_BPS,
+        )],
+        &[&setup.taker],
+    )
+    .await
+    .unwrap();
+
+    let taker_a = token_account(&mut setup.context, &setup.taker_token_account_a).await;
+    let taker_b = token_account(&mut setup.context, &setup.taker_token_account_b).await;
+    let initializer_b = token_account(&mut setup.context, &setup.initializer_token_account_b).await;
+    assert_eq!(taker_a.amount, 2 * INITIALIZER_AMOUNT);
+    assert_eq!(taker_b.amount, 0);
+    assert_eq!(initializer_b.amount, 2 * TAKER_AMOUNT);
+    assert!(!account_exists(&mut setup.context, &setup.escrow.pubkey()).await);
+    assert!(!account_exists(&mut setup.context, &second_escrow.pubkey()).await);
+}
+
+#[tokio::test]
+async fn exchange_many_rejects_any_mismatch() {
+    let mut setup = setup().await;
+    let second_escrow = setup.initialize_second_escrow().await;
+
+    let first_accounts = setup.exchange_accounts().await;
+    // The second escrow is given the first escrow's deposit account.
+    let second_accounts = accounts::Exchange {
+        escrow_account: second_escrow.pubkey(),
+        pda_account: client::escrow_pda(&second_escrow.pubkey()).0,
+        ..setup.exchange_accounts().await
+    };
+    let mut accounts = accounts::ExchangeMany {
+        taker: setup.taker.pubkey(),
+    }
+    .to_account_metas(None);
+    accounts.extend(first_accounts.to_account_metas(None));
+    accounts.extend(second_accounts.to_account_metas(None));
+    let data = instruction::ExchangeMany {
+        expected_initializer_amounts: vec![INITIALIZER_AMOUNT; 2],
+        expected_taker_amounts: vec![TAKER_AMOUNT; 2],
+        max_fee_bps: MAX_FEE_BPS,
+    };
+    let result = process(
+        &mut setup.context,
+        &[Instruction {
+            program_id: escrow::id(),
+            accounts,
+            data: data.data(),
+        }],
+        &[&setup.taker],
+    )
+    .await;
+    assert_error(result, AnchorErrorCode::ConstraintRaw);
+
+    // The first exchange was rolled back along with the second.
+    let taker_a = token_account(&mut setup.context, &setup.taker_token_account_a).await;
+    assert_eq!(taker_a.amount, 0);
+    assert!(account_exists(&mut setup.context, &setup.escrow.pubkey()).await);
+}
+
+#[tokio::test]
+async fn exchange_many_rejects_missing_accounts() {
+    let mut setup = setup().await;
+
+    let accounts = setup.exchange_accounts().await;
+    let mut accounts = accounts.to_account_metas(None);
+    accounts.insert(0, AccountMeta::new_readonly(setup.taker.pubkey(), true));
+    // Two pairs of expected amounts, but only one escrow's accounts.
+    let data = instruction::ExchangeMany {
+        expected_initializer_amounts: vec![INITIALIZER_AMOUNT; 2],
+        expected_taker_amounts: vec![TAKER_AMOUNT; 2],
+        max_fee_bps: MAX_FEE_BPS,
+    };
+    let result = process(
+        &mut setup.context,
+        &[Instruction {
+            program_id: escrow::id(),
+            accounts,
+            data: data.data(),
+        }],
+        &[&setup.taker],
+    )
+    .await;
+    assert_error(result, AnchorErrorCode::AccountNotEnoughKeys);
+    assert!(account_exists(&mut setup.context, &setup.escrow.pubkey()).await);
+}
+
+#[tokio::test]
+async fn full_order_book_does_not_block_escrows() {
+    // An order book that's one escrow short of full, as if someone had filled
+    // it with dust escrows.
+    let mut program_test = program_test(&Pubkey::new_unique());
+    let initializer = Keypair::new();
+    let mint_a = add_mint(&mut program_test, 2 * INITIALIZER_AMOUNT);
+    let mint_b = add_mint(&mut program_test, 0);
+    let deposits = [
+        add_token_account(
+            &mut program_test,
+            &mint_a,
+            &initializer.pubkey(),
+            INITIALIZER_AMOUNT,
+        ),
+        add_token_account(
+            &mut program_test,
+            &mint_a,
+            &initializer.pubkey(),
+            INITIALIZER_AMOUNT,
+        ),
+    ];
+    let receive = add_token_account(&mut program_test, &mint_b, &initializer.pubkey(), 0);
+    add_order_book(
+        &mut program_test,
+        &mint_a,
+        &mint_b,
+        (1..MAX_ORDER_BOOK_ENTRIES)
+            .map(|_| Pubkey::new_unique())
+            .collect(),
+    );
+    let mut context = program_test.start_with_context().await;
+    let payer = context.payer.pubkey();
+    process(
+        &mut context,
+        &[system_instruction::transfer(
+            &payer,
+            &initializer.pubkey(),
+            1_000_000_000,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    // The first escrow fills the book, and the second is created anyway, just
+    // without being listed.
+    let escrows = [Keypair::new(), Keypair::new()];
+    for (escrow, deposit) in escrows.iter().zip(&deposits) {
+        process(
+            &mut context,
+            &[client::initialize_escrow_ix(
+                &initializer.pubkey(),
+                deposit,
+                &receive,
+                &mint_a,
+                &mint_b,
+                &escrow.pubkey(),
+                INITIALIZER_AMOUNT,
+                TAKER_AMOUNT,
+                None,
+                None,
+                None,
+            )],
+            &[&initializer, escrow],
+        )
+        .await
+        .unwrap();
+    }
+    let book = order_book(&mut context, &mint_a, &mint_b).await;
+    assert_eq!(book.escrows.len(), MAX_ORDER_BOOK_ENTRIES);
+    assert!(book.escrows.contains(&escrows[0].pubkey()));
+    assert!(!book.escrows.contains(&escrows[1].pubkey()));
+
+    let account = context
+        .banks_client
+        .get_account(escrows[1].pubkey())
+        .await
+        .unwrap()
+        .unwrap();
+    let unlisted = EscrowAccount::try_deserialize(&mut account.data.as_slice()).unwrap();
+    let result = process(
+        &mut context,
+        &[client::list_escrow_ix(&escrows[1].pubkey(), &unlisted)],
+        &[],
+    )
+    .await;
+    assert_error(result, ErrorCode::OrderBookFull);
+
+    // Once the first escrow is cancelled, anyone can list the second.
+    let account = context
+        .banks_client
+        .get_account(escrows[0].pubkey())
+        .await
+        .unwrap()
+        .unwrap();
+    let listed = EscrowAccount::try_deserialize(&mut account.data.as_slice()).unwrap();
+    process(
+        &mut context,
+        &[client::cancel_escrow_ix(
+            &escrows[0].pubkey(),
+            &listed,
+            &initializer.pubkey(),
+        )],
+        &[&initializer],
+    )
+    .await
+    .unwrap();
+    process(
+        &mut context,
+        &[client::list_escrow_ix(&escrows[1].pubkey(), &unlisted)],
+        &[],
+    )
+    .await
+    .unwrap();
+    let book = order_book(&mut context, &mint_a, &mint_b).await;
+    assert_eq!(book.escrows.len(), MAX_ORDER_BOOK_ENTRIES);
+    assert!(!book.escrows.contains(&escrows[0].pubkey()));
+    assert!(book.escrows.contains(&escrows[1].pubkey()));
+}
+
+// EscrowReclaimed isn't checked by these: BanksClient doesn't expose a
+// transaction's logs on this version of solana-program-test.
+#[tokio::test]
+async fn reclaim_drained_vault() {
+    let mut setup = setup_stale(Some(INITIALIZER_AMOUNT / 5)).await;
+    setup.reclaim().await.unwrap();
+
+    assert!(!account_exists(&mut setup.context, &setup.escrow).await);
+    assert!(!account_exists(&mut setup.context, &setup.vault).await);
+    let refund = token_account(&mut setup.context, &setup.initializer_token_account_a).await;
+    assert_eq!(refund.amount, INITIALIZER_AMOUNT / 5);
+    // Both the escrow's and the vault's rent go back to the initializer.
+    let rent = Rent::default();
+    assert_eq!(
+        setup.initializer_lamports().await,
+        rent.minimum_balance(8 + EscrowAccount::LEN)
+            + rent.minimum_balance(spl_token::state::Account::LEN)
+    );
+    let book = order_book(&mut setup.context, &setup.mint_a, &setup.mint_b).await;
+    assert!(!book.escrows.contains(&setup.escrow));
+}
+
+#[tokio::test]
+async fn reclaim_closed_vault() {
+    let mut setup = setup_stale(None).await;
+    setup.reclaim().await.unwrap();
+
+    assert!(!account_exists(&mut setup.context, &setup.escrow).await);
+    let refund = token_account(&mut setup.context, &setup.initializer_token_account_a).await;
+    assert_eq!(refund.amount, 0);
+    assert_eq!(
+        setup.initializer_lamports().await,
+        Rent::default().minimum_balance(8 + EscrowAccount::LEN)
+    );
+    let book = order_book(&mut setup.context, &setup.mint_a, &setup.mint_b).await;
+    assert!(book.escrows.is_empty());
+}
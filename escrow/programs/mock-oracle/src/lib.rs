@@ -0,0 +1,72 @@
+//! A stand-in price oracle for testing oracle-priced escrows locally. Whoever
+//! creates a PriceFeed can set its price (and publish time, to test staleness)
+//! to anything. The escrow program reads PriceFeed accounts in this layout (see
+//! its oracle module), so keep the two in sync.
+
+use anchor_lang::prelude::*;
+
+declare_id!("HavNFwmTZXCiytNo7Vf7stjESAndnWWvNG4nG2Yu7xT2");
+
+#[program]
+pub mod mock_oracle {
+    use super::*;
+
+    pub fn initialize(
+        ctx: Context<Initialize>,
+        price: u64,
+        expo: i32,
+        publish_time: i64,
+    ) -> ProgramResult {
+        let price_feed = &mut ctx.accounts.price_feed;
+        price_feed.authority = *ctx.accounts.authority.key;
+        price_feed.price = price;
+        price_feed.expo = expo;
+        price_feed.publish_time = publish_time;
+        Ok(())
+    }
+
+    pub fn set_price(
+        ctx: Context<SetPrice>,
+        price: u64,
+        expo: i32,
+        publish_time: i64,
+    ) -> ProgramResult {
+        let price_feed = &mut ctx.accounts.price_feed;
+        price_feed.price = price;
+        price_feed.expo = expo;
+        price_feed.publish_time = publish_time;
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct Initialize<'info> {
+    // Pays for the feed, and is the only one who can set its price.
+    #[account(signer, mut)]
+    pub authority: AccountInfo<'info>,
+    #[account(init, payer = authority, space = 8 + PriceFeed::LEN)]
+    pub price_feed: Account<'info, PriceFeed>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetPrice<'info> {
+    #[account(signer)]
+    pub authority: AccountInfo<'info>,
+    #[account(mut, has_one = authority)]
+    pub price_feed: Account<'info, PriceFeed>,
+}
+
+// One base unit of the priced token is worth price * 10^expo base units of the
+// quote token, as of publish_time.
+#[account]
+pub struct PriceFeed {
+    pub authority: Pubkey,
+    pub price: u64,
+    pub expo: i32,
+    pub publish_time: i64,
+}
+
+impl PriceFeed {
+    pub const LEN: usize = 32 + 8 + 4 + 8;
+}
@@ -2,19 +2,30 @@ import * as anchor from "@project-serum/anchor";
 
 import {
 	Keypair,
+	LAMPORTS_PER_SOL,
 	PublicKey,
 	SYSVAR_RENT_PUBKEY,
 	SystemProgram,
 } from '@solana/web3.js';
-import { TOKEN_PROGRAM_ID, Token } from "@solana/spl-token";
+import {
+  ASSOCIATED_TOKEN_PROGRAM_ID,
+  NATIVE_MINT,
+  TOKEN_PROGRAM_ID,
+  Token,
+} from "@solana/spl-token";
 
 import { assert } from "chai";
 
+const BPF_LOADER_UPGRADEABLE_ID = new PublicKey(
+  "BPFLoaderUpgradeab1e11111111111111111111111"
+);
+
 describe("escrow", () => {
   const provider = anchor.Provider.env();
   anchor.setProvider(provider);
 
   const program = anchor.workspace.Escrow as anchor.Program<any>;
+  const mockOracle = anchor.workspace.MockOracle as anchor.Program<any>;
 
   let mintA: Token = null;
   let mintB: Token = null;
@@ -23,13 +34,22 @@ describe("escrow", () => {
   let takerTokenAccountA: PublicKey = null;
   let takerTokenAccountB: PublicKey = null;
   let pda: PublicKey = null;
+  let bump: number = null;
+  let config: PublicKey = null;
+  let orderBook: PublicKey = null;
+  let feeTokenAccountA: PublicKey = null;
+  let feeTokenAccountB: PublicKey = null;
 
   const takerAmount = 1000;
   const initializerAmount = 500;
+  // The highest fee the program allows, so exchanges accept whatever the
+  // config charges.
+  const maxFeeBps = 500;
 
   const escrowAccount = Keypair.generate();
   const payer = Keypair.generate();
   const mintAuthority = Keypair.generate();
+  const feeRecipient = Keypair.generate();
 
   it("Initialise escrow state", async () => {
     // Airdropping tokens to a payer.
@@ -95,18 +115,62 @@ describe("escrow", () => {
 
     assert.ok(_initializerTokenAccountA.amount.toNumber() == initializerAmount);
     assert.ok(_takerTokenAccountB.amount.toNumber() == takerAmount);
+
+    // Creates the global config, with no fees to begin with. Fees are paid
+    // into token accounts owned by feeRecipient.
+    feeTokenAccountA = await mintA.createAccount(feeRecipient.publicKey);
+    feeTokenAccountB = await mintB.createAccount(feeRecipient.publicKey);
+    let configBump: number;
+    [config, configBump] = await PublicKey.findProgramAddress(
+      [Buffer.from(anchor.utils.bytes.utf8.encode("config"))],
+      program.programId
+    );
+    // Only the upgrade authority can create the config, which the program
+    // checks against its ProgramData account.
+    const [programData] = await PublicKey.findProgramAddress(
+      [program.programId.toBuffer()],
+      BPF_LOADER_UPGRADEABLE_ID
+    );
+    await program.rpc.initializeConfig(0, 0, feeRecipient.publicKey, configBump, {
+      accounts: {
+        config,
+        admin: provider.wallet.publicKey,
+        escrowProgram: program.programId,
+        programData,
+        systemProgram: SystemProgram.programId,
+      },
+    });
+
+    // Every escrow in these tests offers mintA for mintB, unless noted otherwise.
+    orderBook = await initializeOrderBook(mintA.publicKey, mintB.publicKey);
   });
 
   it("Initialize escrow", async () => {
+    // Get the PDA that is assigned authority to token account. Each escrow
+    // has its own PDA, seeded by the escrow account's key.
+    [pda, bump] = await PublicKey.findProgramAddress(
+      [
+        Buffer.from(anchor.utils.bytes.utf8.encode("escrow")),
+        escrowAccount.publicKey.toBuffer(),
+      ],
+      program.programId
+    );
+
     await program.rpc.initializeEscrow(
       new anchor.BN(initializerAmount),
       new anchor.BN(takerAmount),
+      bump,
+      null,
+      null,
+      null,
       {
         accounts: {
           initializer: provider.wallet.publicKey,
           initializerDepositTokenAccount: initializerTokenAccountA,
           initializerReceiveTokenAccount: initializerTokenAccountB,
           escrowAccount: escrowAccount.publicKey,
+          orderBook,
+          pdaAccount: pda,
           systemProgram: SystemProgram.programId,
           tokenProgram: TOKEN_PROGRAM_ID,
           rent: SYSVAR_RENT_PUBKEY,
@@ -115,14 +179,6 @@ describe("escrow", () => {
       }
     );
 
-    // Get the PDA that is assigned authority to token account.
-    const [_pda, _nonce] = await PublicKey.findProgramAddress(
-      [Buffer.from(anchor.utils.bytes.utf8.encode("escrow"))],
-      program.programId
-    );
-
-    pda = _pda;
-
     let _initializerTokenAccountA = await mintA.getAccountInfo(
       initializerTokenAccountA
     );
@@ -138,6 +194,9 @@ describe("escrow", () => {
     assert.ok(_escrowAccount.initializerKey.equals(provider.wallet.publicKey));
     assert.ok(_escrowAccount.initializerAmount.toNumber() == initializerAmount);
     assert.ok(_escrowAccount.takerAmount.toNumber() == takerAmount);
+    assert.ok(_escrowAccount.bump == bump);
+    assert.ok(_escrowAccount.offeredMint.equals(mintA.publicKey));
+    assert.ok(_escrowAccount.requestedMint.equals(mintB.publicKey));
     assert.ok(
       _escrowAccount.initializerDepositTokenAccount.equals(
         initializerTokenAccountA
@@ -148,22 +207,90 @@ describe("escrow", () => {
         initializerTokenAccountB
       )
     );
+
+    // The escrow is listed in the order book for its mint pair.
+    let _orderBook = await program.account.orderBook.fetch(orderBook);
+    assert.ok(_orderBook.offeredMint.equals(mintA.publicKey));
+    assert.ok(_orderBook.requestedMint.equals(mintB.publicKey));
+    assert.ok(_orderBook.escrows.length == 1);
+    assert.ok(_orderBook.escrows[0].equals(escrowAccount.publicKey));
+  });
+
+  it("Exchange rejects a deposit of the wrong mint", async () => {
+    // A token with the same decimals as mintB, but not what the initializer asked for.
+    const mintC = await Token.createMint(
+      provider.connection,
+      payer,
+      mintAuthority.publicKey,
+      null,
+      0,
+      TOKEN_PROGRAM_ID
+    );
+    const takerTokenAccountC = await mintC.createAccount(
+      provider.wallet.publicKey
+    );
+    await mintC.mintTo(
+      takerTokenAccountC,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      takerAmount
+    );
+
+    try {
+      await program.rpc.exchange(
+        new anchor.BN(initializerAmount),
+        new anchor.BN(takerAmount),
+        maxFeeBps,
+        {
+          accounts: {
+            taker: provider.wallet.publicKey,
+            takerDepositTokenAccount: takerTokenAccountC,
+            takerReceiveTokenAccount: takerTokenAccountA,
+            pdaDepositTokenAccount: initializerTokenAccountA,
+            initializerReceiveTokenAccount: initializerTokenAccountB,
+            initializerMainAccount: provider.wallet.publicKey,
+            escrowAccount: escrowAccount.publicKey,
+            orderBook,
+            pdaAccount: pda,
+            config,
+            offeredFeeTokenAccount: feeTokenAccountA,
+            requestedFeeTokenAccount: feeTokenAccountB,
+            tokenProgram: TOKEN_PROGRAM_ID,
+          },
+        }
+      );
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(
+        err.msg,
+        "The taker's deposit token account does not hold the requested mint."
+      );
+    }
   });
 
   it("Exchange escrow", async () => {
-    await program.rpc.exchange({
-      accounts: {
-        taker: provider.wallet.publicKey,
-        takerDepositTokenAccount: takerTokenAccountB,
-        takerReceiveTokenAccount: takerTokenAccountA,
-        pdaDepositTokenAccount: initializerTokenAccountA,
-        initializerReceiveTokenAccount: initializerTokenAccountB,
-        initializerMainAccount: provider.wallet.publicKey,
-        escrowAccount: escrowAccount.publicKey,
-        pdaAccount: pda,
-        tokenProgram: TOKEN_PROGRAM_ID,
-      },
-    });
+    await program.rpc.exchange(
+      new anchor.BN(initializerAmount),
+      new anchor.BN(takerAmount),
+      maxFeeBps,
+      {
+        accounts: {
+          taker: provider.wallet.publicKey,
+          takerDepositTokenAccount: takerTokenAccountB,
+          takerReceiveTokenAccount: takerTokenAccountA,
+          pdaDepositTokenAccount: initializerTokenAccountA,
+          initializerReceiveTokenAccount: initializerTokenAccountB,
+          initializerMainAccount: provider.wallet.publicKey,
+          escrowAccount: escrowAccount.publicKey,
+          orderBook,
+          pdaAccount: pda,
+          config,
+          offeredFeeTokenAccount: feeTokenAccountA,
+          requestedFeeTokenAccount: feeTokenAccountB,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        },
+      }
+    );
 
     let _takerTokenAccountA = await mintA.getAccountInfo(takerTokenAccountA);
     let _takerTokenAccountB = await mintB.getAccountInfo(takerTokenAccountB);
@@ -181,9 +308,14 @@ describe("escrow", () => {
     assert.ok(_initializerTokenAccountA.amount.toNumber() == 0);
     assert.ok(_initializerTokenAccountB.amount.toNumber() == takerAmount);
     assert.ok(_takerTokenAccountB.amount.toNumber() == 0);
+
+    // Exchanged escrows are taken off the order book.
+    let _orderBook = await program.account.orderBook.fetch(orderBook);
+    assert.ok(_orderBook.escrows.length == 0);
   });
 
   let newEscrow = Keypair.generate();
+  let newPda: PublicKey = null;
 
   it("Initialize escrow and cancel escrow", async () => {
     // Put back tokens into initializer token A account.
@@ -194,15 +326,30 @@ describe("escrow", () => {
       initializerAmount
     );
 
+    let newBump: number;
+    [newPda, newBump] = await PublicKey.findProgramAddress(
+      [
+        Buffer.from(anchor.utils.bytes.utf8.encode("escrow")),
+        newEscrow.publicKey.toBuffer(),
+      ],
+      program.programId
+    );
+
     await program.rpc.initializeEscrow(
       new anchor.BN(initializerAmount),
       new anchor.BN(takerAmount),
+      newBump,
+      null,
+      null,
+      null,
       {
         accounts: {
           initializer: provider.wallet.publicKey,
           initializerDepositTokenAccount: initializerTokenAccountA,
           initializerReceiveTokenAccount: initializerTokenAccountB,
           escrowAccount: newEscrow.publicKey,
+          orderBook,
+          pdaAccount: newPda,
           systemProgram: SystemProgram.programId,
           tokenProgram: TOKEN_PROGRAM_ID,
           rent: SYSVAR_RENT_PUBKEY,
@@ -215,16 +362,20 @@ describe("escrow", () => {
       initializerTokenAccountA
     );
 
-    // Check that the new owner is the PDA.
-    assert.ok(_initializerTokenAccountA.owner.equals(pda));
+    // Check that the new owner is this escrow's PDA, not the first escrow's.
+    assert.ok(_initializerTokenAccountA.owner.equals(newPda));
+    assert.ok(!newPda.equals(pda));
 
     // Cancel the escrow.
     await program.rpc.cancelEscrow({
       accounts: {
+        authority: provider.wallet.publicKey,
         initializer: provider.wallet.publicKey,
         pdaDepositTokenAccount: initializerTokenAccountA,
-        pdaAccount: pda,
+        initializerDepositTokenAccount: initializerTokenAccountA,
+        pdaAccount: newPda,
         escrowAccount: newEscrow.publicKey,
+        orderBook,
         tokenProgram: TOKEN_PROGRAM_ID,
       },
     });
@@ -240,4 +391,2723 @@ describe("escrow", () => {
     // Check all the funds are still there.
     assert.ok(_initializerTokenAccountA.amount.toNumber() == initializerAmount);
   });
+
+  // Derives the PDA authority and the vault address for an escrow created with
+  // initializeEscrowWithVault.
+  async function initializeOrderBook(
+    offeredMint: PublicKey,
+    requestedMint: PublicKey
+  ) {
+    const [orderBook, orderBookBump] = await PublicKey.findProgramAddress(
+      [
+        Buffer.from(anchor.utils.bytes.utf8.encode("order-book")),
+        offeredMint.toBuffer(),
+        requestedMint.toBuffer(),
+      ],
+      program.programId
+    );
+    await program.rpc.initializeOrderBook(orderBookBump, {
+      accounts: {
+        payer: provider.wallet.publicKey,
+        offeredMint,
+        requestedMint,
+        orderBook,
+        systemProgram: SystemProgram.programId,
+      },
+    });
+    return orderBook;
+  }
+
+  // Returns the events emitted by the given transaction, parsed from its logs.
+  async function parseEvents(txSignature: string) {
+    await provider.connection.confirmTransaction(txSignature, "confirmed");
+    const tx = await provider.connection.getTransaction(txSignature, {
+      commitment: "confirmed",
+    });
+    const events = [];
+    new anchor.EventParser(program.programId, program.coder).parseLogs(
+      tx.meta.logMessages,
+      (event) => events.push(event)
+    );
+    return events;
+  }
+
+  async function findVaultAddresses(escrow: PublicKey) {
+    const [vaultPda, vaultPdaBump] = await PublicKey.findProgramAddress(
+      [Buffer.from(anchor.utils.bytes.utf8.encode("escrow")), escrow.toBuffer()],
+      program.programId
+    );
+    const [vault, vaultBump] = await PublicKey.findProgramAddress(
+      [Buffer.from(anchor.utils.bytes.utf8.encode("vault")), escrow.toBuffer()],
+      program.programId
+    );
+    return { vaultPda, vaultPdaBump, vault, vaultBump };
+  }
+
+  async function initializeEscrowWithVault(escrow: Keypair) {
+    const addresses = await findVaultAddresses(escrow.publicKey);
+    await program.rpc.initializeEscrowWithVault(
+      new anchor.BN(initializerAmount),
+      new anchor.BN(takerAmount),
+      addresses.vaultPdaBump,
+      addresses.vaultBump,
+      null,
+      null,
+      null,
+      {
+        accounts: {
+          initializer: provider.wallet.publicKey,
+          initializerDepositTokenAccount: initializerTokenAccountA,
+          initializerReceiveTokenAccount: initializerTokenAccountB,
+          mint: mintA.publicKey,
+          escrowAccount: escrow.publicKey,
+          orderBook,
+          pdaAccount: addresses.vaultPda,
+          vault: addresses.vault,
+          systemProgram: SystemProgram.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          rent: SYSVAR_RENT_PUBKEY,
+        },
+        signers: [escrow],
+      }
+    );
+    return addresses;
+  }
+
+  it("Initialize escrow with vault and exchange", async () => {
+    // Give the taker mintB tokens again, the first exchange used them up.
+    await mintB.mintTo(
+      takerTokenAccountB,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      takerAmount
+    );
+
+    const vaultEscrow = Keypair.generate();
+    const { vaultPda, vault } = await initializeEscrowWithVault(vaultEscrow);
+
+    // The initializer keeps their token account, the tokens move to the vault.
+    let _initializerTokenAccountA = await mintA.getAccountInfo(
+      initializerTokenAccountA
+    );
+    let _vault = await mintA.getAccountInfo(vault);
+    assert.ok(
+      _initializerTokenAccountA.owner.equals(provider.wallet.publicKey)
+    );
+    assert.ok(_initializerTokenAccountA.amount.toNumber() == 0);
+    assert.ok(_vault.owner.equals(vaultPda));
+    assert.ok(_vault.amount.toNumber() == initializerAmount);
+
+    await program.rpc.exchange(
+      new anchor.BN(initializerAmount),
+      new anchor.BN(takerAmount),
+      maxFeeBps,
+      {
+        accounts: {
+          taker: provider.wallet.publicKey,
+          takerDepositTokenAccount: takerTokenAccountB,
+          takerReceiveTokenAccount: takerTokenAccountA,
+          pdaDepositTokenAccount: vault,
+          initializerReceiveTokenAccount: initializerTokenAccountB,
+          initializerMainAccount: provider.wallet.publicKey,
+          escrowAccount: vaultEscrow.publicKey,
+          orderBook,
+          pdaAccount: vaultPda,
+          config,
+          offeredFeeTokenAccount: feeTokenAccountA,
+          requestedFeeTokenAccount: feeTokenAccountB,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        },
+      }
+    );
+
+    let _takerTokenAccountA = await mintA.getAccountInfo(takerTokenAccountA);
+    let _initializerTokenAccountB = await mintB.getAccountInfo(
+      initializerTokenAccountB
+    );
+    assert.ok(_takerTokenAccountA.amount.toNumber() == 2 * initializerAmount);
+    assert.ok(_initializerTokenAccountB.amount.toNumber() == 2 * takerAmount);
+
+    // The vault gets closed once the trade is done.
+    assert.ok((await provider.connection.getAccountInfo(vault)) == null);
+  });
+
+  it("Initialize escrow with vault and cancel escrow", async () => {
+    await mintA.mintTo(
+      initializerTokenAccountA,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      initializerAmount
+    );
+
+    const vaultEscrow = Keypair.generate();
+    const { vaultPda, vault } = await initializeEscrowWithVault(vaultEscrow);
+
+    await program.rpc.cancelEscrow({
+      accounts: {
+        authority: provider.wallet.publicKey,
+        initializer: provider.wallet.publicKey,
+        pdaDepositTokenAccount: vault,
+        initializerDepositTokenAccount: initializerTokenAccountA,
+        pdaAccount: vaultPda,
+        escrowAccount: vaultEscrow.publicKey,
+        orderBook,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      },
+    });
+
+    // The deposit goes back to the initializer and the vault is closed.
+    let _initializerTokenAccountA = await mintA.getAccountInfo(
+      initializerTokenAccountA
+    );
+    assert.ok(_initializerTokenAccountA.amount.toNumber() == initializerAmount);
+    assert.ok((await provider.connection.getAccountInfo(vault)) == null);
+  });
+
+  it("Partially fill escrow", async () => {
+    await mintB.mintTo(
+      takerTokenAccountB,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      takerAmount
+    );
+
+    const partialEscrow = Keypair.generate();
+    const [partialPda, partialBump] = await PublicKey.findProgramAddress(
+      [
+        Buffer.from(anchor.utils.bytes.utf8.encode("escrow")),
+        partialEscrow.publicKey.toBuffer(),
+      ],
+      program.programId
+    );
+    await program.rpc.initializeEscrow(
+      new anchor.BN(initializerAmount),
+      new anchor.BN(takerAmount),
+      partialBump,
+      null,
+      null,
+      null,
+      {
+        accounts: {
+          initializer: provider.wallet.publicKey,
+          initializerDepositTokenAccount: initializerTokenAccountA,
+          initializerReceiveTokenAccount: initializerTokenAccountB,
+          escrowAccount: partialEscrow.publicKey,
+          orderBook,
+          pdaAccount: partialPda,
+          systemProgram: SystemProgram.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          rent: SYSVAR_RENT_PUBKEY,
+        },
+        signers: [partialEscrow],
+      }
+    );
+
+    const accounts = {
+      taker: provider.wallet.publicKey,
+      takerDepositTokenAccount: takerTokenAccountB,
+      takerReceiveTokenAccount: takerTokenAccountA,
+      pdaDepositTokenAccount: initializerTokenAccountA,
+      initializerReceiveTokenAccount: initializerTokenAccountB,
+      initializerMainAccount: provider.wallet.publicKey,
+      escrowAccount: partialEscrow.publicKey,
+      orderBook,
+      pdaAccount: partialPda,
+      config,
+      offeredFeeTokenAccount: feeTokenAccountA,
+      requestedFeeTokenAccount: feeTokenAccountB,
+      tokenProgram: TOKEN_PROGRAM_ID,
+    };
+    const takerABefore = (
+      await mintA.getAccountInfo(takerTokenAccountA)
+    ).amount.toNumber();
+
+    // 300 of 1000 at a price of 500 A for 1000 B buys 150 A.
+    await program.rpc.exchangePartial(
+      new anchor.BN(300),
+      new anchor.BN(initializerAmount),
+      new anchor.BN(takerAmount),
+      maxFeeBps,
+      { accounts }
+    );
+    let _escrowAccount = await program.account.escrowAccount.fetch(
+      partialEscrow.publicKey
+    );
+    assert.ok(_escrowAccount.initializerAmount.toNumber() == 350);
+    assert.ok(_escrowAccount.takerAmount.toNumber() == 700);
+
+    // The taker must expect what's left, not the escrow's original terms.
+    try {
+      await program.rpc.exchangePartial(
+        new anchor.BN(333),
+        new anchor.BN(initializerAmount),
+        new anchor.BN(takerAmount),
+        maxFeeBps,
+        { accounts }
+      );
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(
+        err.msg,
+        "The escrow's terms don't match what the taker expected."
+      );
+    }
+
+    // 333 * 350 / 700 = 166.5, which rounds down in the initializer's favour.
+    await program.rpc.exchangePartial(
+      new anchor.BN(333),
+      new anchor.BN(350),
+      new anchor.BN(700),
+      maxFeeBps,
+      { accounts }
+    );
+    _escrowAccount = await program.account.escrowAccount.fetch(
+      partialEscrow.publicKey
+    );
+    assert.ok(_escrowAccount.initializerAmount.toNumber() == 184);
+    assert.ok(_escrowAccount.takerAmount.toNumber() == 367);
+    // Partially filled escrows stay on the order book.
+    assert.ok(
+      (await program.account.orderBook.fetch(orderBook)).escrows.some((key) =>
+        key.equals(partialEscrow.publicKey)
+      )
+    );
+
+    // The terms have changed since the escrow was created, so a taker still
+    // expecting the original amounts is turned away.
+    try {
+      await program.rpc.exchange(
+        new anchor.BN(initializerAmount),
+        new anchor.BN(takerAmount),
+        maxFeeBps,
+        { accounts }
+      );
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(
+        err.msg,
+        "The escrow's terms don't match what the taker expected."
+      );
+    }
+
+    // Filling the rest takes everything that's left and closes the escrow.
+    await program.rpc.exchange(
+      new anchor.BN(184),
+      new anchor.BN(367),
+      maxFeeBps,
+      {
+        accounts,
+      }
+    );
+
+    let _takerTokenAccountA = await mintA.getAccountInfo(takerTokenAccountA);
+    let _initializerTokenAccountA = await mintA.getAccountInfo(
+      initializerTokenAccountA
+    );
+    assert.ok(
+      _takerTokenAccountA.amount.toNumber() == takerABefore + initializerAmount
+    );
+    assert.ok(_initializerTokenAccountA.amount.toNumber() == 0);
+    assert.ok(
+      _initializerTokenAccountA.owner.equals(provider.wallet.publicKey)
+    );
+    assert.ok(
+      (await provider.connection.getAccountInfo(partialEscrow.publicKey)) ==
+        null
+    );
+  });
+
+  it("Crank an expired escrow", async () => {
+    await mintA.mintTo(
+      initializerTokenAccountA,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      initializerAmount
+    );
+
+    // Expire the escrow a couple of seconds from now, by the cluster's clock.
+    const now = await provider.connection.getBlockTime(
+      await provider.connection.getSlot()
+    );
+    const expiresAt = now + 2;
+
+    const expiringEscrow = Keypair.generate();
+    const [expiringPda, expiringBump] = await PublicKey.findProgramAddress(
+      [
+        Buffer.from(anchor.utils.bytes.utf8.encode("escrow")),
+        expiringEscrow.publicKey.toBuffer(),
+      ],
+      program.programId
+    );
+    await program.rpc.initializeEscrow(
+      new anchor.BN(initializerAmount),
+      new anchor.BN(takerAmount),
+      expiringBump,
+      new anchor.BN(expiresAt),
+      null,
+      null,
+      {
+        accounts: {
+          initializer: provider.wallet.publicKey,
+          initializerDepositTokenAccount: initializerTokenAccountA,
+          initializerReceiveTokenAccount: initializerTokenAccountB,
+          escrowAccount: expiringEscrow.publicKey,
+          orderBook,
+          pdaAccount: expiringPda,
+          systemProgram: SystemProgram.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          rent: SYSVAR_RENT_PUBKEY,
+        },
+        signers: [expiringEscrow],
+      }
+    );
+
+    let _escrowAccount = await program.account.escrowAccount.fetch(
+      expiringEscrow.publicKey
+    );
+    assert.ok(_escrowAccount.expiresAt.toNumber() == expiresAt);
+
+    // Wait for the cluster's clock to pass the expiry.
+    while (
+      (await provider.connection.getBlockTime(
+        await provider.connection.getSlot()
+      )) <= expiresAt
+    ) {
+      await new Promise((resolve) => setTimeout(resolve, 500));
+    }
+
+    try {
+      await program.rpc.exchange(
+        new anchor.BN(initializerAmount),
+        new anchor.BN(takerAmount),
+        maxFeeBps,
+        {
+          accounts: {
+            taker: provider.wallet.publicKey,
+            takerDepositTokenAccount: takerTokenAccountB,
+            takerReceiveTokenAccount: takerTokenAccountA,
+            pdaDepositTokenAccount: initializerTokenAccountA,
+            initializerReceiveTokenAccount: initializerTokenAccountB,
+            initializerMainAccount: provider.wallet.publicKey,
+            escrowAccount: expiringEscrow.publicKey,
+            orderBook,
+            pdaAccount: expiringPda,
+            config,
+            offeredFeeTokenAccount: feeTokenAccountA,
+            requestedFeeTokenAccount: feeTokenAccountB,
+            tokenProgram: TOKEN_PROGRAM_ID,
+          },
+        }
+      );
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(err.msg, "The escrow has expired.");
+    }
+
+    // crankExpired doesn't need the initializer's signature.
+    await program.rpc.crankExpired({
+      accounts: {
+        initializer: provider.wallet.publicKey,
+        pdaDepositTokenAccount: initializerTokenAccountA,
+        initializerDepositTokenAccount: initializerTokenAccountA,
+        pdaAccount: expiringPda,
+        escrowAccount: expiringEscrow.publicKey,
+        orderBook,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      },
+    });
+
+    let _initializerTokenAccountA = await mintA.getAccountInfo(
+      initializerTokenAccountA
+    );
+    assert.ok(
+      _initializerTokenAccountA.owner.equals(provider.wallet.publicKey)
+    );
+    assert.ok(_initializerTokenAccountA.amount.toNumber() == initializerAmount);
+    assert.ok(
+      (await provider.connection.getAccountInfo(expiringEscrow.publicKey)) ==
+        null
+    );
+  });
+
+  it("Only the designated taker can take a private escrow", async () => {
+    await mintB.mintTo(
+      takerTokenAccountB,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      takerAmount
+    );
+
+    const privateEscrow = Keypair.generate();
+    const [privatePda, privateBump] = await PublicKey.findProgramAddress(
+      [
+        Buffer.from(anchor.utils.bytes.utf8.encode("escrow")),
+        privateEscrow.publicKey.toBuffer(),
+      ],
+      program.programId
+    );
+    await program.rpc.initializeEscrow(
+      new anchor.BN(initializerAmount),
+      new anchor.BN(takerAmount),
+      privateBump,
+      null,
+      provider.wallet.publicKey,
+      null,
+      {
+        accounts: {
+          initializer: provider.wallet.publicKey,
+          initializerDepositTokenAccount: initializerTokenAccountA,
+          initializerReceiveTokenAccount: initializerTokenAccountB,
+          escrowAccount: privateEscrow.publicKey,
+          orderBook,
+          pdaAccount: privatePda,
+          systemProgram: SystemProgram.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          rent: SYSVAR_RENT_PUBKEY,
+        },
+        signers: [privateEscrow],
+      }
+    );
+
+    let _escrowAccount = await program.account.escrowAccount.fetch(
+      privateEscrow.publicKey
+    );
+    assert.ok(_escrowAccount.allowedTaker.equals(provider.wallet.publicKey));
+
+    const accounts = {
+      taker: provider.wallet.publicKey,
+      takerDepositTokenAccount: takerTokenAccountB,
+      takerReceiveTokenAccount: takerTokenAccountA,
+      pdaDepositTokenAccount: initializerTokenAccountA,
+      initializerReceiveTokenAccount: initializerTokenAccountB,
+      initializerMainAccount: provider.wallet.publicKey,
+      escrowAccount: privateEscrow.publicKey,
+      orderBook,
+      pdaAccount: privatePda,
+      config,
+      offeredFeeTokenAccount: feeTokenAccountA,
+      requestedFeeTokenAccount: feeTokenAccountB,
+      tokenProgram: TOKEN_PROGRAM_ID,
+    };
+
+    // Anyone else is rejected.
+    const stranger = Keypair.generate();
+    try {
+      await program.rpc.exchange(
+        new anchor.BN(initializerAmount),
+        new anchor.BN(takerAmount),
+        maxFeeBps,
+        {
+          accounts: { ...accounts, taker: stranger.publicKey },
+          signers: [stranger],
+        }
+      );
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(
+        err.msg,
+        "This escrow can only be taken by its designated taker."
+      );
+    }
+
+    // The designated taker goes through as usual.
+    await program.rpc.exchange(
+      new anchor.BN(initializerAmount),
+      new anchor.BN(takerAmount),
+      maxFeeBps,
+      { accounts }
+    );
+    assert.ok(
+      (await provider.connection.getAccountInfo(privateEscrow.publicKey)) ==
+        null
+    );
+  });
+
+  it("Only the initializer or their canceller can cancel escrow", async () => {
+    await mintA.mintTo(
+      initializerTokenAccountA,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      initializerAmount
+    );
+
+    const cancelledEscrow = Keypair.generate();
+    const [cancelledPda, cancelledBump] = await PublicKey.findProgramAddress(
+      [
+        Buffer.from(anchor.utils.bytes.utf8.encode("escrow")),
+        cancelledEscrow.publicKey.toBuffer(),
+      ],
+      program.programId
+    );
+    await program.rpc.initializeEscrow(
+      new anchor.BN(initializerAmount),
+      new anchor.BN(takerAmount),
+      cancelledBump,
+      null,
+      null,
+      null,
+      {
+        accounts: {
+          initializer: provider.wallet.publicKey,
+          initializerDepositTokenAccount: initializerTokenAccountA,
+          initializerReceiveTokenAccount: initializerTokenAccountB,
+          escrowAccount: cancelledEscrow.publicKey,
+          orderBook,
+          pdaAccount: cancelledPda,
+          systemProgram: SystemProgram.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          rent: SYSVAR_RENT_PUBKEY,
+        },
+        signers: [cancelledEscrow],
+      }
+    );
+
+    const accounts = {
+      authority: provider.wallet.publicKey,
+      initializer: provider.wallet.publicKey,
+      pdaDepositTokenAccount: initializerTokenAccountA,
+      initializerDepositTokenAccount: initializerTokenAccountA,
+      pdaAccount: cancelledPda,
+      escrowAccount: cancelledEscrow.publicKey,
+      orderBook,
+      tokenProgram: TOKEN_PROGRAM_ID,
+    };
+
+    // A third party can't cancel someone else's escrow.
+    const stranger = Keypair.generate();
+    try {
+      await program.rpc.cancelEscrow({
+        accounts: { ...accounts, authority: stranger.publicKey },
+        signers: [stranger],
+      });
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(
+        err.msg,
+        "Only the initializer or their delegated canceller can cancel this escrow."
+      );
+    }
+
+    let _initializerTokenAccountA = await mintA.getAccountInfo(
+      initializerTokenAccountA
+    );
+    assert.ok(_initializerTokenAccountA.owner.equals(cancelledPda));
+
+    // Once the initializer delegates to a canceller, the canceller can cancel.
+    const canceller = Keypair.generate();
+    await program.rpc.setCanceller(canceller.publicKey, {
+      accounts: {
+        initializer: provider.wallet.publicKey,
+        escrowAccount: cancelledEscrow.publicKey,
+      },
+    });
+    await program.rpc.cancelEscrow({
+      accounts: { ...accounts, authority: canceller.publicKey },
+      signers: [canceller],
+    });
+
+    _initializerTokenAccountA = await mintA.getAccountInfo(
+      initializerTokenAccountA
+    );
+    assert.ok(
+      _initializerTokenAccountA.owner.equals(provider.wallet.publicKey)
+    );
+    assert.ok(_initializerTokenAccountA.amount.toNumber() == initializerAmount);
+  });
+
+  // Takes the SOL side of the native escrows below.
+  const solTaker = Keypair.generate();
+  const solAmount = LAMPORTS_PER_SOL / 2;
+
+  it("Offer SOL in exchange for tokens", async () => {
+    const solTakerTokenAccountB = await mintB.createAccount(
+      solTaker.publicKey
+    );
+    await mintB.mintTo(
+      solTakerTokenAccountB,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      takerAmount
+    );
+
+    // Native SOL legs are listed under the native mint.
+    const solOfferOrderBook = await initializeOrderBook(
+      NATIVE_MINT,
+      mintB.publicKey
+    );
+    const solEscrow = Keypair.generate();
+    await program.rpc.initializeEscrowOfferingSol(
+      new anchor.BN(solAmount),
+      new anchor.BN(takerAmount),
+      null,
+      null,
+      {
+        accounts: {
+          initializer: provider.wallet.publicKey,
+          initializerReceiveTokenAccount: initializerTokenAccountB,
+          escrowAccount: solEscrow.publicKey,
+          orderBook: solOfferOrderBook,
+          systemProgram: SystemProgram.programId,
+        },
+        signers: [solEscrow],
+      }
+    );
+
+    // The escrow account holds the offered lamports on top of its rent.
+    let _escrowAccount = await program.account.escrowAccount.fetch(
+      solEscrow.publicKey
+    );
+    assert.ok(_escrowAccount.nativeLeg.offered !== undefined);
+    assert.ok(
+      (await provider.connection.getBalance(solEscrow.publicKey)) > solAmount
+    );
+
+    const initializerBBefore = (
+      await mintB.getAccountInfo(initializerTokenAccountB)
+    ).amount.toNumber();
+
+    await program.rpc.exchangeSolOffer(maxFeeBps, {
+      accounts: {
+        taker: solTaker.publicKey,
+        takerDepositTokenAccount: solTakerTokenAccountB,
+        initializerReceiveTokenAccount: initializerTokenAccountB,
+        initializerMainAccount: provider.wallet.publicKey,
+        escrowAccount: solEscrow.publicKey,
+        orderBook: solOfferOrderBook,
+        config,
+        feeRecipient: feeRecipient.publicKey,
+        requestedFeeTokenAccount: feeTokenAccountB,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      },
+      signers: [solTaker],
+    });
+
+    let _initializerTokenAccountB = await mintB.getAccountInfo(
+      initializerTokenAccountB
+    );
+    assert.ok(
+      _initializerTokenAccountB.amount.toNumber() ==
+        initializerBBefore + takerAmount
+    );
+    assert.ok(
+      (await provider.connection.getBalance(solTaker.publicKey)) == solAmount
+    );
+    assert.ok(
+      (await provider.connection.getAccountInfo(solEscrow.publicKey)) == null
+    );
+  });
+
+  it("Request SOL in exchange for tokens", async () => {
+    const solTakerTokenAccountA = await mintA.createAccount(
+      solTaker.publicKey
+    );
+
+    const solRequestOrderBook = await initializeOrderBook(
+      mintA.publicKey,
+      NATIVE_MINT
+    );
+    const solEscrow = Keypair.generate();
+    const { vaultPda, vaultPdaBump, vault, vaultBump } =
+      await findVaultAddresses(solEscrow.publicKey);
+
+    // Half of what the taker received for the offer above.
+    const price = solAmount / 2;
+    await program.rpc.initializeEscrowRequestingSol(
+      new anchor.BN(initializerAmount),
+      new anchor.BN(price),
+      vaultPdaBump,
+      vaultBump,
+      null,
+      null,
+      {
+        accounts: {
+          initializer: provider.wallet.publicKey,
+          initializerDepositTokenAccount: initializerTokenAccountA,
+          mint: mintA.publicKey,
+          escrowAccount: solEscrow.publicKey,
+          orderBook: solRequestOrderBook,
+          pdaAccount: vaultPda,
+          vault,
+          systemProgram: SystemProgram.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          rent: SYSVAR_RENT_PUBKEY,
+        },
+        signers: [solEscrow],
+      }
+    );
+
+    await program.rpc.exchangeSolRequest(maxFeeBps, {
+      accounts: {
+        taker: solTaker.publicKey,
+        takerReceiveTokenAccount: solTakerTokenAccountA,
+        pdaDepositTokenAccount: vault,
+        initializerMainAccount: provider.wallet.publicKey,
+        escrowAccount: solEscrow.publicKey,
+        orderBook: solRequestOrderBook,
+        pdaAccount: vaultPda,
+        config,
+        feeRecipient: feeRecipient.publicKey,
+        offeredFeeTokenAccount: feeTokenAccountA,
+        systemProgram: SystemProgram.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      },
+      signers: [solTaker],
+    });
+
+    let _solTakerTokenAccountA = await mintA.getAccountInfo(
+      solTakerTokenAccountA
+    );
+    assert.ok(_solTakerTokenAccountA.amount.toNumber() == initializerAmount);
+    assert.ok(
+      (await provider.connection.getBalance(solTaker.publicKey)) ==
+        solAmount - price
+    );
+    assert.ok((await provider.connection.getAccountInfo(vault)) == null);
+  });
+
+  it("Exchange a bundle of tokens", async () => {
+    // Offer 500 A and 200 C, for 1000 B.
+    const mintC = await Token.createMint(
+      provider.connection,
+      payer,
+      mintAuthority.publicKey,
+      null,
+      0,
+      TOKEN_PROGRAM_ID
+    );
+    const initializerTokenAccountC = await mintC.createAccount(
+      provider.wallet.publicKey
+    );
+    const takerTokenAccountC = await mintC.createAccount(
+      provider.wallet.publicKey
+    );
+    const feeTokenAccountC = await mintC.createAccount(feeRecipient.publicKey);
+    await mintA.mintTo(
+      initializerTokenAccountA,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      initializerAmount
+    );
+    await mintC.mintTo(
+      initializerTokenAccountC,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      200
+    );
+    await mintB.mintTo(
+      takerTokenAccountB,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      takerAmount
+    );
+
+    const bundleEscrow = Keypair.generate();
+    const [bundlePda, bundleBump] = await PublicKey.findProgramAddress(
+      [
+        Buffer.from(anchor.utils.bytes.utf8.encode("escrow")),
+        bundleEscrow.publicKey.toBuffer(),
+      ],
+      program.programId
+    );
+    const writable = (pubkey: PublicKey) => ({
+      pubkey,
+      isWritable: true,
+      isSigner: false,
+    });
+
+    await program.rpc.initializeBundleEscrow(
+      [new anchor.BN(initializerAmount), new anchor.BN(200)],
+      [new anchor.BN(takerAmount)],
+      bundleBump,
+      {
+        accounts: {
+          initializer: provider.wallet.publicKey,
+          bundleEscrowAccount: bundleEscrow.publicKey,
+          pdaAccount: bundlePda,
+          systemProgram: SystemProgram.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        },
+        remainingAccounts: [
+          // Deposit accounts, one per offered leg.
+          writable(initializerTokenAccountA),
+          writable(initializerTokenAccountC),
+          // Receive accounts, one per requested leg.
+          writable(initializerTokenAccountB),
+        ],
+        signers: [bundleEscrow],
+      }
+    );
+
+    let _bundleEscrowAccount = await program.account.bundleEscrowAccount.fetch(
+      bundleEscrow.publicKey
+    );
+    assert.ok(_bundleEscrowAccount.offered.length == 2);
+    assert.ok(_bundleEscrowAccount.requested.length == 1);
+    assert.ok(_bundleEscrowAccount.offered[1].mint.equals(mintC.publicKey));
+    assert.ok(
+      (await mintC.getAccountInfo(initializerTokenAccountC)).owner.equals(
+        bundlePda
+      )
+    );
+
+    const takerABefore = (
+      await mintA.getAccountInfo(takerTokenAccountA)
+    ).amount.toNumber();
+    const initializerBBefore = (
+      await mintB.getAccountInfo(initializerTokenAccountB)
+    ).amount.toNumber();
+
+    await program.rpc.exchangeBundle(maxFeeBps, {
+      accounts: {
+        taker: provider.wallet.publicKey,
+        initializerMainAccount: provider.wallet.publicKey,
+        bundleEscrowAccount: bundleEscrow.publicKey,
+        pdaAccount: bundlePda,
+        config,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      },
+      remainingAccounts: [
+        // Offered legs: deposit account, then the taker's receive account,
+        // then the fee account.
+        writable(initializerTokenAccountA),
+        writable(takerTokenAccountA),
+        writable(feeTokenAccountA),
+        writable(initializerTokenAccountC),
+        writable(takerTokenAccountC),
+        writable(feeTokenAccountC),
+        // Requested legs: the taker's deposit account, then the initializer's
+        // receive account, then the fee account.
+        writable(takerTokenAccountB),
+        writable(initializerTokenAccountB),
+        writable(feeTokenAccountB),
+      ],
+    });
+
+    assert.ok(
+      (await mintA.getAccountInfo(takerTokenAccountA)).amount.toNumber() ==
+        takerABefore + initializerAmount
+    );
+    assert.ok(
+      (await mintC.getAccountInfo(takerTokenAccountC)).amount.toNumber() == 200
+    );
+    assert.ok(
+      (await mintB.getAccountInfo(initializerTokenAccountB)).amount.toNumber() ==
+        initializerBBefore + takerAmount
+    );
+    // The initializer gets their deposit accounts back.
+    assert.ok(
+      (await mintC.getAccountInfo(initializerTokenAccountC)).owner.equals(
+        provider.wallet.publicKey
+      )
+    );
+    assert.ok(
+      (await provider.connection.getAccountInfo(bundleEscrow.publicKey)) ==
+        null
+    );
+  });
+
+  it("Charge a protocol fee on exchange", async () => {
+    // 1% of the offered leg, 2.5% of the requested leg.
+    await program.rpc.updateConfig(
+      100,
+      250,
+      feeRecipient.publicKey,
+      provider.wallet.publicKey,
+      {
+        accounts: {
+          config,
+          admin: provider.wallet.publicKey,
+        },
+      }
+    );
+
+    // Only the admin can update the config.
+    try {
+      await program.rpc.updateConfig(
+        0,
+        0,
+        payer.publicKey,
+        payer.publicKey,
+        {
+          accounts: {
+            config,
+            admin: payer.publicKey,
+          },
+          signers: [payer],
+        }
+      );
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(err.msg, "Only the config's admin can update it.");
+    }
+
+    // Fees are capped at 5%.
+    try {
+      await program.rpc.updateConfig(
+        501,
+        0,
+        feeRecipient.publicKey,
+        provider.wallet.publicKey,
+        {
+          accounts: {
+            config,
+            admin: provider.wallet.publicKey,
+          },
+        }
+      );
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(err.msg, "Fees can't be more than 500 basis points.");
+    }
+
+    await mintA.mintTo(
+      initializerTokenAccountA,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      initializerAmount
+    );
+    await mintB.mintTo(
+      takerTokenAccountB,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      takerAmount
+    );
+
+    const feeEscrow = Keypair.generate();
+    const { vaultPda, vault } = await initializeEscrowWithVault(feeEscrow);
+
+    const takerABefore = (
+      await mintA.getAccountInfo(takerTokenAccountA)
+    ).amount.toNumber();
+    const initializerBBefore = (
+      await mintB.getAccountInfo(initializerTokenAccountB)
+    ).amount.toNumber();
+
+    // The taker only accepts fees of up to 2%, so the 2.5% fee on the
+    // requested leg fails the exchange.
+    try {
+      await program.rpc.exchange(
+        new anchor.BN(initializerAmount),
+        new anchor.BN(takerAmount),
+        200,
+        {
+          accounts: {
+            taker: provider.wallet.publicKey,
+            takerDepositTokenAccount: takerTokenAccountB,
+            takerReceiveTokenAccount: takerTokenAccountA,
+            pdaDepositTokenAccount: vault,
+            initializerReceiveTokenAccount: initializerTokenAccountB,
+            initializerMainAccount: provider.wallet.publicKey,
+            escrowAccount: feeEscrow.publicKey,
+            orderBook,
+            pdaAccount: vaultPda,
+            config,
+            offeredFeeTokenAccount: feeTokenAccountA,
+            requestedFeeTokenAccount: feeTokenAccountB,
+            tokenProgram: TOKEN_PROGRAM_ID,
+          },
+        }
+      );
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(
+        err.msg,
+        "The protocol fee is higher than the taker's max_fee_bps."
+      );
+    }
+
+    await program.rpc.exchange(
+      new anchor.BN(initializerAmount),
+      new anchor.BN(takerAmount),
+      maxFeeBps,
+      {
+        accounts: {
+          taker: provider.wallet.publicKey,
+          takerDepositTokenAccount: takerTokenAccountB,
+          takerReceiveTokenAccount: takerTokenAccountA,
+          pdaDepositTokenAccount: vault,
+          initializerReceiveTokenAccount: initializerTokenAccountB,
+          initializerMainAccount: provider.wallet.publicKey,
+          escrowAccount: feeEscrow.publicKey,
+          orderBook,
+          pdaAccount: vaultPda,
+          config,
+          offeredFeeTokenAccount: feeTokenAccountA,
+          requestedFeeTokenAccount: feeTokenAccountB,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        },
+      }
+    );
+
+    // Each side receives its leg less the fee, which goes to feeRecipient.
+    assert.ok(
+      (await mintA.getAccountInfo(takerTokenAccountA)).amount.toNumber() ==
+        takerABefore + initializerAmount - 5
+    );
+    assert.ok(
+      (await mintB.getAccountInfo(initializerTokenAccountB)).amount.toNumber() ==
+        initializerBBefore + takerAmount - 25
+    );
+    assert.ok(
+      (await mintA.getAccountInfo(feeTokenAccountA)).amount.toNumber() == 5
+    );
+    assert.ok(
+      (await mintB.getAccountInfo(feeTokenAccountB)).amount.toNumber() == 25
+    );
+
+    // Native SOL legs pay fees too, with the fee on the lamports going to
+    // feeRecipient itself.
+    await mintB.mintTo(
+      takerTokenAccountB,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      takerAmount
+    );
+    const [solOfferOrderBook] = await PublicKey.findProgramAddress(
+      [
+        Buffer.from(anchor.utils.bytes.utf8.encode("order-book")),
+        NATIVE_MINT.toBuffer(),
+        mintB.publicKey.toBuffer(),
+      ],
+      program.programId
+    );
+    const solEscrow = Keypair.generate();
+    await program.rpc.initializeEscrowOfferingSol(
+      new anchor.BN(solAmount),
+      new anchor.BN(takerAmount),
+      null,
+      null,
+      {
+        accounts: {
+          initializer: provider.wallet.publicKey,
+          initializerReceiveTokenAccount: initializerTokenAccountB,
+          escrowAccount: solEscrow.publicKey,
+          orderBook: solOfferOrderBook,
+          systemProgram: SystemProgram.programId,
+        },
+        signers: [solEscrow],
+      }
+    );
+    const feeRecipientBefore = await provider.connection.getBalance(
+      feeRecipient.publicKey
+    );
+    await program.rpc.exchangeSolOffer(maxFeeBps, {
+      accounts: {
+        taker: provider.wallet.publicKey,
+        takerDepositTokenAccount: takerTokenAccountB,
+        initializerReceiveTokenAccount: initializerTokenAccountB,
+        initializerMainAccount: provider.wallet.publicKey,
+        escrowAccount: solEscrow.publicKey,
+        orderBook: solOfferOrderBook,
+        config,
+        feeRecipient: feeRecipient.publicKey,
+        requestedFeeTokenAccount: feeTokenAccountB,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      },
+    });
+    assert.ok(
+      (await provider.connection.getBalance(feeRecipient.publicKey)) ==
+        feeRecipientBefore + solAmount / 100
+    );
+    assert.ok(
+      (await mintB.getAccountInfo(feeTokenAccountB)).amount.toNumber() == 50
+    );
+
+    // Turn fees back off for any tests that follow.
+    await program.rpc.updateConfig(
+      0,
+      0,
+      feeRecipient.publicKey,
+      provider.wallet.publicKey,
+      {
+        accounts: {
+          config,
+          admin: provider.wallet.publicKey,
+        },
+      }
+    );
+  });
+
+  it("Emit events for the escrow lifecycle", async () => {
+    await mintA.mintTo(
+      initializerTokenAccountA,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      initializerAmount
+    );
+    await mintB.mintTo(
+      takerTokenAccountB,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      takerAmount
+    );
+
+    const eventEscrow = Keypair.generate();
+    const [eventPda, eventBump] = await PublicKey.findProgramAddress(
+      [
+        Buffer.from(anchor.utils.bytes.utf8.encode("escrow")),
+        eventEscrow.publicKey.toBuffer(),
+      ],
+      program.programId
+    );
+    let events = await parseEvents(
+      await program.rpc.initializeEscrow(
+        new anchor.BN(initializerAmount),
+        new anchor.BN(takerAmount),
+        eventBump,
+        null,
+        null,
+        null,
+        {
+          accounts: {
+            initializer: provider.wallet.publicKey,
+            initializerDepositTokenAccount: initializerTokenAccountA,
+            initializerReceiveTokenAccount: initializerTokenAccountB,
+            escrowAccount: eventEscrow.publicKey,
+            orderBook,
+            pdaAccount: eventPda,
+            systemProgram: SystemProgram.programId,
+            tokenProgram: TOKEN_PROGRAM_ID,
+            rent: SYSVAR_RENT_PUBKEY,
+          },
+          signers: [eventEscrow],
+        }
+      )
+    );
+    assert.equal(events.length, 1);
+    assert.equal(events[0].name, "EscrowCreated");
+    assert.ok(events[0].data.escrow.equals(eventEscrow.publicKey));
+    assert.ok(events[0].data.initializer.equals(provider.wallet.publicKey));
+    assert.ok(events[0].data.offeredMint.equals(mintA.publicKey));
+    assert.ok(events[0].data.requestedMint.equals(mintB.publicKey));
+    assert.ok(events[0].data.initializerAmount.toNumber() == initializerAmount);
+    assert.ok(events[0].data.takerAmount.toNumber() == takerAmount);
+    assert.ok(events[0].data.slot.toNumber() > 0);
+
+    events = await parseEvents(
+      await program.rpc.exchangePartial(
+        new anchor.BN(400),
+        new anchor.BN(initializerAmount),
+        new anchor.BN(takerAmount),
+        maxFeeBps,
+        {
+          accounts: {
+            taker: provider.wallet.publicKey,
+            takerDepositTokenAccount: takerTokenAccountB,
+            takerReceiveTokenAccount: takerTokenAccountA,
+            pdaDepositTokenAccount: initializerTokenAccountA,
+            initializerReceiveTokenAccount: initializerTokenAccountB,
+            initializerMainAccount: provider.wallet.publicKey,
+            escrowAccount: eventEscrow.publicKey,
+            orderBook,
+            pdaAccount: eventPda,
+            config,
+            offeredFeeTokenAccount: feeTokenAccountA,
+            requestedFeeTokenAccount: feeTokenAccountB,
+            tokenProgram: TOKEN_PROGRAM_ID,
+          },
+        }
+      )
+    );
+    assert.equal(events.length, 1);
+    assert.equal(events[0].name, "EscrowExchanged");
+    assert.ok(events[0].data.taker.equals(provider.wallet.publicKey));
+    assert.ok(events[0].data.initializerAmount.toNumber() == 200);
+    assert.ok(events[0].data.takerAmount.toNumber() == 400);
+    assert.ok(events[0].data.remainingInitializerAmount.toNumber() == 300);
+    assert.ok(events[0].data.remainingTakerAmount.toNumber() == 600);
+
+    events = await parseEvents(
+      await program.rpc.cancelEscrow({
+        accounts: {
+          authority: provider.wallet.publicKey,
+          initializer: provider.wallet.publicKey,
+          pdaDepositTokenAccount: initializerTokenAccountA,
+          initializerDepositTokenAccount: initializerTokenAccountA,
+          pdaAccount: eventPda,
+          escrowAccount: eventEscrow.publicKey,
+          orderBook,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        },
+      })
+    );
+    assert.equal(events.length, 1);
+    assert.equal(events[0].name, "EscrowCancelled");
+    assert.ok(events[0].data.escrow.equals(eventEscrow.publicKey));
+    assert.ok(events[0].data.initializerAmount.toNumber() == 300);
+    assert.ok(events[0].data.takerAmount.toNumber() == 600);
+    assert.ok(!events[0].data.expired);
+  });
+
+  it("Update an escrow's amounts in place", async () => {
+    await mintA.mintTo(
+      initializerTokenAccountA,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      initializerAmount * 2
+    );
+
+    const updatedEscrow = Keypair.generate();
+    const { vaultPda, vault } = await initializeEscrowWithVault(updatedEscrow);
+    const initializerABefore = (
+      await mintA.getAccountInfo(initializerTokenAccountA)
+    ).amount.toNumber();
+
+    const accounts = {
+      initializer: provider.wallet.publicKey,
+      initializerDepositTokenAccount: initializerTokenAccountA,
+      pdaDepositTokenAccount: vault,
+      pdaAccount: vaultPda,
+      escrowAccount: updatedEscrow.publicKey,
+      tokenProgram: TOKEN_PROGRAM_ID,
+    };
+
+    // Offering more tops up the vault from the initializer's deposit account.
+    await program.rpc.updateEscrow(
+      new anchor.BN(initializerAmount + 300),
+      new anchor.BN(takerAmount + 200),
+      { accounts }
+    );
+    let _escrowAccount = await program.account.escrowAccount.fetch(
+      updatedEscrow.publicKey
+    );
+    assert.ok(_escrowAccount.initializerAmount.toNumber() == 800);
+    assert.ok(_escrowAccount.takerAmount.toNumber() == 1200);
+    assert.ok((await mintA.getAccountInfo(vault)).amount.toNumber() == 800);
+    assert.ok(
+      (await mintA.getAccountInfo(initializerTokenAccountA)).amount.toNumber() ==
+        initializerABefore - 300
+    );
+
+    // Offering less sends the difference back.
+    await program.rpc.updateEscrow(new anchor.BN(100), new anchor.BN(200), {
+      accounts,
+    });
+    _escrowAccount = await program.account.escrowAccount.fetch(
+      updatedEscrow.publicKey
+    );
+    assert.ok(_escrowAccount.initializerAmount.toNumber() == 100);
+    assert.ok(_escrowAccount.takerAmount.toNumber() == 200);
+    assert.ok((await mintA.getAccountInfo(vault)).amount.toNumber() == 100);
+    assert.ok(
+      (await mintA.getAccountInfo(initializerTokenAccountA)).amount.toNumber() ==
+        initializerABefore + 400
+    );
+
+    // Can't offer more than the initializer holds.
+    try {
+      await program.rpc.updateEscrow(
+        new anchor.BN(100 + initializerABefore + 401),
+        new anchor.BN(200),
+        { accounts }
+      );
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(
+        err.msg,
+        "The initializer's deposit token account does not hold enough tokens."
+      );
+    }
+
+    try {
+      await program.rpc.updateEscrow(new anchor.BN(100), new anchor.BN(0), {
+        accounts,
+      });
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(err.msg, "Escrow amounts must be greater than zero.");
+    }
+
+    await program.rpc.cancelEscrow({
+      accounts: {
+        authority: provider.wallet.publicKey,
+        initializer: provider.wallet.publicKey,
+        pdaDepositTokenAccount: vault,
+        initializerDepositTokenAccount: initializerTokenAccountA,
+        pdaAccount: vaultPda,
+        escrowAccount: updatedEscrow.publicKey,
+        orderBook,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      },
+    });
+  });
+
+  it("Trade an NFT for tokens", async () => {
+    // An NFT is a 0-decimal mint with a single token and no mint authority left.
+    const nftMint = await Token.createMint(
+      provider.connection,
+      payer,
+      mintAuthority.publicKey,
+      null,
+      0,
+      TOKEN_PROGRAM_ID
+    );
+    const initializerNftAccount = await nftMint.createAccount(
+      provider.wallet.publicKey
+    );
+    const takerNftAccount = await nftMint.createAccount(
+      provider.wallet.publicKey
+    );
+    await nftMint.mintTo(
+      initializerNftAccount,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      1
+    );
+    await nftMint.setAuthority(
+      nftMint.publicKey,
+      null,
+      "MintTokens",
+      mintAuthority.publicKey,
+      [mintAuthority]
+    );
+    await mintB.mintTo(
+      takerTokenAccountB,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      takerAmount
+    );
+    const nftOrderBook = await initializeOrderBook(
+      nftMint.publicKey,
+      mintB.publicKey
+    );
+    const nftTerms = { leg: { offered: {} }, collection: null };
+
+    const nftEscrow = Keypair.generate();
+    const [nftPda, nftBump] = await PublicKey.findProgramAddress(
+      [
+        Buffer.from(anchor.utils.bytes.utf8.encode("escrow")),
+        nftEscrow.publicKey.toBuffer(),
+      ],
+      program.programId
+    );
+
+    // mintA has more than one token, so it can't be an NFT leg.
+    try {
+      await program.rpc.initializeEscrow(
+        new anchor.BN(1),
+        new anchor.BN(takerAmount),
+        nftBump,
+        null,
+        null,
+        nftTerms,
+        {
+          accounts: {
+            initializer: provider.wallet.publicKey,
+            initializerDepositTokenAccount: initializerTokenAccountA,
+            initializerReceiveTokenAccount: initializerTokenAccountB,
+            escrowAccount: nftEscrow.publicKey,
+            orderBook,
+            pdaAccount: nftPda,
+            systemProgram: SystemProgram.programId,
+            tokenProgram: TOKEN_PROGRAM_ID,
+            rent: SYSVAR_RENT_PUBKEY,
+          },
+          remainingAccounts: [
+            { pubkey: mintA.publicKey, isWritable: false, isSigner: false },
+          ],
+          signers: [nftEscrow],
+        }
+      );
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(
+        err.msg,
+        "The NFT leg's mint must have 0 decimals and a supply of 1."
+      );
+    }
+
+    await program.rpc.initializeEscrow(
+      new anchor.BN(1),
+      new anchor.BN(takerAmount),
+      nftBump,
+      null,
+      null,
+      nftTerms,
+      {
+        accounts: {
+          initializer: provider.wallet.publicKey,
+          initializerDepositTokenAccount: initializerNftAccount,
+          initializerReceiveTokenAccount: initializerTokenAccountB,
+          escrowAccount: nftEscrow.publicKey,
+          orderBook: nftOrderBook,
+          pdaAccount: nftPda,
+          systemProgram: SystemProgram.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          rent: SYSVAR_RENT_PUBKEY,
+        },
+        remainingAccounts: [
+          { pubkey: nftMint.publicKey, isWritable: false, isSigner: false },
+        ],
+        signers: [nftEscrow],
+      }
+    );
+    let _escrowAccount = await program.account.escrowAccount.fetch(
+      nftEscrow.publicKey
+    );
+    assert.ok("offered" in _escrowAccount.nft.leg);
+    assert.equal(_escrowAccount.nft.collection, null);
+
+    const exchangeAccounts = {
+      taker: provider.wallet.publicKey,
+      takerDepositTokenAccount: takerTokenAccountB,
+      takerReceiveTokenAccount: takerNftAccount,
+      pdaDepositTokenAccount: initializerNftAccount,
+      initializerReceiveTokenAccount: initializerTokenAccountB,
+      initializerMainAccount: provider.wallet.publicKey,
+      escrowAccount: nftEscrow.publicKey,
+      orderBook: nftOrderBook,
+      pdaAccount: nftPda,
+      config,
+      offeredFeeTokenAccount: await nftMint.createAccount(
+        feeRecipient.publicKey
+      ),
+      requestedFeeTokenAccount: feeTokenAccountB,
+      tokenProgram: TOKEN_PROGRAM_ID,
+    };
+
+    // The NFT's mint has to be passed again on exchange.
+    try {
+      await program.rpc.exchange(
+        new anchor.BN(1),
+        new anchor.BN(takerAmount),
+        maxFeeBps,
+        {
+          accounts: exchangeAccounts,
+        }
+      );
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(
+        err.msg,
+        "The NFT's mint (and metadata account, if a collection is required) must be passed in the remaining accounts."
+      );
+    }
+
+    await program.rpc.exchange(
+      new anchor.BN(1),
+      new anchor.BN(takerAmount),
+      maxFeeBps,
+      {
+        accounts: exchangeAccounts,
+        remainingAccounts: [
+          { pubkey: nftMint.publicKey, isWritable: false, isSigner: false },
+        ],
+      }
+    );
+    assert.ok(
+      (await nftMint.getAccountInfo(takerNftAccount)).amount.toNumber() == 1
+    );
+    assert.ok(
+      (await nftMint.getAccountInfo(initializerNftAccount)).amount.toNumber() ==
+        0
+    );
+  });
+
+  it("Don't reclaim an escrow whose deposit is intact", async () => {
+    await mintA.mintTo(
+      initializerTokenAccountA,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      initializerAmount
+    );
+    const intactEscrow = Keypair.generate();
+    const { vaultPda, vault } = await initializeEscrowWithVault(intactEscrow);
+
+    // spl-token gives no way to drain a vault only the PDA controls, so the
+    // stale case can't be set up here; this checks reclaim leaves live escrows alone.
+    try {
+      await program.rpc.reclaim({
+        accounts: {
+          initializer: provider.wallet.publicKey,
+          pdaDepositTokenAccount: vault,
+          initializerDepositTokenAccount: initializerTokenAccountA,
+          pdaAccount: vaultPda,
+          escrowAccount: intactEscrow.publicKey,
+          orderBook,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        },
+      });
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(err.msg, "The escrow's deposit still holds the offered amount.");
+    }
+
+    await program.rpc.cancelEscrow({
+      accounts: {
+        authority: provider.wallet.publicKey,
+        initializer: provider.wallet.publicKey,
+        pdaDepositTokenAccount: vault,
+        initializerDepositTokenAccount: initializerTokenAccountA,
+        pdaAccount: vaultPda,
+        escrowAccount: intactEscrow.publicKey,
+        orderBook,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      },
+    });
+  });
+
+  it("Sell through a Dutch auction", async () => {
+    await mintA.mintTo(
+      initializerTokenAccountA,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      initializerAmount
+    );
+    await mintB.mintTo(
+      takerTokenAccountB,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      takerAmount
+    );
+
+    const auctionEscrow = Keypair.generate();
+    const { vaultPda, vault } = await initializeEscrowWithVault(auctionEscrow);
+    const now = await provider.connection.getBlockTime(
+      await provider.connection.getSlot()
+    );
+    const setAccounts = {
+      initializer: provider.wallet.publicKey,
+      escrowAccount: auctionEscrow.publicKey,
+    };
+
+    // The price has to fall.
+    try {
+      await program.rpc.setDutchAuction(
+        {
+          startTakerAmount: new anchor.BN(takerAmount),
+          endTakerAmount: new anchor.BN(takerAmount * 2),
+          startTime: new anchor.BN(now),
+          endTime: new anchor.BN(now + 1000),
+        },
+        { accounts: setAccounts }
+      );
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(
+        err.msg,
+        "A Dutch auction's price must fall from start to a non-zero end price, between a start and a later end time, and can't be used to request an NFT or with oracle pricing."
+      );
+    }
+
+    // Before it starts, an auction charges its start price.
+    await program.rpc.setDutchAuction(
+      {
+        startTakerAmount: new anchor.BN(takerAmount * 2),
+        endTakerAmount: new anchor.BN(takerAmount),
+        startTime: new anchor.BN(now + 1000),
+        endTime: new anchor.BN(now + 2000),
+      },
+      { accounts: setAccounts }
+    );
+    let _escrowAccount = await program.account.escrowAccount.fetch(
+      auctionEscrow.publicKey
+    );
+    assert.ok(_escrowAccount.takerAmount.toNumber() == takerAmount * 2);
+
+    // Once it has ended, it charges its end price.
+    await program.rpc.setDutchAuction(
+      {
+        startTakerAmount: new anchor.BN(takerAmount * 2),
+        endTakerAmount: new anchor.BN(takerAmount),
+        startTime: new anchor.BN(now - 2000),
+        endTime: new anchor.BN(now - 1000),
+      },
+      { accounts: setAccounts }
+    );
+    const simulated = await program.simulate.logDutchAuctionPrice({
+      accounts: { escrowAccount: auctionEscrow.publicKey },
+    });
+    assert.ok(
+      simulated.raw.includes(`Program log: Current price: ${takerAmount}`)
+    );
+
+    const exchangeAccounts = {
+      taker: provider.wallet.publicKey,
+      takerDepositTokenAccount: takerTokenAccountB,
+      takerReceiveTokenAccount: takerTokenAccountA,
+      pdaDepositTokenAccount: vault,
+      initializerReceiveTokenAccount: initializerTokenAccountB,
+      initializerMainAccount: provider.wallet.publicKey,
+      escrowAccount: auctionEscrow.publicKey,
+      orderBook,
+      pdaAccount: vaultPda,
+      config,
+      offeredFeeTokenAccount: feeTokenAccountA,
+      requestedFeeTokenAccount: feeTokenAccountB,
+      tokenProgram: TOKEN_PROGRAM_ID,
+    };
+
+    try {
+      await program.rpc.exchangePartial(
+        new anchor.BN(takerAmount / 2),
+        new anchor.BN(initializerAmount),
+        new anchor.BN(takerAmount),
+        maxFeeBps,
+        {
+          accounts: exchangeAccounts,
+        }
+      );
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(
+        err.msg,
+        "Dutch auctions can only be exchanged in full, and are repriced with set_dutch_auction."
+      );
+    }
+
+    // The taker's expected amount is the most they'll pay.
+    try {
+      await program.rpc.exchange(
+        new anchor.BN(initializerAmount),
+        new anchor.BN(takerAmount - 1),
+        maxFeeBps,
+        { accounts: exchangeAccounts }
+      );
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(
+        err.msg,
+        "The escrow's terms don't match what the taker expected."
+      );
+    }
+
+    const takerBBefore = (
+      await mintB.getAccountInfo(takerTokenAccountB)
+    ).amount.toNumber();
+    await program.rpc.exchange(
+      new anchor.BN(initializerAmount),
+      new anchor.BN(takerAmount * 2),
+      maxFeeBps,
+      { accounts: exchangeAccounts }
+    );
+    assert.ok(
+      (await mintB.getAccountInfo(takerTokenAccountB)).amount.toNumber() ==
+        takerBBefore - takerAmount
+    );
+  });
+
+  // Starts an English auction, with a minimum bid of takerAmount, on a new
+  // vault escrow.
+  async function startEnglishAuction() {
+    const escrow = Keypair.generate();
+    const addresses = await initializeEscrowWithVault(escrow);
+    const now = await provider.connection.getBlockTime(
+      await provider.connection.getSlot()
+    );
+    const endsAt = now + 5;
+    await program.rpc.startEnglishAuction(
+      new anchor.BN(takerAmount),
+      new anchor.BN(endsAt),
+      {
+        accounts: {
+          initializer: provider.wallet.publicKey,
+          escrowAccount: escrow.publicKey,
+        },
+      }
+    );
+    return { escrow, endsAt, ...addresses };
+  }
+
+  async function findBidVault(escrow: PublicKey, bidder: PublicKey) {
+    return await PublicKey.findProgramAddress(
+      [
+        Buffer.from(anchor.utils.bytes.utf8.encode("bid")),
+        escrow.toBuffer(),
+        bidder.toBuffer(),
+      ],
+      program.programId
+    );
+  }
+
+  function bidAccounts(
+    escrow: PublicKey,
+    vaultPda: PublicKey,
+    bidder: PublicKey,
+    bidderDepositTokenAccount: PublicKey,
+    bidderReceiveTokenAccount: PublicKey,
+    bidVault: PublicKey
+  ) {
+    return {
+      bidder,
+      bidderDepositTokenAccount,
+      bidderReceiveTokenAccount,
+      mint: mintB.publicKey,
+      bidVault,
+      pdaAccount: vaultPda,
+      escrowAccount: escrow,
+      config,
+      systemProgram: SystemProgram.programId,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+      rent: SYSVAR_RENT_PUBKEY,
+    };
+  }
+
+  // The remaining accounts place_bid needs to refund the bid it beats.
+  async function previousBidAccounts(
+    bidder: PublicKey,
+    refundTokenAccount: PublicKey,
+    bidVault: PublicKey
+  ) {
+    const associatedTokenAccount = await Token.getAssociatedTokenAddress(
+      ASSOCIATED_TOKEN_PROGRAM_ID,
+      TOKEN_PROGRAM_ID,
+      mintB.publicKey,
+      bidder
+    );
+    return [
+      { pubkey: bidVault, isWritable: true, isSigner: false },
+      { pubkey: refundTokenAccount, isWritable: true, isSigner: false },
+      { pubkey: bidder, isWritable: true, isSigner: false },
+      { pubkey: associatedTokenAccount, isWritable: true, isSigner: false },
+    ];
+  }
+
+  async function auctionSettleAccounts(
+    escrow: PublicKey,
+    vaultPda: PublicKey,
+    vault: PublicKey,
+    winner: PublicKey,
+    bidVault: PublicKey,
+    takerReceiveTokenAccount: PublicKey
+  ) {
+    return {
+      winner,
+      bidVault,
+      takerReceiveTokenAccount,
+      pdaDepositTokenAccount: vault,
+      initializerReceiveTokenAccount: initializerTokenAccountB,
+      initializerMainAccount: provider.wallet.publicKey,
+      winnerAssociatedTokenAccount: await Token.getAssociatedTokenAddress(
+        ASSOCIATED_TOKEN_PROGRAM_ID,
+        TOKEN_PROGRAM_ID,
+        mintA.publicKey,
+        winner
+      ),
+      initializerAssociatedTokenAccount: await Token.getAssociatedTokenAddress(
+        ASSOCIATED_TOKEN_PROGRAM_ID,
+        TOKEN_PROGRAM_ID,
+        mintB.publicKey,
+        provider.wallet.publicKey
+      ),
+      offeredMint: mintA.publicKey,
+      requestedMint: mintB.publicKey,
+      escrowAccount: escrow,
+      pdaAccount: vaultPda,
+      orderBook,
+      config,
+      offeredFeeTokenAccount: feeTokenAccountA,
+      requestedFeeTokenAccount: feeTokenAccountB,
+      payer: provider.wallet.publicKey,
+      systemProgram: SystemProgram.programId,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+      rent: SYSVAR_RENT_PUBKEY,
+    };
+  }
+
+  // Waits for the cluster's clock to pass the end of an auction.
+  async function waitForAuctionEnd(endsAt: number) {
+    while (
+      (await provider.connection.getBlockTime(
+        await provider.connection.getSlot()
+      )) <= endsAt
+    ) {
+      await new Promise((resolve) => setTimeout(resolve, 500));
+    }
+  }
+
+  // A new taker (or bidder) holding amount of mintB, with token accounts for
+  // both mints.
+  async function fundTaker(amount: number) {
+    const wallet = Keypair.generate();
+    await provider.connection.confirmTransaction(
+      await provider.connection.requestAirdrop(
+        wallet.publicKey,
+        LAMPORTS_PER_SOL
+      ),
+      "confirmed"
+    );
+    const tokenAccountA = await mintA.createAccount(wallet.publicKey);
+    const tokenAccountB = await mintB.createAccount(wallet.publicKey);
+    await mintB.mintTo(
+      tokenAccountB,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      amount
+    );
+    return { wallet, tokenAccountA, tokenAccountB };
+  }
+
+  it("Sell through an English auction", async () => {
+    await mintA.mintTo(
+      initializerTokenAccountA,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      initializerAmount
+    );
+    await mintB.mintTo(
+      takerTokenAccountB,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      takerAmount
+    );
+
+    const {
+      escrow: auctionEscrow,
+      endsAt,
+      vaultPda,
+      vault,
+    } = await startEnglishAuction();
+
+    // The first bid, from the provider's wallet.
+    const takerBBefore = (
+      await mintB.getAccountInfo(takerTokenAccountB)
+    ).amount.toNumber();
+    const [firstBidVault, firstBidVaultBump] = await findBidVault(
+      auctionEscrow.publicKey,
+      provider.wallet.publicKey
+    );
+    await program.rpc.placeBid(
+      new anchor.BN(takerAmount),
+      maxFeeBps,
+      firstBidVaultBump,
+      {
+        accounts: bidAccounts(
+          auctionEscrow.publicKey,
+          vaultPda,
+          provider.wallet.publicKey,
+          takerTokenAccountB,
+          takerTokenAccountA,
+          firstBidVault
+        ),
+      }
+    );
+    assert.ok(
+      (await mintB.getAccountInfo(firstBidVault)).amount.toNumber() ==
+        takerAmount
+    );
+
+    // While it's running, the auction can't be exchanged or cancelled.
+    try {
+      await program.rpc.exchange(
+        new anchor.BN(initializerAmount),
+        new anchor.BN(takerAmount),
+        maxFeeBps,
+        {
+          accounts: {
+            taker: provider.wallet.publicKey,
+            takerDepositTokenAccount: takerTokenAccountB,
+            takerReceiveTokenAccount: takerTokenAccountA,
+            pdaDepositTokenAccount: vault,
+            initializerReceiveTokenAccount: initializerTokenAccountB,
+            initializerMainAccount: provider.wallet.publicKey,
+            escrowAccount: auctionEscrow.publicKey,
+            orderBook,
+            pdaAccount: vaultPda,
+            config,
+            offeredFeeTokenAccount: feeTokenAccountA,
+            requestedFeeTokenAccount: feeTokenAccountB,
+            tokenProgram: TOKEN_PROGRAM_ID,
+          },
+        }
+      );
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(
+        err.msg,
+        "This escrow is an English auction: bid on it with place_bid and close it with settle."
+      );
+    }
+    try {
+      await program.rpc.cancelEscrow({
+        accounts: {
+          authority: provider.wallet.publicKey,
+          initializer: provider.wallet.publicKey,
+          pdaDepositTokenAccount: vault,
+          initializerDepositTokenAccount: initializerTokenAccountA,
+          pdaAccount: vaultPda,
+          escrowAccount: auctionEscrow.publicKey,
+          orderBook,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        },
+      });
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(err.msg, "The auction has bids, so it can only be settled.");
+    }
+
+    // A second bidder outbids the first, who is refunded.
+    const secondBidder = await fundTaker(takerAmount + 100);
+    const [secondBidVault, secondBidVaultBump] = await findBidVault(
+      auctionEscrow.publicKey,
+      secondBidder.wallet.publicKey
+    );
+    const secondBidAccounts = bidAccounts(
+      auctionEscrow.publicKey,
+      vaultPda,
+      secondBidder.wallet.publicKey,
+      secondBidder.tokenAccountB,
+      secondBidder.tokenAccountA,
+      secondBidVault
+    );
+    const previousBid = await previousBidAccounts(
+      provider.wallet.publicKey,
+      takerTokenAccountB,
+      firstBidVault
+    );
+
+    try {
+      await program.rpc.placeBid(
+        new anchor.BN(takerAmount),
+        maxFeeBps,
+        secondBidVaultBump,
+        {
+          accounts: secondBidAccounts,
+          remainingAccounts: previousBid,
+          signers: [secondBidder.wallet],
+        }
+      );
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(
+        err.msg,
+        "A bid must be at least the minimum bid, and more than the best bid so far."
+      );
+    }
+
+    await program.rpc.placeBid(
+      new anchor.BN(takerAmount + 100),
+      maxFeeBps,
+      secondBidVaultBump,
+      {
+        accounts: secondBidAccounts,
+        remainingAccounts: previousBid,
+        signers: [secondBidder.wallet],
+      }
+    );
+    assert.ok(
+      (await mintB.getAccountInfo(takerTokenAccountB)).amount.toNumber() ==
+        takerBBefore
+    );
+    assert.equal(
+      await provider.connection.getAccountInfo(firstBidVault),
+      null
+    );
+
+    const secondBidSettleAccounts = await auctionSettleAccounts(
+      auctionEscrow.publicKey,
+      vaultPda,
+      vault,
+      secondBidder.wallet.publicKey,
+      secondBidVault,
+      secondBidder.tokenAccountA
+    );
+    try {
+      await program.rpc.settle({ accounts: secondBidSettleAccounts });
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(err.msg, "The auction has not ended yet.");
+    }
+
+    await waitForAuctionEnd(endsAt);
+
+    const initializerBBefore = (
+      await mintB.getAccountInfo(initializerTokenAccountB)
+    ).amount.toNumber();
+    await program.rpc.settle({ accounts: secondBidSettleAccounts });
+    assert.ok(
+      (
+        await mintA.getAccountInfo(secondBidder.tokenAccountA)
+      ).amount.toNumber() == initializerAmount
+    );
+    assert.ok(
+      (await mintB.getAccountInfo(initializerTokenAccountB)).amount.toNumber() ==
+        initializerBBefore + takerAmount + 100
+    );
+    assert.equal(
+      await provider.connection.getAccountInfo(secondBidVault),
+      null
+    );
+    assert.equal(
+      await provider.connection.getAccountInfo(auctionEscrow.publicKey),
+      null
+    );
+  });
+
+  it("Bidders can't lock up an English auction", async () => {
+    await mintA.mintTo(
+      initializerTokenAccountA,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      initializerAmount
+    );
+    const {
+      escrow: auctionEscrow,
+      endsAt,
+      vaultPda,
+      vault,
+    } = await startEnglishAuction();
+
+    // A bidder who closes their deposit account once their bid is in, so it
+    // can't be refunded there.
+    const loser = await fundTaker(takerAmount);
+    const [loserBidVault, loserBidVaultBump] = await findBidVault(
+      auctionEscrow.publicKey,
+      loser.wallet.publicKey
+    );
+    await program.rpc.placeBid(
+      new anchor.BN(takerAmount),
+      maxFeeBps,
+      loserBidVaultBump,
+      {
+        accounts: bidAccounts(
+          auctionEscrow.publicKey,
+          vaultPda,
+          loser.wallet.publicKey,
+          loser.tokenAccountB,
+          loser.tokenAccountA,
+          loserBidVault
+        ),
+        signers: [loser.wallet],
+      }
+    );
+    await mintB.closeAccount(
+      loser.tokenAccountB,
+      loser.wallet.publicKey,
+      loser.wallet,
+      []
+    );
+
+    // Outbidding them refunds their bid to their associated token account
+    // instead, which the new bidder pays for.
+    const winner = await fundTaker(takerAmount + 100);
+    const [winnerBidVault, winnerBidVaultBump] = await findBidVault(
+      auctionEscrow.publicKey,
+      winner.wallet.publicKey
+    );
+    const loserBid = await previousBidAccounts(
+      loser.wallet.publicKey,
+      loser.tokenAccountB,
+      loserBidVault
+    );
+    await program.rpc.placeBid(
+      new anchor.BN(takerAmount + 100),
+      maxFeeBps,
+      winnerBidVaultBump,
+      {
+        accounts: bidAccounts(
+          auctionEscrow.publicKey,
+          vaultPda,
+          winner.wallet.publicKey,
+          winner.tokenAccountB,
+          winner.tokenAccountA,
+          winnerBidVault
+        ),
+        remainingAccounts: loserBid,
+        signers: [winner.wallet],
+      }
+    );
+    assert.ok(
+      (await mintB.getAccountInfo(loserBid[3].pubkey)).amount.toNumber() ==
+        takerAmount
+    );
+    assert.equal(
+      await provider.connection.getAccountInfo(loserBidVault),
+      null
+    );
+
+    // The winner closes the account they asked to be paid in, so settle pays
+    // their associated token account instead, creating it.
+    await mintA.closeAccount(
+      winner.tokenAccountA,
+      winner.wallet.publicKey,
+      winner.wallet,
+      []
+    );
+    await waitForAuctionEnd(endsAt);
+    const winnerSettleAccounts = await auctionSettleAccounts(
+      auctionEscrow.publicKey,
+      vaultPda,
+      vault,
+      winner.wallet.publicKey,
+      winnerBidVault,
+      winner.tokenAccountA
+    );
+    const initializerBBefore = (
+      await mintB.getAccountInfo(initializerTokenAccountB)
+    ).amount.toNumber();
+    await program.rpc.settle({ accounts: winnerSettleAccounts });
+    assert.ok(
+      (
+        await mintA.getAccountInfo(
+          winnerSettleAccounts.winnerAssociatedTokenAccount
+        )
+      ).amount.toNumber() == initializerAmount
+    );
+    assert.ok(
+      (await mintB.getAccountInfo(initializerTokenAccountB)).amount.toNumber() ==
+        initializerBBefore + takerAmount + 100
+    );
+    assert.equal(
+      await provider.connection.getAccountInfo(auctionEscrow.publicKey),
+      null
+    );
+  });
+
+  it("Price an escrow off an oracle", async () => {
+    await mintA.mintTo(
+      initializerTokenAccountA,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      initializerAmount
+    );
+    // At a price of 2 and a 1% spread, the deposit costs 1010.
+    const oraclePrice = initializerAmount * 2 + 10;
+    await mintB.mintTo(
+      takerTokenAccountB,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      oraclePrice
+    );
+
+    const now = await provider.connection.getBlockTime(
+      await provider.connection.getSlot()
+    );
+    const priceFeed = Keypair.generate();
+    await mockOracle.rpc.initialize(new anchor.BN(2), 0, new anchor.BN(now), {
+      accounts: {
+        authority: provider.wallet.publicKey,
+        priceFeed: priceFeed.publicKey,
+        systemProgram: SystemProgram.programId,
+      },
+      signers: [priceFeed],
+    });
+
+    const oracleEscrow = Keypair.generate();
+    const { vaultPda, vault } = await initializeEscrowWithVault(oracleEscrow);
+    await program.rpc.setOraclePricing(
+      {
+        oracleProgram: mockOracle.programId,
+        priceAccount: priceFeed.publicKey,
+        spreadBps: 100,
+        maxStaleness: new anchor.BN(60),
+      },
+      {
+        accounts: {
+          initializer: provider.wallet.publicKey,
+          escrowAccount: oracleEscrow.publicKey,
+        },
+      }
+    );
+
+    const exchangeAccounts = {
+      taker: provider.wallet.publicKey,
+      takerDepositTokenAccount: takerTokenAccountB,
+      takerReceiveTokenAccount: takerTokenAccountA,
+      pdaDepositTokenAccount: vault,
+      initializerReceiveTokenAccount: initializerTokenAccountB,
+      initializerMainAccount: provider.wallet.publicKey,
+      escrowAccount: oracleEscrow.publicKey,
+      orderBook,
+      pdaAccount: vaultPda,
+      config,
+      offeredFeeTokenAccount: feeTokenAccountA,
+      requestedFeeTokenAccount: feeTokenAccountB,
+      tokenProgram: TOKEN_PROGRAM_ID,
+    };
+    const remainingAccounts = [
+      { pubkey: priceFeed.publicKey, isWritable: false, isSigner: false },
+    ];
+
+    // The taker's expected amount is the most they'll pay.
+    try {
+      await program.rpc.exchange(
+        new anchor.BN(initializerAmount),
+        new anchor.BN(oraclePrice - 1),
+        maxFeeBps,
+        { accounts: exchangeAccounts, remainingAccounts }
+      );
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(
+        err.msg,
+        "The escrow's terms don't match what the taker expected."
+      );
+    }
+
+    // A price published too long ago is rejected.
+    await mockOracle.rpc.setPrice(new anchor.BN(2), 0, new anchor.BN(now - 120), {
+      accounts: {
+        authority: provider.wallet.publicKey,
+        priceFeed: priceFeed.publicKey,
+      },
+    });
+    try {
+      await program.rpc.exchange(
+        new anchor.BN(initializerAmount),
+        new anchor.BN(oraclePrice),
+        maxFeeBps,
+        { accounts: exchangeAccounts, remainingAccounts }
+      );
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(
+        err.msg,
+        "The oracle's price is older than the escrow's staleness limit."
+      );
+    }
+
+    // At a price of 0.3 and a 1% spread, the deposit costs 151.5, which rounds
+    // up in the initializer's favour.
+    await mockOracle.rpc.setPrice(new anchor.BN(3), -1, new anchor.BN(now), {
+      accounts: {
+        authority: provider.wallet.publicKey,
+        priceFeed: priceFeed.publicKey,
+      },
+    });
+    const roundedPrice = Math.ceil((initializerAmount * 3 * 101) / 1000);
+    const takerBBefore = (
+      await mintB.getAccountInfo(takerTokenAccountB)
+    ).amount.toNumber();
+    await program.rpc.exchange(
+      new anchor.BN(initializerAmount),
+      new anchor.BN(roundedPrice),
+      maxFeeBps,
+      { accounts: exchangeAccounts, remainingAccounts }
+    );
+    assert.ok(
+      (await mintB.getAccountInfo(takerTokenAccountB)).amount.toNumber() ==
+        takerBBefore - roundedPrice
+    );
+  });
+
+  const arbiter = Keypair.generate();
+
+  // Creates a vault escrow with arbiter as its arbiter, and funds it from the
+  // provider's wallet, accepting fees of up to fundMaxFeeBps.
+  async function fundArbitratedEscrow(
+    escrow: Keypair,
+    fundMaxFeeBps: number = maxFeeBps
+  ) {
+    await mintA.mintTo(
+      initializerTokenAccountA,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      initializerAmount
+    );
+    await mintB.mintTo(
+      takerTokenAccountB,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      takerAmount
+    );
+    const { vaultPda, vault } = await initializeEscrowWithVault(escrow);
+    await program.rpc.setArbiter(arbiter.publicKey, {
+      accounts: {
+        initializer: provider.wallet.publicKey,
+        escrowAccount: escrow.publicKey,
+      },
+    });
+
+    const [takerVault, takerVaultBump] = await findTakerVault(escrow.publicKey);
+    await program.rpc.fund(
+      new anchor.BN(initializerAmount),
+      new anchor.BN(takerAmount),
+      arbiter.publicKey,
+      fundMaxFeeBps,
+      takerVaultBump,
+      {
+        accounts: fundAccounts(
+          escrow.publicKey,
+          vaultPda,
+          provider.wallet.publicKey,
+          takerTokenAccountB,
+          takerTokenAccountA,
+          takerVault
+        ),
+      }
+    );
+    const settleAccounts = await arbitrationSettleAccounts(
+      escrow.publicKey,
+      vaultPda,
+      vault,
+      provider.wallet.publicKey,
+      takerTokenAccountB,
+      takerTokenAccountA,
+      takerVault
+    );
+    return { vaultPda, vault, takerVault, settleAccounts };
+  }
+
+  async function findTakerVault(escrow: PublicKey) {
+    return await PublicKey.findProgramAddress(
+      [
+        Buffer.from(anchor.utils.bytes.utf8.encode("taker-vault")),
+        escrow.toBuffer(),
+      ],
+      program.programId
+    );
+  }
+
+  function fundAccounts(
+    escrow: PublicKey,
+    vaultPda: PublicKey,
+    taker: PublicKey,
+    takerDepositTokenAccount: PublicKey,
+    takerReceiveTokenAccount: PublicKey,
+    takerVault: PublicKey
+  ) {
+    return {
+      taker,
+      takerDepositTokenAccount,
+      takerReceiveTokenAccount,
+      mint: mintB.publicKey,
+      takerVault,
+      pdaAccount: vaultPda,
+      escrowAccount: escrow,
+      orderBook,
+      config,
+      systemProgram: SystemProgram.programId,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      rent: SYSVAR_RENT_PUBKEY,
+    };
+  }
+
+  // The accounts for releasing or resolving an escrow, with the provider's
+  // wallet as the authority.
+  async function arbitrationSettleAccounts(
+    escrow: PublicKey,
+    vaultPda: PublicKey,
+    vault: PublicKey,
+    taker: PublicKey,
+    takerDepositTokenAccount: PublicKey,
+    takerReceiveTokenAccount: PublicKey,
+    takerVault: PublicKey
+  ) {
+    const associatedTokenAccount = (mint: Token, owner: PublicKey) =>
+      Token.getAssociatedTokenAddress(
+        ASSOCIATED_TOKEN_PROGRAM_ID,
+        TOKEN_PROGRAM_ID,
+        mint.publicKey,
+        owner
+      );
+    return {
+      authority: provider.wallet.publicKey,
+      taker,
+      takerVault,
+      takerDepositTokenAccount,
+      takerReceiveTokenAccount,
+      pdaDepositTokenAccount: vault,
+      initializerDepositTokenAccount: initializerTokenAccountA,
+      initializerReceiveTokenAccount: initializerTokenAccountB,
+      initializerMainAccount: provider.wallet.publicKey,
+      takerAssociatedDepositTokenAccount: await associatedTokenAccount(
+        mintB,
+        taker
+      ),
+      takerAssociatedReceiveTokenAccount: await associatedTokenAccount(
+        mintA,
+        taker
+      ),
+      initializerAssociatedDepositTokenAccount: await associatedTokenAccount(
+        mintA,
+        provider.wallet.publicKey
+      ),
+      initializerAssociatedReceiveTokenAccount: await associatedTokenAccount(
+        mintB,
+        provider.wallet.publicKey
+      ),
+      offeredMint: mintA.publicKey,
+      requestedMint: mintB.publicKey,
+      escrowAccount: escrow,
+      pdaAccount: vaultPda,
+      config,
+      offeredFeeTokenAccount: feeTokenAccountA,
+      requestedFeeTokenAccount: feeTokenAccountB,
+      payer: provider.wallet.publicKey,
+      systemProgram: SystemProgram.programId,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+      rent: SYSVAR_RENT_PUBKEY,
+    };
+  }
+
+  it("Release an arbitrated escrow once both sides approve", async () => {
+    const arbitratedEscrow = Keypair.generate();
+    // The taker won't pay any protocol fee.
+    const { vaultPda, vault, settleAccounts } = await fundArbitratedEscrow(
+      arbitratedEscrow,
+      0
+    );
+    let _escrowAccount = await program.account.escrowAccount.fetch(
+      arbitratedEscrow.publicKey
+    );
+    assert.ok("funded" in _escrowAccount.state);
+    assert.ok(
+      !(await program.account.orderBook.fetch(orderBook)).escrows.some(
+        (escrow: PublicKey) => escrow.equals(arbitratedEscrow.publicKey)
+      )
+    );
+
+    // The taker's payment is locked in, so the initializer can't back out.
+    try {
+      await program.rpc.cancelEscrow({
+        accounts: {
+          authority: provider.wallet.publicKey,
+          initializer: provider.wallet.publicKey,
+          pdaDepositTokenAccount: vault,
+          initializerDepositTokenAccount: initializerTokenAccountA,
+          pdaAccount: vaultPda,
+          escrowAccount: arbitratedEscrow.publicKey,
+          orderBook,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        },
+      });
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(
+        err.msg,
+        "The taker has funded this escrow, so it can only be released or resolved."
+      );
+    }
+
+    try {
+      await program.rpc.release({ accounts: settleAccounts });
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(
+        err.msg,
+        "The escrow isn't in the right state for this instruction, or the accounts passed in don't match its funding."
+      );
+    }
+
+    // The arbiter isn't a party to the trade.
+    try {
+      await program.rpc.approve({
+        accounts: {
+          authority: arbiter.publicKey,
+          escrowAccount: arbitratedEscrow.publicKey,
+        },
+        signers: [arbiter],
+      });
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(err.msg, "Only the initializer or the taker can do this.");
+    }
+
+    // The provider's wallet is both the initializer and the taker here, so one
+    // approval covers both.
+    await program.rpc.approve({
+      accounts: {
+        authority: provider.wallet.publicKey,
+        escrowAccount: arbitratedEscrow.publicKey,
+      },
+    });
+    _escrowAccount = await program.account.escrowAccount.fetch(
+      arbitratedEscrow.publicKey
+    );
+    assert.ok("approved" in _escrowAccount.state);
+
+    const takerABefore = (
+      await mintA.getAccountInfo(takerTokenAccountA)
+    ).amount.toNumber();
+    const initializerBBefore = (
+      await mintB.getAccountInfo(initializerTokenAccountB)
+    ).amount.toNumber();
+
+    // Fees raised after the escrow was funded don't apply to it: it can't be
+    // released until they're back within what the taker signed for.
+    const setFee = (feeBps: number) =>
+      program.rpc.updateConfig(
+        feeBps,
+        feeBps,
+        feeRecipient.publicKey,
+        provider.wallet.publicKey,
+        {
+          accounts: {
+            config,
+            admin: provider.wallet.publicKey,
+          },
+        }
+      );
+    await setFee(100);
+    try {
+      await program.rpc.release({ accounts: settleAccounts });
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(
+        err.msg,
+        "The protocol fee is higher than the taker's max_fee_bps."
+      );
+    }
+    await setFee(0);
+
+    await program.rpc.release({ accounts: settleAccounts });
+    assert.ok(
+      (await mintA.getAccountInfo(takerTokenAccountA)).amount.toNumber() ==
+        takerABefore + initializerAmount
+    );
+    assert.ok(
+      (await mintB.getAccountInfo(initializerTokenAccountB)).amount.toNumber() ==
+        initializerBBefore + takerAmount
+    );
+    assert.equal(
+      await provider.connection.getAccountInfo(arbitratedEscrow.publicKey),
+      null
+    );
+  });
+
+  it("Let the arbiter resolve a dispute", async () => {
+    const disputedEscrow = Keypair.generate();
+    const { settleAccounts } = await fundArbitratedEscrow(disputedEscrow);
+
+    await program.rpc.dispute({
+      accounts: {
+        authority: provider.wallet.publicKey,
+        escrowAccount: disputedEscrow.publicKey,
+      },
+    });
+    const _escrowAccount = await program.account.escrowAccount.fetch(
+      disputedEscrow.publicKey
+    );
+    assert.ok("disputed" in _escrowAccount.state);
+
+    try {
+      await program.rpc.resolve(new anchor.BN(200), new anchor.BN(600), {
+        accounts: settleAccounts,
+      });
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(err.msg, "Only the escrow's arbiter can resolve a dispute.");
+    }
+
+    const balances = async () => ({
+      initializerA: (
+        await mintA.getAccountInfo(initializerTokenAccountA)
+      ).amount.toNumber(),
+      initializerB: (
+        await mintB.getAccountInfo(initializerTokenAccountB)
+      ).amount.toNumber(),
+      takerA: (await mintA.getAccountInfo(takerTokenAccountA)).amount.toNumber(),
+      takerB: (await mintB.getAccountInfo(takerTokenAccountB)).amount.toNumber(),
+    });
+    const before = await balances();
+
+    // The taker gets 200 of the 500 deposited and pays 600 of the 1000 funded.
+    await program.rpc.resolve(new anchor.BN(200), new anchor.BN(600), {
+      accounts: { ...settleAccounts, authority: arbiter.publicKey },
+      signers: [arbiter],
+    });
+    const after = await balances();
+    assert.equal(after.takerA, before.takerA + 200);
+    assert.equal(after.initializerA, before.initializerA + 300);
+    assert.equal(after.initializerB, before.initializerB + 600);
+    assert.equal(after.takerB, before.takerB + 400);
+  });
+
+  it("Settle a dispute whose taker closed their token accounts", async () => {
+    await mintA.mintTo(
+      initializerTokenAccountA,
+      mintAuthority.publicKey,
+      [mintAuthority],
+      initializerAmount
+    );
+    const disputedEscrow = Keypair.generate();
+    const { vaultPda, vault } = await initializeEscrowWithVault(disputedEscrow);
+    await program.rpc.setArbiter(arbiter.publicKey, {
+      accounts: {
+        initializer: provider.wallet.publicKey,
+        escrowAccount: disputedEscrow.publicKey,
+      },
+    });
+
+    const { wallet: taker, tokenAccountA, tokenAccountB } = await fundTaker(
+      takerAmount
+    );
+    const [takerVault, takerVaultBump] = await findTakerVault(
+      disputedEscrow.publicKey
+    );
+    const accounts = fundAccounts(
+      disputedEscrow.publicKey,
+      vaultPda,
+      taker.publicKey,
+      tokenAccountB,
+      tokenAccountA,
+      takerVault
+    );
+
+    // The taker only funds the escrow with the arbiter they agreed to.
+    try {
+      await program.rpc.fund(
+        new anchor.BN(initializerAmount),
+        new anchor.BN(takerAmount),
+        taker.publicKey,
+        maxFeeBps,
+        takerVaultBump,
+        { accounts, signers: [taker] }
+      );
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(
+        err.msg,
+        "The escrow's terms don't match what the taker expected."
+      );
+    }
+    await program.rpc.fund(
+      new anchor.BN(initializerAmount),
+      new anchor.BN(takerAmount),
+      arbiter.publicKey,
+      maxFeeBps,
+      takerVaultBump,
+      { accounts, signers: [taker] }
+    );
+
+    // Both of the taker's (now empty) token accounts are closed before the
+    // dispute is resolved, so their shares go to their associated token
+    // accounts instead.
+    await mintB.closeAccount(tokenAccountB, taker.publicKey, taker, []);
+    await mintA.closeAccount(tokenAccountA, taker.publicKey, taker, []);
+    await program.rpc.dispute({
+      accounts: {
+        authority: taker.publicKey,
+        escrowAccount: disputedEscrow.publicKey,
+      },
+      signers: [taker],
+    });
+    const settleAccounts = await arbitrationSettleAccounts(
+      disputedEscrow.publicKey,
+      vaultPda,
+      vault,
+      taker.publicKey,
+      tokenAccountB,
+      tokenAccountA,
+      takerVault
+    );
+    await program.rpc.resolve(new anchor.BN(200), new anchor.BN(600), {
+      accounts: { ...settleAccounts, authority: arbiter.publicKey },
+      signers: [arbiter],
+    });
+    assert.equal(
+      (
+        await mintA.getAccountInfo(
+          settleAccounts.takerAssociatedReceiveTokenAccount
+        )
+      ).amount.toNumber(),
+      200
+    );
+    assert.equal(
+      (
+        await mintB.getAccountInfo(
+          settleAccounts.takerAssociatedDepositTokenAccount
+        )
+      ).amount.toNumber(),
+      400
+    );
+    assert.equal(
+      await provider.connection.getAccountInfo(disputedEscrow.publicKey),
+      null
+    );
+  });
 });